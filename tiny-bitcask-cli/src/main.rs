@@ -0,0 +1,285 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use tiny_bitcask::{dump_file, verify, BitCask, BitCaskHandle, Opts};
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+/// Inspect and modify a tiny-bitcask data directory.
+#[derive(Parser)]
+#[command(name = "bitcask")]
+struct Cli {
+    /// data directory of the store
+    #[arg(short, long)]
+    dir: PathBuf,
+    /// size limit of a single data file in bytes
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    data_file_limit: u32,
+    /// keys and values on the command line and in the output are hex encoded
+    #[arg(long)]
+    hex: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// print the value of a key
+    Get { key: String },
+    /// set a key to a value
+    Put { key: String, value: String },
+    /// delete a key
+    Delete { key: String },
+    /// print the keys, optionally only those starting with a prefix
+    List {
+        #[arg(short, long, default_value = "")]
+        prefix: String,
+    },
+    /// print the key/value pairs, optionally only those starting with a prefix
+    Scan {
+        #[arg(short, long, default_value = "")]
+        prefix: String,
+    },
+    /// print key count and data file usage
+    Stats,
+    /// compact the sealed data files
+    Merge,
+    /// write every key/value pair as a hex encoded `key<TAB>value` line
+    Export {
+        /// output file, stdout if absent
+        file: Option<PathBuf>,
+    },
+    /// read `key<TAB>value` lines written by export and put them into the store
+    Import {
+        /// input file, stdin if absent
+        file: Option<PathBuf>,
+    },
+    /// check data and hint files of a store that is not open, without modifying them
+    Verify {
+        /// truncate torn tails, regenerate bad hint files and quarantine unreadable files
+        #[arg(long)]
+        repair: bool,
+    },
+    /// print every physical record of data and hint files, without opening the store
+    Dump {
+        /// `.dat`/`.idx` files, relative to the data directory; all of them if absent
+        files: Vec<PathBuf>,
+        /// print one JSON object per line
+        #[arg(long)]
+        json: bool,
+        /// maximum number of key and value bytes shown
+        #[arg(long, default_value_t = 32)]
+        preview: usize,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("bitcask: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Runs a command, returns false when the key it operates on does not exist or verify finds
+/// problems it did not repair.
+fn run(cli: Cli) -> CliResult<bool> {
+    if let Command::Dump {
+        files,
+        json,
+        preview,
+    } = &cli.command
+    {
+        dump(&cli.dir, files, *json, *preview)?;
+        return Ok(true);
+    }
+    if let Command::Verify { repair } = cli.command {
+        let report = verify(&cli.dir, repair)?;
+        for issue in &report.issues {
+            println!("issue: {}", issue);
+        }
+        for repair in &report.repairs {
+            println!("repaired: {}", repair);
+        }
+        println!(
+            "checked {} data files, {} hint files, {} records: {} issues, {} repairs",
+            report.data_files,
+            report.hint_files,
+            report.records,
+            report.issues.len(),
+            report.repairs.len()
+        );
+        return Ok(report.is_clean() || (repair && report.repairs.len() == report.issues.len()));
+    }
+
+    let mut db = BitCaskHandle::open(cli.dir.clone(), Opts::new(cli.data_file_limit))?;
+    let codec = Codec { hex: cli.hex };
+    let mut out = BufWriter::new(std::io::stdout().lock());
+
+    match cli.command {
+        Command::Get { key } => match db.get(&codec.decode(&key)?)? {
+            Some(value) => codec.write_line(&mut out, &[&value])?,
+            None => return Ok(false),
+        },
+        Command::Put { key, value } => {
+            db.put(&codec.decode(&key)?, &codec.decode(&value)?)?;
+            db.close()?;
+        }
+        Command::Delete { key } => {
+            let found = db.delete(&codec.decode(&key)?)?;
+            db.close()?;
+            return Ok(found);
+        }
+        Command::List { prefix } => {
+            let prefix = codec.decode(&prefix)?;
+            for key in db.list_keys().iter().filter(|key| key.starts_with(&prefix)) {
+                codec.write_line(&mut out, &[key])?;
+            }
+        }
+        Command::Scan { prefix } => {
+            for kv in db.scan(&codec.decode(&prefix)?) {
+                let (key, value) = kv?;
+                codec.write_line(&mut out, &[&key, &value])?;
+            }
+        }
+        Command::Stats => {
+            let stats = db.stats()?;
+            writeln!(out, "keys: {}", stats.keys)?;
+            writeln!(out, "data files: {}", stats.data_files)?;
+            writeln!(out, "data bytes: {}", stats.data_bytes)?;
+            writeln!(out, "live bytes: {}", stats.live_bytes)?;
+            writeln!(out, "dead ratio: {:.2}", stats.dead_ratio())?;
+            writeln!(out, "key dir bytes: {}", stats.key_dir_bytes)?;
+        }
+        Command::Merge => db.merge()?,
+        Command::Export { file } => {
+            let mut writer: Box<dyn Write> = match file {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(&mut out),
+            };
+            // export from a snapshot, so the dump is consistent even if a merge runs meanwhile
+            let snapshot = db.snapshot();
+            for kv in snapshot.scan(b"") {
+                let (key, value) = kv?;
+                writeln!(writer, "{}\t{}", encode_hex(&key), encode_hex(&value))?;
+            }
+            writer.flush()?;
+        }
+        Command::Import { file } => {
+            let reader: Box<dyn BufRead> = match file {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(std::io::stdin().lock()),
+            };
+            for (no, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let Some((key, value)) = line.split_once('\t') else {
+                    return Err(format!("line {}: expected key<TAB>value", no + 1).into());
+                };
+                db.put(&decode_hex(key)?, &decode_hex(value)?)?;
+            }
+            db.close()?;
+        }
+        Command::Dump { .. } | Command::Verify { .. } => {
+            unreachable!("dump and verify do not open the store")
+        }
+    }
+    out.flush()?;
+    Ok(true)
+}
+
+fn dump(dir: &Path, files: &[PathBuf], json: bool, preview: usize) -> CliResult<()> {
+    let files = if files.is_empty() {
+        let mut all = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        all.retain(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "dat" || ext == "idx")
+        });
+        all.sort();
+        all
+    } else {
+        files.iter().map(|file| dir.join(file)).collect()
+    };
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    for path in files {
+        if !json {
+            writeln!(out, "# {}", path.display())?;
+        }
+        for record in dump_file(&path)? {
+            let record = record?;
+            if json {
+                let line = record.to_json(preview);
+                // splice the file name in, so lines of several files can be told apart
+                writeln!(
+                    out,
+                    "{{\"file\":{:?},{}",
+                    path.display().to_string(),
+                    &line[1..]
+                )?;
+            } else {
+                writeln!(out, "{}", record.to_text(preview))?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Converts keys and values between their command line and stored form.
+struct Codec {
+    hex: bool,
+}
+
+impl Codec {
+    fn decode(&self, arg: &str) -> CliResult<Vec<u8>> {
+        if self.hex {
+            decode_hex(arg)
+        } else {
+            Ok(arg.as_bytes().to_vec())
+        }
+    }
+
+    fn write_line(&self, out: &mut impl Write, fields: &[&[u8]]) -> CliResult<()> {
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.write_all(b"\t")?;
+            }
+            if self.hex {
+                out.write_all(encode_hex(field).as_bytes())?;
+            } else {
+                out.write_all(field)?;
+            }
+        }
+        out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> CliResult<Vec<u8>> {
+    let invalid = || format!("invalid hex string {:?}", s).into();
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    let nibble = |c: u8| (c as char).to_digit(16).ok_or_else(invalid);
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok((nibble(pair[0])? << 4 | nibble(pair[1])?) as u8))
+        .collect()
+}
@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn fresh_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from("/tmp").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn bitcask(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bitcask"))
+        .arg("--dir")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn test_put_get_delete() {
+    let dir = fresh_dir("bitcask_cli_test");
+    assert!(bitcask(&dir, &["put", "hello", "world"]).status.success());
+    assert!(bitcask(&dir, &["put", "hey", "there"]).status.success());
+
+    let output = bitcask(&dir, &["get", "hello"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "world\n");
+
+    let output = bitcask(&dir, &["scan", "--prefix", "he"]);
+    assert_eq!(stdout(&output), "hello\tworld\nhey\tthere\n");
+
+    assert!(bitcask(&dir, &["delete", "hello"]).status.success());
+    assert!(!bitcask(&dir, &["delete", "hello"]).status.success());
+    assert!(!bitcask(&dir, &["get", "hello"]).status.success());
+    assert_eq!(stdout(&bitcask(&dir, &["list"])), "hey\n");
+    assert_eq!(
+        stdout(&bitcask(&dir, &["--hex", "get", "686579"])),
+        "7468657265\n"
+    );
+}
+
+#[test]
+fn test_invalid_hex() {
+    let dir = fresh_dir("bitcask_cli_hex_test");
+    // "é" is two bytes, a split of the string by bytes cuts it in half
+    for key in ["6", "zz", "\u{e9}", "a\u{e9}b"] {
+        let output = bitcask(&dir, &["--hex", "get", key]);
+        assert_eq!(output.status.code(), Some(2), "{key}");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("invalid hex string"));
+    }
+}
+
+#[test]
+fn test_overwrite() {
+    let dir = fresh_dir("bitcask_cli_overwrite_test");
+    for value in ["v1", "v2", "v3"] {
+        assert!(bitcask(&dir, &["put", "key", value]).status.success());
+    }
+    assert_eq!(stdout(&bitcask(&dir, &["get", "key"])), "v3\n");
+}
+
+#[test]
+fn test_export_import() {
+    let src = fresh_dir("bitcask_cli_export_src");
+    let dest = fresh_dir("bitcask_cli_export_dest");
+    bitcask(&src, &["put", "k1", "v1"]);
+    bitcask(&src, &["--hex", "put", "00ff", "0a0d"]);
+    let dump = PathBuf::from("/tmp/bitcask_cli_export.tsv");
+    assert!(bitcask(&src, &["export", dump.to_str().unwrap()])
+        .status
+        .success());
+    assert_eq!(
+        std::fs::read_to_string(&dump).unwrap(),
+        "00ff\t0a0d\n6b31\t7631\n"
+    );
+
+    assert!(bitcask(&dest, &["import", dump.to_str().unwrap()])
+        .status
+        .success());
+    assert_eq!(
+        stdout(&bitcask(&dest, &["stats"])).lines().next(),
+        Some("keys: 2")
+    );
+    assert_eq!(stdout(&bitcask(&dest, &["--hex", "get", "00ff"])), "0a0d\n");
+}
+
+#[test]
+fn test_dump() {
+    let dir = fresh_dir("bitcask_cli_dump_test");
+    bitcask(&dir, &["put", "hello", "world"]);
+    bitcask(&dir, &["delete", "hello"]);
+
+    let output = stdout(&bitcask(&dir, &["dump", "--json"]));
+    let lines: Vec<_> = output.lines().collect();
+    // each command closes its data file, writing a hint (a record and a footer) for it
+    assert_eq!(lines.len(), 6);
+    assert!(lines[0].starts_with(
+        "{\"file\":\"/tmp/bitcask_cli_dump_test/000000000.dat\",\"type\":\"put\",\"offset\":0,"
+    ));
+    assert!(lines[0].contains("\"crc_valid\":true"));
+    assert!(lines[1].contains("\"type\":\"hint\",\"offset\":0,\"kind\":\"put\""));
+    assert!(lines[2].contains("\"type\":\"footer\""));
+    assert!(lines[2].contains("\"records\":1,"));
+    assert!(lines[3].contains("\"type\":\"delete\""));
+    assert!(lines[4].contains("\"kind\":\"delete\""));
+
+    let output = stdout(&bitcask(&dir, &["dump", "000000000.dat"]));
+    assert!(output.contains("put    crc="));
+    assert!(output.contains("key=\"hello\" value=\"world\""));
+}
+
+#[test]
+fn test_verify() {
+    let dir = fresh_dir("bitcask_cli_verify_test");
+    bitcask(&dir, &["put", "hello", "world"]);
+    let output = bitcask(&dir, &["verify"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("1 records: 0 issues"));
+
+    let path = dir.join("000000000.dat");
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes.extend_from_slice(&[0; 5]);
+    std::fs::write(&path, bytes).unwrap();
+    assert!(!bitcask(&dir, &["verify"]).status.success());
+    assert!(bitcask(&dir, &["verify", "--repair"]).status.success());
+    assert!(bitcask(&dir, &["verify"]).status.success());
+}
@@ -0,0 +1,254 @@
+use std::future::Future;
+use std::io;
+use std::sync::Mutex;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+use crate::errors::{ClientError, ClientResult};
+use crate::resp::{encode_command, parse_reply, prefix_pattern, Reply};
+use crate::{
+    check_batch, encode_batch, ttl_from_reply, BatchOp, ClientOpts, Key, KeyRef, Value, ValueRef,
+    SCAN_PAGE,
+};
+
+/// The tokio flavor of `Client`, with the same operations and retry rules.
+pub struct AsyncClient {
+    opts: ClientOpts,
+    idle: Mutex<Vec<Connection>>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    // received bytes not parsed yet
+    buf: Vec<u8>,
+}
+
+async fn with_timeout<T>(
+    limit: Option<std::time::Duration>,
+    fut: impl Future<Output = ClientResult<T>>,
+) -> ClientResult<T> {
+    match limit {
+        Some(limit) => timeout(limit, fut)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into())),
+        None => fut.await,
+    }
+}
+
+impl Connection {
+    async fn open(opts: &ClientOpts) -> ClientResult<Self> {
+        let stream = with_timeout(Some(opts.connect_timeout), async {
+            Ok(TcpStream::connect(&opts.addr).await?)
+        })
+        .await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Sends a request of `count` commands and reads their replies.
+    async fn roundtrip(&mut self, request: &[u8], count: usize) -> ClientResult<Vec<Reply>> {
+        self.stream.write_all(request).await?;
+        let mut replies = Vec::with_capacity(count);
+        let mut chunk = [0; 16 * 1024];
+        while replies.len() < count {
+            match parse_reply(&self.buf)? {
+                Some((reply, len)) => {
+                    self.buf.drain(..len);
+                    replies.push(reply);
+                }
+                None => {
+                    let read = self.stream.read(&mut chunk).await?;
+                    if read == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    self.buf.extend_from_slice(&chunk[..read]);
+                }
+            }
+        }
+        Ok(replies)
+    }
+}
+
+impl AsyncClient {
+    /// Connects once to check the server is reachable, the connection is kept for reuse.
+    pub async fn connect(opts: ClientOpts) -> ClientResult<Self> {
+        let conn = Connection::open(&opts).await?;
+        Ok(Self {
+            opts,
+            idle: Mutex::new(vec![conn]),
+        })
+    }
+
+    async fn attempt(&self, request: &[u8], count: usize) -> ClientResult<Vec<Reply>> {
+        let pooled = self.idle.lock().unwrap().pop();
+        let mut conn = match pooled {
+            Some(conn) => conn,
+            None => Connection::open(&self.opts).await?,
+        };
+        let replies = with_timeout(self.opts.io_timeout, conn.roundtrip(request, count)).await?;
+        // a connection that failed may hold half a reply, only good ones go back
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.opts.max_idle {
+            idle.push(conn);
+        }
+        Ok(replies)
+    }
+
+    async fn execute(
+        &self,
+        request: &[u8],
+        count: usize,
+        idempotent: bool,
+    ) -> ClientResult<Vec<Reply>> {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(request, count).await {
+                Err(err) if idempotent && err.is_retryable() && attempt < self.opts.retries => {
+                    attempt += 1;
+                    sleep(self.opts.backoff(attempt)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn call(&self, args: &[&[u8]], idempotent: bool) -> ClientResult<Reply> {
+        let mut request = Vec::new();
+        encode_command(&mut request, args);
+        Ok(self.execute(&request, 1, idempotent).await?.remove(0))
+    }
+
+    pub async fn ping(&self) -> ClientResult<()> {
+        match self.call(&[b"PING"], true).await? {
+            Reply::Simple(s) if s == "PONG" => Ok(()),
+            other => Err(ClientError::ProtocolError(format!(
+                "unexpected reply {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub async fn get(&self, key: &KeyRef) -> ClientResult<Option<Value>> {
+        self.call(&[b"GET", key], true).await?.into_bulk()
+    }
+
+    /// See `Client::multi_get`.
+    pub async fn multi_get(&self, keys: &[&KeyRef]) -> ClientResult<Vec<Option<Value>>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut args: Vec<&[u8]> = vec![b"MGET"];
+        args.extend_from_slice(keys);
+        self.call(&args, true)
+            .await?
+            .into_array()?
+            .into_iter()
+            .map(Reply::into_bulk)
+            .collect()
+    }
+
+    pub async fn put(&self, key: &KeyRef, value: &ValueRef) -> ClientResult<()> {
+        self.call(&[b"SET", key, value], true).await?.into_ok()
+    }
+
+    pub async fn put_with_ttl(
+        &self,
+        key: &KeyRef,
+        value: &ValueRef,
+        ttl_secs: u32,
+    ) -> ClientResult<()> {
+        let ttl = ttl_secs.to_string();
+        self.call(&[b"SET", key, value, b"EX", ttl.as_bytes()], true)
+            .await?
+            .into_ok()
+    }
+
+    /// See `Client::delete`.
+    pub async fn delete(&self, key: &KeyRef) -> ClientResult<bool> {
+        Ok(self.call(&[b"DEL", key], false).await?.into_integer()? == 1)
+    }
+
+    /// See `Client::batch`.
+    pub async fn batch(&self, ops: &[BatchOp]) -> ClientResult<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let replies = self.execute(&encode_batch(ops), ops.len(), true).await?;
+        check_batch(ops, replies)
+    }
+
+    /// See `BitCask::expire`.
+    pub async fn expire(&self, key: &KeyRef, ttl_secs: Option<u32>) -> ClientResult<bool> {
+        match ttl_secs {
+            Some(ttl) => {
+                let ttl = ttl.to_string();
+                let reply = self.call(&[b"EXPIRE", key, ttl.as_bytes()], false).await?;
+                Ok(reply.into_integer()? == 1)
+            }
+            None => {
+                // PERSIST only tells whether there was a ttl to remove
+                let mut request = Vec::new();
+                encode_command(&mut request, &[b"PERSIST", key]);
+                encode_command(&mut request, &[b"EXISTS", key]);
+                let exists = self.execute(&request, 2, true).await?.remove(1);
+                Ok(exists.into_integer()? == 1)
+            }
+        }
+    }
+
+    /// See `BitCask::ttl`.
+    pub async fn ttl(&self, key: &KeyRef) -> ClientResult<Option<Option<u32>>> {
+        ttl_from_reply(self.call(&[b"TTL", key], true).await?)
+    }
+
+    pub async fn list_keys(&self) -> ClientResult<Vec<Key>> {
+        self.call(&[b"KEYS", b"*"], true)
+            .await?
+            .into_array()?
+            .into_iter()
+            .filter_map(|key| key.into_bulk().transpose())
+            .collect()
+    }
+
+    /// See `Client::scan`.
+    pub async fn scan(&self, prefix: &KeyRef) -> ClientResult<Vec<(Key, Value)>> {
+        let pattern = prefix_pattern(prefix);
+        let mut cursor = "0".to_string();
+        let mut pairs = vec![];
+        loop {
+            let page = self
+                .call(
+                    &[
+                        b"SCAN",
+                        cursor.as_bytes(),
+                        b"MATCH",
+                        &pattern,
+                        b"COUNT",
+                        SCAN_PAGE,
+                    ],
+                    true,
+                )
+                .await?;
+            let (next, keys) = page.into_scan_page()?;
+            if !keys.is_empty() {
+                let key_refs: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+                let values = self.multi_get(&key_refs).await?;
+                // keys deleted since the SCAN come back empty
+                pairs.extend(
+                    keys.into_iter()
+                        .zip(values)
+                        .filter_map(|(key, value)| Some((key, value?))),
+                );
+            }
+            if next == "0" {
+                return Ok(pairs);
+            }
+            cursor = next;
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::errors::ClientResult;
+use crate::resp::{encode_command, parse_reply, prefix_pattern, Reply};
+use crate::{
+    check_batch, encode_batch, ttl_from_reply, BatchOp, ClientOpts, Key, KeyRef, Value, ValueRef,
+    SCAN_PAGE,
+};
+
+/// A blocking client of `bitcask-server`, with the operations of the `BitCask` trait.
+/// It can be shared between threads, each request borrows a pooled connection.
+pub struct Client {
+    opts: ClientOpts,
+    idle: Mutex<Vec<Connection>>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    // received bytes not parsed yet
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    fn open(opts: &ClientOpts) -> ClientResult<Self> {
+        let mut last_err = None;
+        for addr in opts.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, opts.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(opts.io_timeout)?;
+                    stream.set_write_timeout(opts.io_timeout)?;
+                    return Ok(Self {
+                        stream,
+                        buf: Vec::new(),
+                    });
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))
+            .into())
+    }
+
+    /// Sends a request of `count` commands and reads their replies.
+    fn roundtrip(&mut self, request: &[u8], count: usize) -> ClientResult<Vec<Reply>> {
+        self.stream.write_all(request)?;
+        let mut replies = Vec::with_capacity(count);
+        let mut chunk = [0; 16 * 1024];
+        while replies.len() < count {
+            match parse_reply(&self.buf)? {
+                Some((reply, len)) => {
+                    self.buf.drain(..len);
+                    replies.push(reply);
+                }
+                None => {
+                    let read = self.stream.read(&mut chunk)?;
+                    if read == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    self.buf.extend_from_slice(&chunk[..read]);
+                }
+            }
+        }
+        Ok(replies)
+    }
+}
+
+impl Client {
+    /// Connects once to check the server is reachable, the connection is kept for reuse.
+    pub fn connect(opts: ClientOpts) -> ClientResult<Self> {
+        let conn = Connection::open(&opts)?;
+        Ok(Self {
+            opts,
+            idle: Mutex::new(vec![conn]),
+        })
+    }
+
+    fn execute(&self, request: &[u8], count: usize, idempotent: bool) -> ClientResult<Vec<Reply>> {
+        let mut attempt = 0;
+        loop {
+            let pooled = self.idle.lock().unwrap().pop();
+            let result = pooled
+                .map_or_else(|| Connection::open(&self.opts), Ok)
+                .and_then(|mut conn| {
+                    let replies = conn.roundtrip(request, count)?;
+                    // a connection that failed may hold half a reply, only good ones go back
+                    let mut idle = self.idle.lock().unwrap();
+                    if idle.len() < self.opts.max_idle {
+                        idle.push(conn);
+                    }
+                    Ok(replies)
+                });
+            match result {
+                Err(err) if idempotent && err.is_retryable() && attempt < self.opts.retries => {
+                    attempt += 1;
+                    thread::sleep(self.opts.backoff(attempt));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn call(&self, args: &[&[u8]], idempotent: bool) -> ClientResult<Reply> {
+        let mut request = Vec::new();
+        encode_command(&mut request, args);
+        Ok(self.execute(&request, 1, idempotent)?.remove(0))
+    }
+
+    pub fn ping(&self) -> ClientResult<()> {
+        match self.call(&[b"PING"], true)? {
+            Reply::Simple(s) if s == "PONG" => Ok(()),
+            other => Err(crate::ClientError::ProtocolError(format!(
+                "unexpected reply {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn get(&self, key: &KeyRef) -> ClientResult<Option<Value>> {
+        self.call(&[b"GET", key], true)?.into_bulk()
+    }
+
+    /// Gets several keys in one round trip, in the order of `keys`.
+    pub fn multi_get(&self, keys: &[&KeyRef]) -> ClientResult<Vec<Option<Value>>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut args: Vec<&[u8]> = vec![b"MGET"];
+        args.extend_from_slice(keys);
+        self.call(&args, true)?
+            .into_array()?
+            .into_iter()
+            .map(Reply::into_bulk)
+            .collect()
+    }
+
+    pub fn put(&self, key: &KeyRef, value: &ValueRef) -> ClientResult<()> {
+        self.call(&[b"SET", key, value], true)?.into_ok()
+    }
+
+    pub fn put_with_ttl(&self, key: &KeyRef, value: &ValueRef, ttl_secs: u32) -> ClientResult<()> {
+        let ttl = ttl_secs.to_string();
+        self.call(&[b"SET", key, value, b"EX", ttl.as_bytes()], true)?
+            .into_ok()
+    }
+
+    /// Returns whether the key existed. Not retried, a retry could not tell whether the first
+    /// attempt deleted it.
+    pub fn delete(&self, key: &KeyRef) -> ClientResult<bool> {
+        Ok(self.call(&[b"DEL", key], false)?.into_integer()? == 1)
+    }
+
+    /// Applies puts and deletes in one round trip. The batch is not atomic: on failure a
+    /// prefix of it may have been applied.
+    pub fn batch(&self, ops: &[BatchOp]) -> ClientResult<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let replies = self.execute(&encode_batch(ops), ops.len(), true)?;
+        check_batch(ops, replies)
+    }
+
+    /// See `BitCask::expire`.
+    pub fn expire(&self, key: &KeyRef, ttl_secs: Option<u32>) -> ClientResult<bool> {
+        match ttl_secs {
+            Some(ttl) => {
+                let ttl = ttl.to_string();
+                Ok(self
+                    .call(&[b"EXPIRE", key, ttl.as_bytes()], false)?
+                    .into_integer()?
+                    == 1)
+            }
+            None => {
+                // PERSIST only tells whether there was a ttl to remove
+                let mut request = Vec::new();
+                encode_command(&mut request, &[b"PERSIST", key]);
+                encode_command(&mut request, &[b"EXISTS", key]);
+                let exists = self.execute(&request, 2, true)?.remove(1);
+                Ok(exists.into_integer()? == 1)
+            }
+        }
+    }
+
+    /// See `BitCask::ttl`.
+    pub fn ttl(&self, key: &KeyRef) -> ClientResult<Option<Option<u32>>> {
+        ttl_from_reply(self.call(&[b"TTL", key], true)?)
+    }
+
+    pub fn list_keys(&self) -> ClientResult<Vec<Key>> {
+        self.call(&[b"KEYS", b"*"], true)?
+            .into_array()?
+            .into_iter()
+            .filter_map(|key| key.into_bulk().transpose())
+            .collect()
+    }
+
+    /// The key/value pairs whose keys start with `prefix`, in key order. Fetched a page at a
+    /// time, so it is not a consistent view when keys change meanwhile.
+    pub fn scan(&self, prefix: &KeyRef) -> ClientResult<Vec<(Key, Value)>> {
+        let pattern = prefix_pattern(prefix);
+        let mut cursor = "0".to_string();
+        let mut pairs = vec![];
+        loop {
+            let page = self.call(
+                &[
+                    b"SCAN",
+                    cursor.as_bytes(),
+                    b"MATCH",
+                    &pattern,
+                    b"COUNT",
+                    SCAN_PAGE,
+                ],
+                true,
+            )?;
+            let (next, keys) = page.into_scan_page()?;
+            if !keys.is_empty() {
+                let key_refs: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+                let values = self.multi_get(&key_refs)?;
+                // keys deleted since the SCAN come back empty
+                pairs.extend(
+                    keys.into_iter()
+                        .zip(values)
+                        .filter_map(|(key, value)| Some((key, value?))),
+                );
+            }
+            if next == "0" {
+                return Ok(pairs);
+            }
+            cursor = next;
+        }
+    }
+}
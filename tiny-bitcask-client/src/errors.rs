@@ -0,0 +1,37 @@
+#[derive(Debug)]
+pub enum ClientError {
+    /// connecting, sending or receiving failed, including timeouts
+    IoError(std::io::Error),
+    /// the server answered with an error reply
+    ServerError(String),
+    /// the server answered with something that is not a valid reply to the command
+    ProtocolError(String),
+}
+
+impl ClientError {
+    /// Whether the request may not have reached the server, so an idempotent one can be
+    /// sent again on a new connection.
+    pub(crate) fn is_retryable(&self) -> bool {
+        matches!(self, ClientError::IoError(_))
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> Self {
+        ClientError::IoError(err)
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::IoError(err) => write!(f, "io error: {}", err),
+            ClientError::ServerError(msg) => write!(f, "server error: {}", msg),
+            ClientError::ProtocolError(msg) => write!(f, "protocol error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+pub type ClientResult<T> = Result<T, ClientError>;
@@ -0,0 +1,96 @@
+#[cfg(feature = "async")]
+mod async_client;
+mod client;
+mod errors;
+mod resp;
+
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+pub use client::Client;
+pub use errors::{ClientError, ClientResult};
+pub use resp::Reply;
+
+pub type Key = Vec<u8>;
+pub type KeyRef = [u8];
+pub type Value = Vec<u8>;
+pub type ValueRef = [u8];
+
+/// Connection settings shared by `Client` and `AsyncClient`.
+#[derive(Debug, Clone)]
+pub struct ClientOpts {
+    /// `host:port` of the server
+    pub addr: String,
+    /// idle connections kept for reuse, more are closed when they are handed back
+    pub max_idle: usize,
+    pub connect_timeout: Duration,
+    /// limit for sending a request and receiving its reply, none if `None`
+    pub io_timeout: Option<Duration>,
+    /// extra attempts of idempotent requests failing with an io error, each on a new
+    /// connection; writes whose result depends on the state they found are never retried
+    pub retries: usize,
+    /// pause before the first retry, doubled for every further one
+    pub retry_backoff: Duration,
+}
+
+impl ClientOpts {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            max_idle: 8,
+            connect_timeout: Duration::from_secs(1),
+            io_timeout: Some(Duration::from_secs(5)),
+            retries: 2,
+            retry_backoff: Duration::from_millis(50),
+        }
+    }
+
+    pub(crate) fn backoff(&self, attempt: usize) -> Duration {
+        self.retry_backoff * 2u32.saturating_pow(attempt as u32 - 1)
+    }
+}
+
+/// One write of `Client::batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Key, Value),
+    Delete(Key),
+}
+
+/// Keys per SCAN and MGET round trip of a scan.
+pub(crate) const SCAN_PAGE: &[u8] = b"1000";
+
+/// Encodes the commands of a batch, all of them are answered with one reply each.
+pub(crate) fn encode_batch(ops: &[BatchOp]) -> Vec<u8> {
+    let mut request = Vec::new();
+    for op in ops {
+        match op {
+            BatchOp::Put(key, value) => resp::encode_command(&mut request, &[b"SET", key, value]),
+            BatchOp::Delete(key) => resp::encode_command(&mut request, &[b"DEL", key]),
+        }
+    }
+    request
+}
+
+/// Converts the replies of a batch, the first error reply fails the whole batch.
+pub(crate) fn check_batch(ops: &[BatchOp], replies: Vec<Reply>) -> ClientResult<()> {
+    for (op, reply) in ops.iter().zip(replies) {
+        match op {
+            BatchOp::Put(..) => reply.into_ok()?,
+            BatchOp::Delete(_) => {
+                reply.into_integer()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Converts a TTL reply to the form of `BitCask::ttl`.
+pub(crate) fn ttl_from_reply(reply: Reply) -> ClientResult<Option<Option<u32>>> {
+    Ok(match reply.into_integer()? {
+        -2 => None,
+        -1 => Some(None),
+        secs => Some(Some(secs.clamp(0, u32::MAX as i64) as u32)),
+    })
+}
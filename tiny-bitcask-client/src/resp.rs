@@ -0,0 +1,206 @@
+use crate::errors::{ClientError, ClientResult};
+use crate::{Key, Value};
+
+/// A reply of the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+fn unexpected(reply: Reply) -> ClientError {
+    match reply {
+        Reply::Error(msg) => ClientError::ServerError(msg),
+        other => ClientError::ProtocolError(format!("unexpected reply {:?}", other)),
+    }
+}
+
+impl Reply {
+    pub(crate) fn into_ok(self) -> ClientResult<()> {
+        match self {
+            Reply::Simple(s) if s == "OK" => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub(crate) fn into_integer(self) -> ClientResult<i64> {
+        match self {
+            Reply::Integer(n) => Ok(n),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub(crate) fn into_bulk(self) -> ClientResult<Option<Value>> {
+        match self {
+            Reply::Bulk(bulk) => Ok(bulk),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub(crate) fn into_array(self) -> ClientResult<Vec<Reply>> {
+        match self {
+            Reply::Array(Some(items)) => Ok(items),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Splits a SCAN reply into the next cursor and the keys.
+    pub(crate) fn into_scan_page(self) -> ClientResult<(String, Vec<Key>)> {
+        let mut items = self.into_array()?.into_iter();
+        let (Some(cursor), Some(keys), None) = (items.next(), items.next(), items.next()) else {
+            return Err(ClientError::ProtocolError(
+                "malformed SCAN reply".to_string(),
+            ));
+        };
+        let cursor = cursor
+            .into_bulk()?
+            .and_then(|cursor| String::from_utf8(cursor).ok())
+            .ok_or_else(|| ClientError::ProtocolError("malformed SCAN cursor".to_string()))?;
+        let keys = keys
+            .into_array()?
+            .into_iter()
+            .map(|key| {
+                key.into_bulk()?
+                    .ok_or_else(|| unexpected(Reply::Bulk(None)))
+            })
+            .collect::<ClientResult<_>>()?;
+        Ok((cursor, keys))
+    }
+}
+
+/// Appends a command as a RESP array of bulk strings.
+pub(crate) fn encode_command(out: &mut Vec<u8>, args: &[&[u8]]) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Escapes the glob characters of a key prefix, so it matches literally in SCAN and KEYS.
+pub(crate) fn prefix_pattern(prefix: &[u8]) -> Vec<u8> {
+    let mut pattern = Vec::with_capacity(prefix.len() + 1);
+    for &b in prefix {
+        if matches!(b, b'*' | b'?' | b'[' | b']' | b'\\') {
+            pattern.push(b'\\');
+        }
+        pattern.push(b);
+    }
+    pattern.push(b'*');
+    pattern
+}
+
+/// Parses the reply at the start of `buf`, returns it with the number of bytes it takes, or
+/// `None` if `buf` does not hold a complete reply yet.
+pub(crate) fn parse_reply(buf: &[u8]) -> ClientResult<Option<(Reply, usize)>> {
+    parse_at(buf, 0)
+}
+
+fn parse_at(buf: &[u8], pos: usize) -> ClientResult<Option<(Reply, usize)>> {
+    let Some(len) = buf[pos..].windows(2).position(|w| w == b"\r\n") else {
+        return Ok(None);
+    };
+    let line = &buf[pos + 1..pos + len];
+    let next = pos + len + 2;
+    let text = || String::from_utf8_lossy(line).into_owned();
+    let number = || {
+        std::str::from_utf8(line)
+            .ok()
+            .and_then(|n| n.parse::<i64>().ok())
+            .ok_or_else(|| ClientError::ProtocolError(format!("invalid number {:?}", text())))
+    };
+    let reply = match buf[pos] {
+        b'+' => Reply::Simple(text()),
+        b'-' => Reply::Error(text()),
+        b':' => Reply::Integer(number()?),
+        b'$' => match number()? {
+            -1 => Reply::Bulk(None),
+            len if len >= 0 => {
+                let end = next + len as usize;
+                if buf.len() < end + 2 {
+                    return Ok(None);
+                }
+                if &buf[end..end + 2] != b"\r\n" {
+                    return Err(ClientError::ProtocolError(
+                        "bulk string not terminated by CRLF".to_string(),
+                    ));
+                }
+                return Ok(Some((Reply::Bulk(Some(buf[next..end].to_vec())), end + 2)));
+            }
+            len => {
+                return Err(ClientError::ProtocolError(format!(
+                    "invalid bulk length {}",
+                    len
+                )))
+            }
+        },
+        b'*' => match number()? {
+            -1 => Reply::Array(None),
+            count if count >= 0 => {
+                let mut items = Vec::with_capacity((count as usize).min(1024));
+                let mut pos = next;
+                for _ in 0..count {
+                    let Some((item, end)) = parse_at(buf, pos)? else {
+                        return Ok(None);
+                    };
+                    items.push(item);
+                    pos = end;
+                }
+                return Ok(Some((Reply::Array(Some(items)), pos)));
+            }
+            count => {
+                return Err(ClientError::ProtocolError(format!(
+                    "invalid array length {}",
+                    count
+                )))
+            }
+        },
+        other => {
+            return Err(ClientError::ProtocolError(format!(
+                "unknown reply type {:?}",
+                other as char
+            )))
+        }
+    };
+    Ok(Some((reply, next)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reply() {
+        let buf = b"*3\r\n+OK\r\n$3\r\na\r\n\r\n*-1\r\n:7\r\n";
+        let (reply, len) = parse_reply(buf).unwrap().unwrap();
+        assert_eq!(
+            reply,
+            Reply::Array(Some(vec![
+                Reply::Simple("OK".to_string()),
+                Reply::Bulk(Some(b"a\r\n".to_vec())),
+                Reply::Array(None),
+            ]))
+        );
+        assert_eq!(
+            parse_reply(&buf[len..]).unwrap(),
+            Some((Reply::Integer(7), 4))
+        );
+
+        // incomplete replies need more input
+        for end in 0..len {
+            assert_eq!(parse_reply(&buf[..end]).unwrap(), None);
+        }
+        assert!(parse_reply(b"!x\r\n").is_err());
+        assert!(parse_reply(b"$1\r\nab\r\n").is_err());
+    }
+
+    #[test]
+    fn test_prefix_pattern() {
+        assert_eq!(prefix_pattern(b"user:"), b"user:*");
+        assert_eq!(prefix_pattern(b"a*b[c]"), b"a\\*b\\[c\\]*");
+    }
+}
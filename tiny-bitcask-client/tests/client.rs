@@ -0,0 +1,162 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tiny_bitcask::{BitCask, BitCaskHandle, Opts};
+use tiny_bitcask_client::{AsyncClient, BatchOp, Client, ClientError, ClientOpts};
+use tiny_bitcask_server::Server;
+
+fn start_server(name: &str) -> String {
+    let dir = PathBuf::from("/tmp").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = BitCaskHandle::open(dir, Opts::new(1024 * 1024)).unwrap();
+    let server = Server::bind("127.0.0.1:0", db).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    thread::spawn(move || server.run());
+    addr
+}
+
+#[test]
+fn test_blocking_client() {
+    let client = Client::connect(ClientOpts::new(start_server("bitcask_client_test"))).unwrap();
+    client.ping().unwrap();
+    client.put(b"k1", b"v1").unwrap();
+    assert_eq!(client.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(client.get(b"nope").unwrap(), None);
+    assert!(client.delete(b"k1").unwrap());
+    assert!(!client.delete(b"k1").unwrap());
+
+    client
+        .batch(&[
+            BatchOp::Put(b"a*1".to_vec(), b"1".to_vec()),
+            BatchOp::Put(b"a*2".to_vec(), vec![0, 255]),
+            BatchOp::Put(b"ab".to_vec(), b"3".to_vec()),
+            BatchOp::Put(b"b".to_vec(), b"4".to_vec()),
+            BatchOp::Delete(b"b".to_vec()),
+        ])
+        .unwrap();
+    assert_eq!(
+        client.multi_get(&[b"a*1", b"b", b"ab"]).unwrap(),
+        vec![Some(b"1".to_vec()), None, Some(b"3".to_vec())]
+    );
+    // glob characters in the prefix match literally
+    assert_eq!(
+        client.scan(b"a*").unwrap(),
+        vec![
+            (b"a*1".to_vec(), b"1".to_vec()),
+            (b"a*2".to_vec(), vec![0, 255])
+        ]
+    );
+    assert_eq!(client.scan(b"").unwrap().len(), 3);
+    assert_eq!(
+        client.list_keys().unwrap(),
+        vec![b"a*1".to_vec(), b"a*2".to_vec(), b"ab".to_vec()]
+    );
+
+    client.put_with_ttl(b"t", b"v", 100).unwrap();
+    assert!(client.ttl(b"t").unwrap().unwrap().unwrap() > 90);
+    assert!(client.expire(b"t", None).unwrap());
+    assert_eq!(client.ttl(b"t").unwrap(), Some(None));
+    assert!(client.expire(b"t", None).unwrap());
+    assert!(!client.expire(b"nope", None).unwrap());
+    assert_eq!(client.ttl(b"nope").unwrap(), None);
+}
+
+#[test]
+fn test_shared_between_threads() {
+    let addr = start_server("bitcask_client_threads_test");
+    let client = Arc::new(Client::connect(ClientOpts::new(addr)).unwrap());
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let client = client.clone();
+            thread::spawn(move || {
+                for i in 0..20 {
+                    let key = format!("{}:{}", t, i);
+                    client.put(key.as_bytes(), key.as_bytes()).unwrap();
+                    assert_eq!(client.get(key.as_bytes()).unwrap(), Some(key.into_bytes()));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(client.list_keys().unwrap().len(), 160);
+}
+
+/// A server that drops the first connection after reading a request, and answers every
+/// request on later connections with `reply`.
+fn flaky_server(reply: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut buf = [0; 1024];
+                while stream.read(&mut buf).unwrap_or(0) > 0 {
+                    if i == 0 {
+                        return;
+                    }
+                    stream.write_all(reply).unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+fn test_retries_idempotent_requests() {
+    let client = Client::connect(ClientOpts::new(flaky_server(b"$1\r\nv\r\n"))).unwrap();
+    assert_eq!(client.get(b"k").unwrap(), Some(b"v".to_vec()));
+
+    let client = Client::connect(ClientOpts::new(flaky_server(b":1\r\n"))).unwrap();
+    assert!(matches!(client.delete(b"k"), Err(ClientError::IoError(_))));
+    // the failed connection was dropped, the next request gets a new one
+    assert!(client.delete(b"k").unwrap());
+}
+
+#[test]
+fn test_timeout() {
+    // accepts connections but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut opts = ClientOpts::new(listener.local_addr().unwrap().to_string());
+    opts.io_timeout = Some(Duration::from_millis(100));
+    opts.retries = 0;
+    let client = Client::connect(opts).unwrap();
+    let start = Instant::now();
+    assert!(matches!(client.get(b"k"), Err(ClientError::IoError(_))));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    drop(listener);
+}
+
+#[tokio::test]
+async fn test_async_client() {
+    let addr = start_server("bitcask_async_client_test");
+    let client = AsyncClient::connect(ClientOpts::new(addr)).await.unwrap();
+    client.ping().await.unwrap();
+    client.put(b"k1", b"v1").await.unwrap();
+    client
+        .batch(&[
+            BatchOp::Put(b"k2".to_vec(), b"v2".to_vec()),
+            BatchOp::Delete(b"k1".to_vec()),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(client.get(b"k1").await.unwrap(), None);
+    assert_eq!(
+        client.scan(b"k").await.unwrap(),
+        vec![(b"k2".to_vec(), b"v2".to_vec())]
+    );
+    assert!(client.delete(b"k2").await.unwrap());
+    assert!(client.list_keys().await.unwrap().is_empty());
+
+    let mut opts = ClientOpts::new(flaky_server(b"$1\r\nv\r\n"));
+    opts.io_timeout = Some(Duration::from_millis(500));
+    let client = AsyncClient::connect(opts).await.unwrap();
+    assert_eq!(client.get(b"k").await.unwrap(), Some(b"v".to_vec()));
+}
@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tiny_bitcask::{BitCask, BitCaskError, BitCaskHandle, Key};
+
+use crate::glob::{glob_match, literal_prefix};
+use crate::replication::Follower;
+use crate::resp::Reply;
+
+/// Open SCAN cursors kept at most, the oldest is dropped beyond that.
+const MAX_CURSORS: usize = 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// SCAN cursors are small integers handed to clients, each standing for the last key returned
+/// by the previous call, so iteration resumes correctly while keys are added and removed.
+#[derive(Default)]
+struct Cursors {
+    last_id: u64,
+    open: BTreeMap<u64, Key>,
+}
+
+impl Cursors {
+    fn insert(&mut self, last_key: Key) -> u64 {
+        self.last_id += 1;
+        self.open.insert(self.last_id, last_key);
+        if self.open.len() > MAX_CURSORS {
+            self.open.pop_first();
+        }
+        self.last_id
+    }
+}
+
+/// State shared by all connections of a server.
+pub(crate) struct Shared {
+    pub(crate) db: Arc<Mutex<BitCaskHandle>>,
+    cursors: Mutex<Cursors>,
+    // set while the store follows a leader
+    pub(crate) follower: Mutex<Option<Follower>>,
+    started: Instant,
+    pub(crate) connected_clients: AtomicUsize,
+    pub(crate) total_connections: AtomicU64,
+    total_commands: AtomicU64,
+}
+
+type CommandResult = Result<Reply, Reply>;
+
+fn wrong_arity(name: &str) -> Reply {
+    Reply::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("ERR syntax error")
+}
+
+fn parse_int(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Reply::error("ERR value is not an integer or out of range"))
+}
+
+/// Converts a client supplied expiry to whole seconds, rounding milliseconds up.
+fn ttl_secs(amount: i64, millis: bool, command: &str) -> Result<u32, Reply> {
+    let secs = if millis {
+        amount.checked_add(999).map(|ms| ms / 1000)
+    } else {
+        Some(amount)
+    };
+    secs.filter(|secs| *secs > 0)
+        .and_then(|secs| u32::try_from(secs).ok())
+        .ok_or_else(|| Reply::error(format!("ERR invalid expire time in '{}' command", command)))
+}
+
+fn storage_error(err: BitCaskError) -> Reply {
+    match err {
+        BitCaskError::ReadOnly => {
+            Reply::error("READONLY You can't write against a read only replica.")
+        }
+        err => Reply::error(format!("ERR {}", err)),
+    }
+}
+
+impl Shared {
+    pub(crate) fn new(db: Arc<Mutex<BitCaskHandle>>) -> Self {
+        Self {
+            db,
+            cursors: Mutex::default(),
+            follower: Mutex::default(),
+            started: Instant::now(),
+            connected_clients: AtomicUsize::new(0),
+            total_connections: AtomicU64::new(0),
+            total_commands: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs one command, `args` holds the command name followed by its arguments.
+    pub(crate) fn execute(&self, args: &[Vec<u8>]) -> Reply {
+        self.total_commands.fetch_add(1, Ordering::Relaxed);
+        let command = String::from_utf8_lossy(&args[0]);
+        let name = command.to_uppercase();
+        let args = &args[1..];
+        let arity_ok = match name.as_str() {
+            "PING" | "INFO" => args.len() <= 1,
+            "QUIT" | "COMMAND" | "DBSIZE" => true,
+            "GET" | "ECHO" | "KEYS" | "TTL" | "PERSIST" => args.len() == 1,
+            "SET" => args.len() >= 2,
+            "EXPIRE" | "REPLICAOF" => args.len() == 2,
+            "DEL" | "EXISTS" | "MGET" | "SCAN" => !args.is_empty(),
+            "MSET" => !args.is_empty() && args.len().is_multiple_of(2),
+            _ => return Reply::error(format!("ERR unknown command '{}'", command)),
+        };
+        if !arity_ok {
+            return wrong_arity(&name);
+        }
+        self.dispatch(&name, args).unwrap_or_else(|err| err)
+    }
+
+    fn dispatch(&self, name: &str, args: &[Vec<u8>]) -> CommandResult {
+        match name {
+            "PING" => Ok(match args.first() {
+                Some(msg) => Reply::bulk(msg.clone()),
+                None => Reply::Simple("PONG".to_string()),
+            }),
+            "ECHO" => Ok(Reply::bulk(args[0].clone())),
+            "QUIT" => Ok(Reply::ok()),
+            // clients probe this on connect, an empty table keeps them happy
+            "COMMAND" => Ok(Reply::Array(vec![])),
+            "GET" => {
+                let value = self.db.lock().unwrap().get(&args[0]);
+                Ok(Reply::Bulk(value.map_err(storage_error)?))
+            }
+            "SET" => self.set(args),
+            "DEL" => self.del(args),
+            "EXISTS" => {
+                let db = self.db.lock().unwrap();
+                let count = args.iter().filter(|key| db.contains_key(key)).count();
+                Ok(Reply::Integer(count as i64))
+            }
+            "MGET" => {
+                let db = self.db.lock().unwrap();
+                let keys: Vec<_> = args.iter().map(Vec::as_slice).collect();
+                let values = db.multi_get(&keys).map_err(storage_error)?;
+                Ok(Reply::Array(values.into_iter().map(Reply::Bulk).collect()))
+            }
+            "MSET" => {
+                let mut db = self.db.lock().unwrap();
+                for pair in args.chunks(2) {
+                    db.put(&pair[0], &pair[1]).map_err(storage_error)?;
+                }
+                Ok(Reply::ok())
+            }
+            "KEYS" => {
+                let pattern = &args[0];
+                let db = self.db.lock().unwrap();
+                let keys = db
+                    .scan(literal_prefix(pattern))
+                    .keys()
+                    .filter(|key| glob_match(pattern, key))
+                    .map(Reply::bulk)
+                    .collect();
+                Ok(Reply::Array(keys))
+            }
+            "SCAN" => self.scan(args),
+            "EXPIRE" => {
+                let secs = parse_int(&args[1])?;
+                // like Redis, a ttl that is not positive deletes the key right away
+                let ttl = u32::try_from(secs.max(0))
+                    .map_err(|_| Reply::error("ERR invalid expire time in 'expire' command"))?;
+                let mut db = self.db.lock().unwrap();
+                let found = db.expire(&args[0], Some(ttl)).map_err(storage_error)?;
+                Ok(Reply::Integer(found as i64))
+            }
+            "TTL" => Ok(Reply::Integer(
+                match self.db.lock().unwrap().ttl(&args[0]) {
+                    None => -2,
+                    Some(None) => -1,
+                    Some(Some(secs)) => secs as i64,
+                },
+            )),
+            "PERSIST" => {
+                let mut db = self.db.lock().unwrap();
+                let persisted = match db.ttl(&args[0]) {
+                    Some(Some(_)) => db.expire(&args[0], None).map_err(storage_error)?,
+                    _ => false,
+                };
+                Ok(Reply::Integer(persisted as i64))
+            }
+            "DBSIZE" => {
+                let db = self.db.lock().unwrap();
+                Ok(Reply::Integer(db.scan(b"").keys().count() as i64))
+            }
+            "INFO" => Ok(Reply::bulk(self.info())),
+            "REPLICAOF" => {
+                // only promotion is supported, a follower is started with the server
+                if !args[0].eq_ignore_ascii_case(b"NO") || !args[1].eq_ignore_ascii_case(b"ONE") {
+                    return Err(Reply::error("ERR only REPLICAOF NO ONE is supported"));
+                }
+                if let Some(follower) = self.follower.lock().unwrap().take() {
+                    follower.promote().map_err(storage_error)?;
+                }
+                Ok(Reply::ok())
+            }
+            _ => unreachable!("arity is checked for every known command"),
+        }
+    }
+
+    fn set(&self, args: &[Vec<u8>]) -> CommandResult {
+        let (key, value) = (&args[0], &args[1]);
+        let mut ttl = None;
+        let (mut nx, mut xx) = (false, false);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" if !xx => nx = true,
+                b"XX" if !nx => xx = true,
+                unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                    let amount = parse_int(options.next().ok_or_else(syntax_error)?)?;
+                    ttl = Some(ttl_secs(amount, unit == b"PX", "set")?);
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let mut db = self.db.lock().unwrap();
+        if nx || xx {
+            let exists = db.ttl(key).is_some();
+            if exists != xx {
+                return Ok(Reply::nil());
+            }
+        }
+        match ttl {
+            Some(ttl) => db.put_with_ttl(key, value, ttl),
+            None => db.put(key, value),
+        }
+        .map_err(storage_error)?;
+        Ok(Reply::ok())
+    }
+
+    fn del(&self, keys: &[Vec<u8>]) -> CommandResult {
+        let mut db = self.db.lock().unwrap();
+        let mut deleted = 0;
+        for key in keys {
+            if db.delete(key).map_err(storage_error)? {
+                deleted += 1;
+            }
+        }
+        Ok(Reply::Integer(deleted))
+    }
+
+    fn scan(&self, args: &[Vec<u8>]) -> CommandResult {
+        let cursor = parse_int(&args[0]).map_err(|_| Reply::error("ERR invalid cursor"))?;
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let value = options.next().ok_or_else(syntax_error)?;
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = Some(value.as_slice()),
+                b"COUNT" => {
+                    count = parse_int(value)?
+                        .try_into()
+                        .ok()
+                        .filter(|count| *count > 0)
+                        .ok_or_else(syntax_error)?;
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let after = match cursor {
+            0 => None,
+            id => {
+                // cursors stay valid after use, so a client can repeat a call whose reply
+                // it lost
+                let cursors = self.cursors.lock().unwrap();
+                let key = u64::try_from(id)
+                    .ok()
+                    .and_then(|id| cursors.open.get(&id).cloned())
+                    .ok_or_else(|| Reply::error("ERR invalid cursor"))?;
+                Some(key)
+            }
+        };
+
+        // like Redis, COUNT bounds the keys examined, not the keys returned
+        let (examined, more) = {
+            let db = self.db.lock().unwrap();
+            let prefix = pattern.map_or(&b""[..], literal_prefix);
+            let iter = match &after {
+                Some(key) => db.scan(prefix).after(key),
+                None => db.scan(prefix),
+            };
+            let mut keys = iter.keys();
+            let examined: Vec<Key> = keys.by_ref().take(count).collect();
+            (examined, keys.next().is_some())
+        };
+
+        let next_cursor = match examined.last() {
+            Some(last) if more => self.cursors.lock().unwrap().insert(last.clone()),
+            _ => 0,
+        };
+        let matched = examined
+            .into_iter()
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(Reply::bulk)
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::bulk(next_cursor.to_string()),
+            Reply::Array(matched),
+        ]))
+    }
+
+    fn info(&self) -> String {
+        let keys = self.db.lock().unwrap().scan(b"").keys().count();
+        let mut info = String::new();
+        info.push_str("# Server\r\n");
+        info.push_str(&format!(
+            "bitcask_version:{}\r\n",
+            env!("CARGO_PKG_VERSION")
+        ));
+        info.push_str(&format!(
+            "uptime_in_seconds:{}\r\n",
+            self.started.elapsed().as_secs()
+        ));
+        info.push_str("\r\n# Clients\r\n");
+        info.push_str(&format!(
+            "connected_clients:{}\r\n",
+            self.connected_clients.load(Ordering::Relaxed)
+        ));
+        info.push_str("\r\n# Stats\r\n");
+        info.push_str(&format!(
+            "total_connections_received:{}\r\n",
+            self.total_connections.load(Ordering::Relaxed)
+        ));
+        info.push_str(&format!(
+            "total_commands_processed:{}\r\n",
+            self.total_commands.load(Ordering::Relaxed)
+        ));
+        info.push_str("\r\n# Replication\r\n");
+        match self.follower.lock().unwrap().as_ref() {
+            Some(follower) => {
+                info.push_str("role:slave\r\n");
+                if let Some(pos) = follower.position() {
+                    info.push_str(&format!(
+                        "master_log_position:{}:{}\r\n",
+                        pos.file_id, pos.offset
+                    ));
+                }
+            }
+            None => info.push_str("role:master\r\n"),
+        }
+        info.push_str("\r\n# Keyspace\r\n");
+        info.push_str(&format!("db0:keys={}\r\n", keys));
+        info
+    }
+}
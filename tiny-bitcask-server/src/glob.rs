@@ -0,0 +1,120 @@
+/// Matches `text` against a Redis style glob pattern: `*` any run of bytes, `?` one byte,
+/// `[abc]`/`[^a-z]` a byte class, and `\` escaping the next byte.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and of the text it is currently extended to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let next = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match class_match(&pattern[p + 1..], text[t]) {
+                Some((matched, len)) => matched.then_some(p + 1 + len),
+                // an unterminated class is a literal `[`
+                None => (text[t] == b'[').then_some(p + 1),
+            },
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(&c) => (c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (next, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class starting after a `[`, returns whether it matched and how many
+/// pattern bytes the class takes including the closing `]`, or `None` if it is not closed.
+fn class_match(class: &[u8], c: u8) -> Option<(bool, usize)> {
+    let negate = class.first() == Some(&b'^');
+    let mut i = negate as usize;
+    let mut matched = false;
+    loop {
+        match *class.get(i)? {
+            b']' => return Some((matched != negate, i + 1)),
+            b'\\' => {
+                matched |= *class.get(i + 1)? == c;
+                i += 2;
+            }
+            lo => match (class.get(i + 1), class.get(i + 2)) {
+                (Some(b'-'), Some(&hi)) if hi != b']' => {
+                    matched |= (lo.min(hi)..=lo.max(hi)).contains(&c);
+                    i += 3;
+                }
+                _ => {
+                    matched |= lo == c;
+                    i += 1;
+                }
+            },
+        }
+    }
+}
+
+/// The bytes every match of `pattern` starts with, used to narrow a key scan.
+pub fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|c| matches!(c, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("user:*", "user:42", true),
+            ("user:*", "users", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeello", true),
+            ("*a*b", "xaybzb", true),
+            ("*a*b", "xaybz", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("key[0-9]", "key7", true),
+            ("key[9-0]", "key7", true),
+            ("key[0-9]", "keyx", false),
+            ("a\\*b", "a*b", true),
+            ("a\\*b", "axb", false),
+            ("a[b", "a[b", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                *expected,
+                "{} ~ {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(literal_prefix(b"user:*:name"), b"user:");
+        assert_eq!(literal_prefix(b"plain"), b"plain");
+        assert_eq!(literal_prefix(b"?x"), b"");
+    }
+}
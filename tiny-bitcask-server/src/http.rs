@@ -0,0 +1,534 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tiny_bitcask::{BitCask, BitCaskError, BitCaskHandle, Histogram, Key};
+
+/// Longest request or header line accepted.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+/// Largest request body accepted.
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 10_000;
+
+/// Serves a store over HTTP, one thread per client connection:
+///
+/// - `GET /kv/{key}` the value as the raw body, `PUT /kv/{key}[?ttl=secs]` sets it to the
+///   request body, `DELETE /kv/{key}` removes it
+/// - `GET /kv?prefix=&cursor=&limit=` lists keys in order, a page at a time; the returned
+///   `cursor` is passed back to get the next page and is null after the last one
+/// - `GET /stats` key count, data file usage, operation counters and latencies as JSON
+/// - `GET /metrics` the same in the Prometheus text format
+/// - `POST /merge` compacts the sealed data files and answers 200 once done; requests made
+///   meanwhile wait for it, `last_merge` in `/stats` tells how it went
+///
+/// Keys in paths, parameters and JSON are percent encoded, so any byte string can be used. A
+/// write to a read only store, such as a follower, is refused with 403.
+pub struct HttpServer {
+    listener: TcpListener,
+    db: Arc<Mutex<BitCaskHandle>>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(Vec<u8>, Vec<u8>)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn empty(status: u16) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: vec![],
+            allow: None,
+        }
+    }
+
+    fn json(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, msg: impl std::fmt::Display) -> Self {
+        Self::json(
+            status,
+            format!("{{\"error\":{}}}", json_string(&msg.to_string())),
+        )
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::error(405, "method not allowed")
+        }
+    }
+
+    fn write_to(&self, out: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        write!(
+            out,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        if let Some(allow) = self.allow {
+            write!(out, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            out.write_all(b"Connection: close\r\n")?;
+        }
+        out.write_all(b"\r\n")?;
+        out.write_all(&self.body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "",
+    }
+}
+
+/// A request that cannot be answered normally, the connection is closed after the response.
+struct BadRequest(Response);
+
+fn bad_request(status: u16, msg: &str) -> io::Result<Result<Request, BadRequest>> {
+    Ok(Err(BadRequest(Response::error(status, msg))))
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Ok(None);
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(String::from_utf8(line).ok())
+}
+
+/// Reads the next request, `Ok(None)` when the client closed the connection between requests.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Result<Request, BadRequest>>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let Some(request_line) = read_line(reader)? else {
+        return bad_request(400, "malformed request line").map(Some);
+    };
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return bad_request(400, "malformed request line").map(Some);
+    };
+
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_len = None;
+    let mut headers = 0;
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return bad_request(431, "malformed or too long header").map(Some);
+        };
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return bad_request(431, "too many headers").map(Some);
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return bad_request(400, "malformed header").map(Some);
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => match value.parse::<usize>() {
+                Ok(len) if len <= MAX_BODY_LEN => content_len = Some(len),
+                Ok(_) => return bad_request(413, "body too large").map(Some),
+                Err(_) => return bad_request(400, "invalid content-length").map(Some),
+            },
+            "transfer-encoding" => {
+                return bad_request(501, "transfer encodings are not supported").map(Some)
+            }
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            _ => {}
+        }
+    }
+
+    // grown as the body arrives, a content-length alone does not get memory set aside
+    let content_len = content_len.unwrap_or(0);
+    let mut body = vec![];
+    reader.take(content_len as u64).read_to_end(&mut body)?;
+    if body.len() != content_len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let Some(query) = parse_query(query) else {
+        return bad_request(400, "malformed query string").map(Some);
+    };
+    Ok(Some(Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        body,
+        keep_alive,
+    })))
+}
+
+fn parse_query(query: &str) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((
+                percent_decode(name.as_bytes(), true)?,
+                percent_decode(value.as_bytes(), true)?,
+            ))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(s: &[u8], plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'%' => {
+                // `from_str_radix` alone would take a sign too
+                let hex = s.get(i + 1..i + 3)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                out.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    Some(out)
+}
+
+/// Escapes every byte but the unreserved characters of RFC 3986.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl HttpServer {
+    pub fn bind(addr: impl ToSocketAddrs, db: Arc<Mutex<BitCaskHandle>>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            db,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let db = self.db.clone();
+            thread::spawn(move || {
+                // a client going away mid-request is not an error of the server
+                let _ = handle_connection(&db, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(db: &Mutex<BitCaskHandle>, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(request) = read_request(&mut reader)? {
+        match request {
+            Ok(request) => {
+                handle(db, &request).write_to(&mut writer, request.keep_alive)?;
+                writer.flush()?;
+                if !request.keep_alive {
+                    break;
+                }
+            }
+            Err(BadRequest(response)) => {
+                response.write_to(&mut writer, false)?;
+                break;
+            }
+        }
+    }
+    writer.flush()
+}
+
+fn handle(db: &Mutex<BitCaskHandle>, request: &Request) -> Response {
+    let method = request.method.as_str();
+    if let Some(key) = request.path.strip_prefix("/kv/") {
+        let Some(key) = percent_decode(key.as_bytes(), false).filter(|key| !key.is_empty()) else {
+            return Response::error(400, "malformed key");
+        };
+        return match method {
+            "GET" => get(db, &key),
+            "PUT" => put(db, &key, request),
+            "DELETE" => match db.lock().unwrap().delete(&key) {
+                Ok(true) => Response::empty(204),
+                Ok(false) => Response::error(404, "key not found"),
+                Err(err) => storage_error(err),
+            },
+            _ => Response::method_not_allowed("GET, PUT, DELETE"),
+        };
+    }
+    match (request.path.as_str(), method) {
+        ("/kv", "GET") => list(db, request),
+        ("/stats", "GET") => stats(db),
+        ("/metrics", "GET") => metrics(db),
+        ("/merge", "POST") => merge(db),
+        ("/kv" | "/stats" | "/metrics", _) => Response::method_not_allowed("GET"),
+        ("/merge", _) => Response::method_not_allowed("POST"),
+        _ => Response::error(404, "no such endpoint"),
+    }
+}
+
+/// Answers a failed store operation.
+fn storage_error(err: BitCaskError) -> Response {
+    match err {
+        BitCaskError::ReadOnly => Response::error(403, err),
+        err => Response::error(500, err),
+    }
+}
+
+/// Merges before answering. The merge holds the store lock, so other requests wait for it
+/// like they would for any other write.
+fn merge(db: &Mutex<BitCaskHandle>) -> Response {
+    match db.lock().unwrap().merge() {
+        Ok(()) => Response::empty(200),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn query_param<'a>(request: &'a Request, name: &str) -> Option<&'a [u8]> {
+    request
+        .query
+        .iter()
+        .find(|(param, _)| param == name.as_bytes())
+        .map(|(_, value)| value.as_slice())
+}
+
+fn get(db: &Mutex<BitCaskHandle>, key: &[u8]) -> Response {
+    match db.lock().unwrap().get(key) {
+        Ok(Some(value)) => Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            body: value,
+            allow: None,
+        },
+        Ok(None) => Response::error(404, "key not found"),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn put(db: &Mutex<BitCaskHandle>, key: &[u8], request: &Request) -> Response {
+    let ttl = match query_param(request, "ttl") {
+        None => None,
+        Some(ttl) => match std::str::from_utf8(ttl).ok().and_then(|t| t.parse().ok()) {
+            Some(ttl) if ttl > 0 => Some(ttl),
+            _ => return Response::error(400, "ttl must be a positive number of seconds"),
+        },
+    };
+    let mut db = db.lock().unwrap();
+    let result = match ttl {
+        Some(ttl) => db.put_with_ttl(key, &request.body, ttl),
+        None => db.put(key, &request.body),
+    };
+    match result {
+        Ok(()) => Response::empty(204),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn list(db: &Mutex<BitCaskHandle>, request: &Request) -> Response {
+    let prefix = query_param(request, "prefix").unwrap_or_default();
+    let limit = match query_param(request, "limit") {
+        None => DEFAULT_LIST_LIMIT,
+        Some(limit) => match std::str::from_utf8(limit).ok().and_then(|l| l.parse().ok()) {
+            Some(limit) if (1..=MAX_LIST_LIMIT).contains(&limit) => limit,
+            _ => {
+                return Response::error(
+                    400,
+                    format!("limit must be between 1 and {}", MAX_LIST_LIMIT),
+                )
+            }
+        },
+    };
+
+    let (keys, more) = {
+        let db = db.lock().unwrap();
+        let iter = match query_param(request, "cursor") {
+            Some(cursor) => db.scan(prefix).after(cursor),
+            None => db.scan(prefix),
+        };
+        let mut iter = iter.keys();
+        let keys: Vec<Key> = iter.by_ref().take(limit).collect();
+        (keys, iter.next().is_some())
+    };
+    // the cursor is the last key of the page, listing resumes right after it
+    let cursor = match keys.last() {
+        Some(last) if more => format!("\"{}\"", percent_encode(last)),
+        _ => "null".to_string(),
+    };
+    let keys: Vec<String> = keys
+        .iter()
+        .map(|key| format!("\"{}\"", percent_encode(key)))
+        .collect();
+    Response::json(
+        200,
+        format!("{{\"keys\":[{}],\"cursor\":{}}}", keys.join(","), cursor),
+    )
+}
+
+fn stats(db: &Mutex<BitCaskHandle>) -> Response {
+    let stats = match db.lock().unwrap().stats() {
+        Ok(stats) => stats,
+        Err(err) => return storage_error(err),
+    };
+    let latency = |histogram: &Histogram| {
+        let micros = |q| {
+            histogram
+                .quantile(q)
+                .map_or("null".to_string(), |d| d.as_micros().to_string())
+        };
+        format!(
+            "{{\"count\":{},\"p50_us\":{},\"p99_us\":{}}}",
+            histogram.count,
+            micros(0.5),
+            micros(0.99)
+        )
+    };
+    let last_merge = match &stats.last_merge {
+        Some(merge) => format!(
+            "{{\"finished_at\":{},\"duration_ms\":{},\"reclaimed_bytes\":{},\"error\":{}}}",
+            merge.finished_at,
+            merge.duration.as_millis(),
+            merge.reclaimed_bytes,
+            merge
+                .error
+                .as_deref()
+                .map_or("null".to_string(), json_string)
+        ),
+        None => "null".to_string(),
+    };
+    Response::json(
+        200,
+        format!(
+            "{{\"keys\":{},\"data_files\":{},\"data_bytes\":{},\"live_bytes\":{},\
+             \"dead_ratio\":{:.4},\"key_dir_bytes\":{},\"gets\":{},\"get_hits\":{},\
+             \"puts\":{},\"deletes\":{},\"bytes_read\":{},\"bytes_written\":{},\
+             \"get_latency\":{},\"put_latency\":{},\"delete_latency\":{},\"last_merge\":{}}}",
+            stats.keys,
+            stats.data_files,
+            stats.data_bytes,
+            stats.live_bytes,
+            stats.dead_ratio(),
+            stats.key_dir_bytes,
+            stats.gets,
+            stats.get_hits,
+            stats.puts,
+            stats.deletes,
+            stats.bytes_read,
+            stats.bytes_written,
+            latency(&stats.get_latency),
+            latency(&stats.put_latency),
+            latency(&stats.delete_latency),
+            last_merge
+        ),
+    )
+}
+
+fn metrics(db: &Mutex<BitCaskHandle>) -> Response {
+    match db.lock().unwrap().stats() {
+        Ok(stats) => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: stats.to_prometheus().into_bytes(),
+            allow: None,
+        },
+        Err(err) => storage_error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_coding() {
+        assert_eq!(percent_decode(b"a%2Fb+c", false).unwrap(), b"a/b+c");
+        assert_eq!(percent_decode(b"a%2fb+c", true).unwrap(), b"a/b c");
+        assert_eq!(percent_decode(b"%zz", false), None);
+        assert_eq!(percent_decode(b"%4", false), None);
+        assert_eq!(percent_decode(b"%+4", false), None);
+        assert_eq!(percent_encode(b"user:1/\x00~"), "user%3A1%2F%00~");
+    }
+}
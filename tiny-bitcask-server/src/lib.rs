@@ -0,0 +1,14 @@
+mod commands;
+mod glob;
+mod http;
+mod memcache;
+mod replication;
+mod resp;
+mod server;
+
+pub use glob::glob_match;
+pub use http::HttpServer;
+pub use memcache::MemcacheServer;
+pub use replication::{Follower, ReplicationServer};
+pub use resp::{read_command, Reply};
+pub use server::Server;
@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+use tiny_bitcask::{BitCask, BitCaskHandle, Opts};
+use tiny_bitcask_server::{Follower, HttpServer, MemcacheServer, ReplicationServer, Server};
+use tracing::error;
+use tracing_subscriber::EnvFilter;
+
+/// Serve a tiny-bitcask data directory over the Redis protocol, and optionally HTTP and the
+/// memcached text protocol. Logs go to stderr, filtered by `RUST_LOG` (`info` by default).
+#[derive(Parser)]
+#[command(name = "bitcask-server")]
+struct Cli {
+    /// data directory of the store
+    #[arg(short, long)]
+    dir: PathBuf,
+    /// address to listen on for Redis clients
+    #[arg(short, long, default_value = "127.0.0.1:6379")]
+    addr: String,
+    /// address to listen on for HTTP requests, no HTTP front end if absent
+    #[arg(long)]
+    http_addr: Option<String>,
+    /// address to listen on for memcached clients, no memcached front end if absent
+    #[arg(long)]
+    memcache_addr: Option<String>,
+    /// address to listen on for followers, no replication if absent
+    #[arg(long)]
+    replication_addr: Option<String>,
+    /// address of a leader's replication listener to follow, the store is read only until
+    /// promoted with REPLICAOF NO ONE
+    #[arg(long)]
+    replicate_from: Option<String>,
+    /// size limit of a single data file in bytes
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    data_file_limit: u32,
+    /// seconds between syncs of the active data file to disk, 0 to never sync explicitly
+    #[arg(long, default_value_t = 1)]
+    sync_interval: u64,
+}
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with_writer(std::io::stderr)
+        .init();
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("bitcask-server: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let db = BitCaskHandle::open(cli.dir, Opts::new(cli.data_file_limit))?;
+    let server = Server::bind(&cli.addr, db)?;
+    if cli.sync_interval > 0 {
+        let db = server.db();
+        let interval = Duration::from_secs(cli.sync_interval);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(err) = db.lock().unwrap().sync() {
+                error!(%err, "sync failed");
+            }
+        });
+    }
+    if let Some(leader_addr) = cli.replicate_from {
+        server.set_follower(Follower::start(leader_addr, server.db())?);
+    }
+    if let Some(replication_addr) = cli.replication_addr {
+        let replication = ReplicationServer::bind(&replication_addr, server.db())?;
+        println!("replication listening on {}", replication.local_addr()?);
+        thread::spawn(move || {
+            if let Err(err) = replication.run() {
+                error!(%err, "replication listener stopped");
+            }
+        });
+    }
+    if let Some(http_addr) = cli.http_addr {
+        let http = HttpServer::bind(&http_addr, server.db())?;
+        println!("http listening on {}", http.local_addr()?);
+        thread::spawn(move || {
+            if let Err(err) = http.run() {
+                error!(%err, "http front end stopped");
+            }
+        });
+    }
+    if let Some(memcache_addr) = cli.memcache_addr {
+        let memcache = MemcacheServer::bind(&memcache_addr, server.db())?;
+        println!("memcache listening on {}", memcache.local_addr()?);
+        thread::spawn(move || {
+            if let Err(err) = memcache.run() {
+                error!(%err, "memcache front end stopped");
+            }
+        });
+    }
+    println!("listening on {}", server.local_addr()?);
+    server.run()?;
+    Ok(())
+}
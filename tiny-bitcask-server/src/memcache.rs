@@ -0,0 +1,501 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use tiny_bitcask::{BitCask, BitCaskHandle, BitCaskResult};
+
+/// Longest command line accepted.
+const MAX_LINE_LEN: u64 = 4096;
+/// Longest key accepted, as in memcached.
+const MAX_KEY_LEN: usize = 250;
+/// Largest value accepted.
+const MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+/// Expiration times up to this many seconds are relative, larger ones are unix timestamps.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+/// Starts the stored value of an item with client flags, see `encode_item`.
+const FLAGS_MAGIC: &[u8; 4] = b"\0mcf";
+
+/// Serves a store over the memcached text protocol, one thread per client connection.
+///
+/// Expiration times map to the per-key ttl of the store, and cas uniques are record versions
+/// (see `BitCaskHandle::version`). Client flags are kept in front of the value, except flags 0:
+/// those items are stored as they are, the same as through the other front ends. A delayed
+/// `flush_all` only hides the items written before its deadline from this server; it lasts
+/// until the server stops.
+pub struct MemcacheServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    db: Arc<Mutex<BitCaskHandle>>,
+    started: Instant,
+    curr_connections: AtomicUsize,
+    total_connections: AtomicU64,
+    cmd_get: AtomicU64,
+    cmd_set: AtomicU64,
+    get_hits: AtomicU64,
+    // unix time a delayed flush_all takes effect at, 0 if none is pending
+    flush_deadline: AtomicU64,
+    // items with a lower version were written before the last flush deadline passed
+    flushed_below: AtomicU64,
+}
+
+/// How the value of a storage command is combined with the existing item.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Store {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+type Reply = Vec<u8>;
+
+fn reply(s: &str) -> Reply {
+    s.as_bytes().to_vec()
+}
+
+fn client_error(msg: &str) -> Reply {
+    format!("CLIENT_ERROR {}\r\n", msg).into_bytes()
+}
+
+fn server_error(err: impl std::fmt::Display) -> Reply {
+    format!("SERVER_ERROR {}\r\n", err).into_bytes()
+}
+
+fn valid_key(key: &[u8]) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && !key.iter().any(u8::is_ascii_control)
+}
+
+fn parse<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// The value stored for an item: `FLAGS_MAGIC` and the flags followed by the data, or just
+/// the data if the flags are 0 and it does not start with the magic itself.
+fn encode_item(flags: u32, data: &[u8]) -> Vec<u8> {
+    if flags == 0 && !data.starts_with(FLAGS_MAGIC) {
+        return data.to_vec();
+    }
+    let mut value = Vec::with_capacity(FLAGS_MAGIC.len() + 4 + data.len());
+    value.extend_from_slice(FLAGS_MAGIC);
+    value.extend_from_slice(&flags.to_le_bytes());
+    value.extend_from_slice(data);
+    value
+}
+
+/// The flags and data of a stored value, see `encode_item`.
+fn decode_item(value: &[u8]) -> (u32, &[u8]) {
+    match value.strip_prefix(FLAGS_MAGIC) {
+        Some(rest) if rest.len() >= 4 => {
+            let (flags, data) = rest.split_at(4);
+            (u32::from_le_bytes(flags.try_into().unwrap()), data)
+        }
+        _ => (0, value),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Converts a memcached expiration time to a ttl: `Ok(None)` never expires, `Err(())` is
+/// already expired.
+fn ttl_from_exptime(exptime: i64) -> Result<Option<u32>, ()> {
+    let secs = match exptime {
+        0 => return Ok(None),
+        exptime if exptime < 0 => return Err(()),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => exptime,
+        timestamp => timestamp - unix_now() as i64,
+    };
+    if secs <= 0 {
+        return Err(());
+    }
+    Ok(Some(u32::try_from(secs).unwrap_or(u32::MAX)))
+}
+
+impl MemcacheServer {
+    pub fn bind(addr: impl ToSocketAddrs, db: Arc<Mutex<BitCaskHandle>>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            shared: Arc::new(Shared {
+                db,
+                started: Instant::now(),
+                curr_connections: AtomicUsize::new(0),
+                total_connections: AtomicU64::new(0),
+                cmd_get: AtomicU64::new(0),
+                cmd_set: AtomicU64::new(0),
+                get_hits: AtomicU64::new(0),
+                flush_deadline: AtomicU64::new(0),
+                flushed_below: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let shared = self.shared.clone();
+            thread::spawn(move || {
+                shared.curr_connections.fetch_add(1, Ordering::Relaxed);
+                shared.total_connections.fetch_add(1, Ordering::Relaxed);
+                // a client going away mid-command is not an error of the server
+                let _ = handle_connection(&shared, stream);
+                shared.curr_connections.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(shared: &Shared, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let mut line = Vec::new();
+        reader
+            .by_ref()
+            .take(MAX_LINE_LEN)
+            .read_until(b'\n', &mut line)?;
+        if line.is_empty() {
+            break;
+        }
+        if line.pop() != Some(b'\n') {
+            writer.write_all(&client_error("line too long"))?;
+            break;
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        let args: Vec<&[u8]> = line
+            .split(|&b| b == b' ')
+            .filter(|arg| !arg.is_empty())
+            .collect();
+        let Some((&command, args)) = args.split_first() else {
+            writer.write_all(b"ERROR\r\n")?;
+            continue;
+        };
+        if command == b"quit" {
+            break;
+        }
+        let reply = match shared.execute(command, args, &mut reader)? {
+            Some(reply) => reply,
+            // the value of a malformed storage command cannot be skipped reliably
+            None => {
+                writer.write_all(&client_error("bad data chunk"))?;
+                break;
+            }
+        };
+        // noreply suppresses the reply, but not error replies
+        let noreply = args.last() == Some(&&b"noreply"[..]);
+        if !noreply || reply.starts_with(b"CLIENT_ERROR") || reply.starts_with(b"SERVER_ERROR") {
+            writer.write_all(&reply)?;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+impl Shared {
+    /// Runs one command and returns its reply. Storage commands read their value from
+    /// `reader`; `None` means the value was not followed by `\r\n`.
+    fn execute(
+        &self,
+        command: &[u8],
+        args: &[&[u8]],
+        reader: &mut impl BufRead,
+    ) -> io::Result<Option<Reply>> {
+        let reply = match command {
+            b"get" | b"gets" => self.get(args, command == b"gets"),
+            b"set" | b"add" | b"replace" | b"cas" => {
+                let mode = match command {
+                    b"set" => Store::Set,
+                    b"add" => Store::Add,
+                    b"replace" => Store::Replace,
+                    _ => match args.get(4).and_then(|cas| parse(cas)) {
+                        Some(cas) => Store::Cas(cas),
+                        None => return Ok(Some(client_error("bad command line format"))),
+                    },
+                };
+                let expected_args = if command == b"cas" { 5 } else { 4 };
+                let (Some(len), true) = (
+                    args.get(3).and_then(|len| parse::<usize>(len)),
+                    (expected_args..=expected_args + 1).contains(&args.len()),
+                ) else {
+                    return Ok(Some(client_error("bad command line format")));
+                };
+                if len > MAX_VALUE_LEN {
+                    io::copy(&mut reader.take(len as u64 + 2), &mut io::sink())?;
+                    return Ok(Some(server_error("object too large for cache")));
+                }
+                let mut value = vec![0; len + 2];
+                reader.read_exact(&mut value)?;
+                if !value.ends_with(b"\r\n") {
+                    return Ok(None);
+                }
+                value.truncate(len);
+                self.store(mode, args, value)
+            }
+            b"delete" => match args {
+                [key] | [key, b"noreply"] => {
+                    let mut db = self.db.lock().unwrap();
+                    let flushed = self.version(&db, key).is_none();
+                    match db.delete(key) {
+                        Ok(true) if !flushed => reply("DELETED\r\n"),
+                        Ok(_) => reply("NOT_FOUND\r\n"),
+                        Err(err) => server_error(err),
+                    }
+                }
+                _ => client_error("bad command line format"),
+            },
+            b"incr" | b"decr" => match args {
+                [key, delta] | [key, delta, b"noreply"] => match parse::<u64>(delta) {
+                    Some(delta) => self.incr(key, delta, command == b"incr"),
+                    None => client_error("invalid numeric delta argument"),
+                },
+                _ => client_error("bad command line format"),
+            },
+            b"flush_all" => {
+                let delay = match args {
+                    [] | [b"noreply"] => Some(0),
+                    [delay] | [delay, b"noreply"] => parse::<u32>(delay),
+                    _ => None,
+                };
+                match delay {
+                    Some(delay) => self.flush_all(delay),
+                    None => client_error("bad command line format"),
+                }
+            }
+            b"stats" if args.is_empty() => self.stats(),
+            b"version" => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+            _ => reply("ERROR\r\n"),
+        };
+        Ok(Some(reply))
+    }
+
+    fn get(&self, keys: &[&[u8]], with_cas: bool) -> Reply {
+        if keys.is_empty() {
+            return reply("ERROR\r\n");
+        }
+        let db = self.db.lock().unwrap();
+        let flushed_below = self.flushed_below(&db);
+        let values = match db.multi_get(keys) {
+            Ok(values) => values,
+            Err(err) => return server_error(err),
+        };
+        let mut reply = Vec::new();
+        for (key, value) in keys.iter().zip(values) {
+            self.cmd_get.fetch_add(1, Ordering::Relaxed);
+            let version = db.version(key).filter(|version| *version >= flushed_below);
+            let (Some(value), Some(version)) = (value, version) else {
+                continue;
+            };
+            self.get_hits.fetch_add(1, Ordering::Relaxed);
+            let (flags, data) = decode_item(&value);
+            reply.extend_from_slice(b"VALUE ");
+            reply.extend_from_slice(key);
+            if with_cas {
+                write!(reply, " {} {} {}\r\n", flags, data.len(), version).unwrap();
+            } else {
+                write!(reply, " {} {}\r\n", flags, data.len()).unwrap();
+            }
+            reply.extend_from_slice(data);
+            reply.extend_from_slice(b"\r\n");
+        }
+        reply.extend_from_slice(b"END\r\n");
+        reply
+    }
+
+    fn store(&self, mode: Store, args: &[&[u8]], data: Vec<u8>) -> Reply {
+        self.cmd_set.fetch_add(1, Ordering::Relaxed);
+        let key = args[0];
+        if !valid_key(key) {
+            return client_error("bad command line format");
+        }
+        let (Some(flags), Some(exptime)) = (parse::<u32>(args[1]), parse::<i64>(args[2])) else {
+            return client_error("bad command line format");
+        };
+        let value = encode_item(flags, &data);
+
+        let mut db = self.db.lock().unwrap();
+        let version = self.version(&db, key);
+        let proceed = match mode {
+            Store::Set => true,
+            Store::Add => version.is_none(),
+            Store::Replace => version.is_some(),
+            Store::Cas(cas) => match version {
+                None => return reply("NOT_FOUND\r\n"),
+                Some(version) => {
+                    if version != cas {
+                        return reply("EXISTS\r\n");
+                    }
+                    true
+                }
+            },
+        };
+        if !proceed {
+            return reply("NOT_STORED\r\n");
+        }
+        let result = match ttl_from_exptime(exptime) {
+            Ok(None) => db.put(key, &value),
+            Ok(Some(ttl)) => db.put_with_ttl(key, &value, ttl),
+            // an item that expires right away is stored and gone
+            Err(()) => db.delete(key).map(|_| ()),
+        };
+        match result {
+            Ok(()) => reply("STORED\r\n"),
+            Err(err) => server_error(err),
+        }
+    }
+
+    fn incr(&self, key: &[u8], delta: u64, incr: bool) -> Reply {
+        let mut db = self.db.lock().unwrap();
+        if self.version(&db, key).is_none() {
+            return reply("NOT_FOUND\r\n");
+        }
+        let value = match db.get(key) {
+            Ok(Some(value)) => value,
+            Ok(None) => return reply("NOT_FOUND\r\n"),
+            Err(err) => return server_error(err),
+        };
+        let (flags, data) = decode_item(&value);
+        let Some(current) = parse::<u64>(data).filter(|_| data.iter().all(u8::is_ascii_digit))
+        else {
+            return client_error("cannot increment or decrement non-numeric value");
+        };
+        // incr wraps around at 64 bits, decr stops at 0
+        let new = if incr {
+            current.wrapping_add(delta)
+        } else {
+            current.saturating_sub(delta)
+        };
+        let new = new.to_string();
+        let value = encode_item(flags, new.as_bytes());
+        let result = match db.ttl(key).flatten() {
+            Some(ttl) => db.put_with_ttl(key, &value, ttl.max(1)),
+            None => db.put(key, &value),
+        };
+        match result {
+            Ok(()) => format!("{}\r\n", new).into_bytes(),
+            Err(err) => server_error(err),
+        }
+    }
+
+    /// Versions below this belong to items a flush_all got rid of. Takes effect of a pending
+    /// delayed flush_all whose deadline has passed; called with the store locked, so no write
+    /// comes between the check and the position taken.
+    fn flushed_below(&self, db: &BitCaskHandle) -> u64 {
+        let deadline = self.flush_deadline.load(Ordering::Relaxed);
+        if deadline != 0 && unix_now() >= deadline {
+            self.flush_deadline.store(0, Ordering::Relaxed);
+            self.flushed_below
+                .store(db.log_end().seq(), Ordering::Relaxed);
+        }
+        self.flushed_below.load(Ordering::Relaxed)
+    }
+
+    /// Version of the item under `key`, `None` if there is none or a flush_all got rid of it.
+    fn version(&self, db: &BitCaskHandle, key: &[u8]) -> Option<u64> {
+        let flushed_below = self.flushed_below(db);
+        db.version(key).filter(|version| *version >= flushed_below)
+    }
+
+    fn flush_all(&self, delay: u32) -> Reply {
+        let mut db = self.db.lock().unwrap();
+        // settles a deadline that passed already before this flush replaces it
+        self.flushed_below(&db);
+        if delay > 0 {
+            self.flush_deadline
+                .store(unix_now() + delay as u64, Ordering::Relaxed);
+            return reply("OK\r\n");
+        }
+        self.flush_deadline.store(0, Ordering::Relaxed);
+        let flush = |db: &mut BitCaskHandle| -> BitCaskResult<()> {
+            for key in db.list_keys() {
+                db.delete(&key)?;
+            }
+            Ok(())
+        };
+        match flush(&mut db) {
+            Ok(()) => reply("OK\r\n"),
+            Err(err) => server_error(err),
+        }
+    }
+
+    fn stats(&self) -> Reply {
+        let curr_items = self.db.lock().unwrap().list_keys().len();
+        let get_hits = self.get_hits.load(Ordering::Relaxed);
+        let cmd_get = self.cmd_get.load(Ordering::Relaxed);
+        let now = unix_now();
+        let stats: [(&str, String); 10] = [
+            ("pid", std::process::id().to_string()),
+            ("uptime", self.started.elapsed().as_secs().to_string()),
+            ("time", now.to_string()),
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            (
+                "curr_connections",
+                self.curr_connections.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "total_connections",
+                self.total_connections.load(Ordering::Relaxed).to_string(),
+            ),
+            ("cmd_get", cmd_get.to_string()),
+            ("cmd_set", self.cmd_set.load(Ordering::Relaxed).to_string()),
+            ("get_hits", get_hits.to_string()),
+            ("get_misses", (cmd_get - get_hits).to_string()),
+        ];
+        let mut reply = String::new();
+        for (name, value) in stats {
+            reply.push_str(&format!("STAT {} {}\r\n", name, value));
+        }
+        reply.push_str(&format!("STAT curr_items {}\r\nEND\r\n", curr_items));
+        reply.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_from_exptime() {
+        assert_eq!(ttl_from_exptime(0), Ok(None));
+        assert_eq!(ttl_from_exptime(-1), Err(()));
+        assert_eq!(ttl_from_exptime(100), Ok(Some(100)));
+        assert_eq!(ttl_from_exptime(MAX_RELATIVE_EXPTIME + 1), Err(()));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        assert!(matches!(ttl_from_exptime(now + 100), Ok(Some(99..=100))));
+    }
+
+    #[test]
+    fn test_item_encoding() {
+        assert_eq!(encode_item(0, b"plain"), b"plain");
+        assert_eq!(decode_item(b"plain"), (0, &b"plain"[..]));
+        for (flags, data) in [(7, &b"data"[..]), (u32::MAX, b""), (0, b"\0mcf\0\0\0\0x")] {
+            let value = encode_item(flags, data);
+            assert_eq!(value.len(), data.len() + 8);
+            assert_eq!(decode_item(&value), (flags, data));
+        }
+        // too short to carry flags
+        assert_eq!(decode_item(b"\0mcf"), (0, &b"\0mcf"[..]));
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tiny_bitcask::{
+    BitCask, BitCaskError, BitCaskHandle, BitCaskResult, Key, LogPosition, LogRecord,
+};
+use tracing::{info, warn};
+
+// Followers send one request when they connect: `F` for a full sync, or `P` with the position
+// to continue from. The leader then streams `R` records, each run followed by a `P` position
+// the follower may persist once the records are applied. A full sync starts with `S`, which
+// tells the follower to drop the keys not sent before the next `P`. A record carries the
+// version of the write, so compare and swap values stay the same on the follower.
+const FULL_SYNC: u8 = b'F';
+const SNAPSHOT: u8 = b'S';
+const RECORD: u8 = b'R';
+const POSITION: u8 = b'P';
+// value size of a delete
+const NO_VALUE: u32 = u32::MAX;
+
+/// Log bytes read under the lock in one go.
+const CHUNK_SIZE: usize = 1024 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// An idle leader repeats its position this often, so dead followers are noticed.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Records a follower applies under one lock.
+const APPLY_BATCH: usize = 1024;
+
+fn storage_error(err: BitCaskError) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+fn write_position(writer: &mut impl Write, pos: LogPosition) -> io::Result<()> {
+    writer.write_all(&[POSITION])?;
+    writer.write_all(&pos.file_id.to_le_bytes())?;
+    writer.write_all(&pos.offset.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_position(reader: &mut impl Read) -> io::Result<LogPosition> {
+    Ok(LogPosition::new(read_u32(reader)?, read_u32(reader)?))
+}
+
+fn write_record(writer: &mut impl Write, record: &LogRecord) -> io::Result<()> {
+    writer.write_all(&[RECORD])?;
+    writer.write_all(&record.tstamp.to_le_bytes())?;
+    writer.write_all(&record.expire_at.to_le_bytes())?;
+    writer.write_all(&record.version.to_le_bytes())?;
+    writer.write_all(&(record.key.len() as u32).to_le_bytes())?;
+    let value_sz = record
+        .value
+        .as_ref()
+        .map_or(NO_VALUE, |value| value.len() as u32);
+    writer.write_all(&value_sz.to_le_bytes())?;
+    writer.write_all(&record.key)?;
+    if let Some(value) = &record.value {
+        writer.write_all(value)?;
+    }
+    Ok(())
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<LogRecord> {
+    let tstamp = read_u32(reader)?;
+    let expire_at = read_u32(reader)?;
+    let version = read_u64(reader)?;
+    let key_sz = read_u32(reader)?;
+    let value_sz = read_u32(reader)?;
+    let mut key = vec![];
+    reader.take(key_sz as u64).read_to_end(&mut key)?;
+    let value = match value_sz {
+        NO_VALUE => None,
+        value_sz => {
+            let mut value = vec![];
+            reader.take(value_sz as u64).read_to_end(&mut value)?;
+            Some(value)
+        }
+    };
+    if key.len() != key_sz as usize || value.as_ref().is_some_and(|v| v.len() != value_sz as usize)
+    {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(LogRecord {
+        tstamp,
+        expire_at,
+        version,
+        key,
+        value,
+    })
+}
+
+/// Serves the log of a store to followers, one thread per follower.
+pub struct ReplicationServer {
+    listener: TcpListener,
+    db: Arc<Mutex<BitCaskHandle>>,
+}
+
+impl ReplicationServer {
+    pub fn bind(addr: impl ToSocketAddrs, db: Arc<Mutex<BitCaskHandle>>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            db,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts followers until accepting fails.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let db = self.db.clone();
+            // a follower going away is not an error of the leader
+            thread::spawn(move || serve_follower(&db, stream));
+        }
+        Ok(())
+    }
+}
+
+fn serve_follower(db: &Mutex<BitCaskHandle>, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut request = [0];
+    reader.read_exact(&mut request)?;
+    let mut pos = match request[0] {
+        POSITION => read_position(&mut reader)?,
+        FULL_SYNC => full_sync(db, &mut writer)?,
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+    let mut last_sent = Instant::now();
+    loop {
+        let read = db.lock().unwrap().read_log(pos, CHUNK_SIZE);
+        match read {
+            Ok((records, next)) if next == pos => {
+                debug_assert!(records.is_empty());
+                if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                    write_position(&mut writer, pos)?;
+                    writer.flush()?;
+                    last_sent = Instant::now();
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            Ok((records, next)) => {
+                for record in &records {
+                    write_record(&mut writer, record)?;
+                }
+                write_position(&mut writer, next)?;
+                writer.flush()?;
+                last_sent = Instant::now();
+                pos = next;
+            }
+            Err(BitCaskError::UnknownLogPosition) => pos = full_sync(db, &mut writer)?,
+            Err(err) => return Err(storage_error(err)),
+        }
+    }
+}
+
+/// Sends every live key of a snapshot, returns the position the log continues from.
+fn full_sync(db: &Mutex<BitCaskHandle>, writer: &mut impl Write) -> io::Result<LogPosition> {
+    let (snapshot, pos) = {
+        let db = db.lock().unwrap();
+        (db.snapshot(), db.log_end())
+    };
+    info!(position = ?pos, "sending a full sync to a follower");
+    writer.write_all(&[SNAPSHOT])?;
+    for record in snapshot.records() {
+        write_record(writer, &record.map_err(storage_error)?)?;
+    }
+    write_position(writer, pos)?;
+    writer.flush()?;
+    Ok(pos)
+}
+
+#[derive(Default)]
+struct FollowerState {
+    stop: AtomicBool,
+    // the connection to the leader, shut down to interrupt a blocked read
+    stream: Mutex<Option<TcpStream>>,
+    position: Mutex<Option<LogPosition>>,
+}
+
+/// Keeps a store in sync with a leader from a background thread. The store is read only while
+/// followed. The applied position is saved in the store's directory, so a follower restarted on
+/// the same directory continues where it stopped.
+pub struct Follower {
+    db: Arc<Mutex<BitCaskHandle>>,
+    state: Arc<FollowerState>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Follower {
+    /// Starts following the leader at `leader_addr`, reconnecting whenever the connection is
+    /// lost.
+    pub fn start(
+        leader_addr: impl Into<String>,
+        db: Arc<Mutex<BitCaskHandle>>,
+    ) -> BitCaskResult<Self> {
+        let position = {
+            let mut db = db.lock().unwrap();
+            let position = LogPosition::load(db.dir())?;
+            db.set_read_only(true);
+            position
+        };
+        let state = Arc::new(FollowerState {
+            position: Mutex::new(position),
+            ..Default::default()
+        });
+        let leader_addr = leader_addr.into();
+        let thread = {
+            let db = db.clone();
+            let state = state.clone();
+            thread::spawn(move || follow(&leader_addr, &db, &state))
+        };
+        Ok(Self {
+            db,
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// The position in the leader's log up to which the store is in sync and saved.
+    pub fn position(&self) -> Option<LogPosition> {
+        *self.state.position.lock().unwrap()
+    }
+
+    /// Stops following and makes the store writable. The saved position is removed, following
+    /// a leader again starts with a full sync.
+    pub fn promote(mut self) -> BitCaskResult<()> {
+        self.stop();
+        let mut db = self.db.lock().unwrap();
+        LogPosition::clear(db.dir())?;
+        db.set_read_only(false);
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.state.stop.store(true, Ordering::SeqCst);
+        if let Some(stream) = self.state.stream.lock().unwrap().as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Follower {
+    /// Stops following, the store stays read only.
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn follow(leader_addr: &str, db: &Mutex<BitCaskHandle>, state: &FollowerState) {
+    while !state.stop.load(Ordering::SeqCst) {
+        if let Err(err) = follow_once(leader_addr, db, state) {
+            if !state.stop.load(Ordering::SeqCst) {
+                warn!(leader = leader_addr, %err, "replication failed, reconnecting");
+            }
+        }
+        *state.stream.lock().unwrap() = None;
+        let deadline = Instant::now() + RECONNECT_DELAY;
+        while !state.stop.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn connect(leader_addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address");
+    for addr in leader_addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+fn follow_once(
+    leader_addr: &str,
+    db: &Mutex<BitCaskHandle>,
+    state: &FollowerState,
+) -> io::Result<()> {
+    let stream = connect(leader_addr)?;
+    stream.set_nodelay(true)?;
+    *state.stream.lock().unwrap() = Some(stream.try_clone()?);
+    // checked after publishing the stream, so a concurrent stop either sees it or is seen here
+    if state.stop.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let mut writer = stream.try_clone()?;
+    match *state.position.lock().unwrap() {
+        Some(pos) => write_position(&mut writer, pos)?,
+        None => writer.write_all(&[FULL_SYNC])?,
+    }
+    let mut reader = BufReader::new(stream);
+    let mut pending = vec![];
+    // during a full sync, the keys of the store not sent by the leader yet
+    let mut stale: Option<BTreeSet<Key>> = None;
+    loop {
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        match tag[0] {
+            SNAPSHOT => {
+                pending.clear();
+                stale = Some(db.lock().unwrap().list_keys().into_iter().collect());
+            }
+            RECORD => {
+                pending.push(read_record(&mut reader)?);
+                if pending.len() >= APPLY_BATCH {
+                    apply(db, &mut pending, stale.as_mut())?;
+                }
+            }
+            POSITION => {
+                let pos = read_position(&mut reader)?;
+                apply(db, &mut pending, stale.as_mut())?;
+                let mut db = db.lock().unwrap();
+                if let Some(stale) = stale.take() {
+                    for key in stale {
+                        // nothing reads the timestamp or version of a delete
+                        db.apply(&LogRecord {
+                            tstamp: 0,
+                            expire_at: 0,
+                            version: 0,
+                            key,
+                            value: None,
+                        })
+                        .map_err(storage_error)?;
+                    }
+                }
+                if *state.position.lock().unwrap() != Some(pos) {
+                    db.sync().map_err(storage_error)?;
+                    pos.save(db.dir()).map_err(storage_error)?;
+                    *state.position.lock().unwrap() = Some(pos);
+                }
+            }
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+fn apply(
+    db: &Mutex<BitCaskHandle>,
+    pending: &mut Vec<LogRecord>,
+    mut stale: Option<&mut BTreeSet<Key>>,
+) -> io::Result<()> {
+    let mut db = db.lock().unwrap();
+    for record in pending.drain(..) {
+        if let Some(stale) = stale.as_mut() {
+            stale.remove(&record.key);
+        }
+        db.apply(&record).map_err(storage_error)?;
+    }
+    Ok(())
+}
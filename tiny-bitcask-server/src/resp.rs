@@ -0,0 +1,191 @@
+use std::io::{self, BufRead, Read, Write};
+
+/// Longest inline command or protocol line accepted.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Most arguments accepted in one command.
+const MAX_ARGS: i64 = 1024 * 1024;
+/// Largest bulk string accepted.
+const MAX_BULK_LEN: i64 = 64 * 1024 * 1024;
+
+/// A RESP reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn ok() -> Self {
+        Reply::Simple("OK".to_string())
+    }
+
+    pub fn nil() -> Self {
+        Reply::Bulk(None)
+    }
+
+    pub fn bulk(bytes: impl Into<Vec<u8>>) -> Self {
+        Reply::Bulk(Some(bytes.into()))
+    }
+
+    pub fn error(msg: impl Into<String>) -> Self {
+        Reply::Error(msg.into())
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(out, "+{}\r\n", s),
+            // a line break would end the error early and desync the client
+            Reply::Error(s) => write!(out, "-{}\r\n", s.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(out))
+            }
+        }
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a line without its terminator, `None` at end of input.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if read > MAX_LINE_LEN {
+            protocol_error("too big inline request")
+        } else {
+            io::ErrorKind::UnexpectedEof.into()
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Reads the next command, either a RESP array of bulk strings as sent by clients, or an
+/// inline command typed by hand, e.g. over telnet. Returns `None` at end of input; malformed
+/// input is an `InvalidData` error after which the stream cannot be resynchronized.
+pub fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return Ok(None);
+        };
+        if line.first() != Some(&b'*') {
+            let args: Vec<Vec<u8>> = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if !args.is_empty() {
+                return Ok(Some(args));
+            }
+            continue;
+        }
+
+        let count = parse_len(&line[1..])
+            .filter(|count| *count <= MAX_ARGS)
+            .ok_or_else(|| protocol_error("invalid multibulk length"))?;
+        if count <= 0 {
+            continue;
+        }
+        let mut args = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            if line.first() != Some(&b'$') {
+                return Err(protocol_error("expected '$'"));
+            }
+            let len = parse_len(&line[1..])
+                .filter(|len| (0..=MAX_BULK_LEN).contains(len))
+                .ok_or_else(|| protocol_error("invalid bulk length"))?
+                as usize;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol_error("bulk string not terminated by CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(input: &[u8]) -> Vec<Vec<Vec<u8>>> {
+        let mut reader = input;
+        let mut commands = vec![];
+        while let Some(args) = read_command(&mut reader).unwrap() {
+            commands.push(args);
+        }
+        commands
+    }
+
+    #[test]
+    fn test_read_command() {
+        let commands =
+            parse_all(b"*2\r\n$3\r\nGET\r\n$4\r\na\r\nb\r\n\r\nset  k v\n*0\r\nPING\r\n");
+        assert_eq!(
+            commands,
+            vec![
+                vec![b"GET".to_vec(), b"a\r\nb".to_vec()],
+                vec![b"set".to_vec(), b"k".to_vec(), b"v".to_vec()],
+                vec![b"PING".to_vec()],
+            ]
+        );
+
+        for bad in [
+            &b"*x\r\n"[..],
+            b"*1\r\n:1\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n$1\r\nab\r\n",
+        ] {
+            let err = read_command(&mut &bad[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        let err = read_command(&mut &b"*2\r\n$1\r\na\r\n"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_write_reply() {
+        let reply = Reply::Array(vec![
+            Reply::ok(),
+            Reply::error("ERR bad\nthing"),
+            Reply::Integer(-2),
+            Reply::bulk("v"),
+            Reply::nil(),
+        ]);
+        let mut out = vec![];
+        reply.write_to(&mut out).unwrap();
+        assert_eq!(
+            out,
+            b"*5\r\n+OK\r\n-ERR bad thing\r\n:-2\r\n$1\r\nv\r\n$-1\r\n"
+        );
+    }
+}
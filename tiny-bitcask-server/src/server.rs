@@ -0,0 +1,92 @@
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tiny_bitcask::BitCaskHandle;
+
+use crate::commands::Shared;
+use crate::replication::Follower;
+use crate::resp::{read_command, Reply};
+
+/// Serves a store over the Redis protocol, one thread per client connection. Commands of all
+/// clients are applied one at a time under a lock around the handle.
+pub struct Server {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, db: BitCaskHandle) -> io::Result<Self> {
+        Self::bind_shared(addr, Arc::new(Mutex::new(db)))
+    }
+
+    /// Like `bind`, for a store also served by other front ends.
+    pub fn bind_shared(
+        addr: impl ToSocketAddrs,
+        db: Arc<Mutex<BitCaskHandle>>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            shared: Arc::new(Shared::new(db)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The served store, for background work such as periodic syncs.
+    pub fn db(&self) -> Arc<Mutex<BitCaskHandle>> {
+        self.shared.db.clone()
+    }
+
+    /// Serves the store as a follower of `follower`'s leader, until a client promotes it with
+    /// `REPLICAOF NO ONE`.
+    pub fn set_follower(&self, follower: Follower) {
+        *self.shared.follower.lock().unwrap() = Some(follower);
+    }
+
+    /// Accepts connections until accepting fails.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let shared = self.shared.clone();
+            thread::spawn(move || {
+                shared.connected_clients.fetch_add(1, Ordering::Relaxed);
+                shared.total_connections.fetch_add(1, Ordering::Relaxed);
+                // a client going away mid-command is not an error of the server
+                let _ = handle_connection(&shared, stream);
+                shared.connected_clients.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(shared: &Shared, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Reply::error(format!("ERR Protocol error: {}", err)).write_to(&mut writer)?;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        shared.execute(&args).write_to(&mut writer)?;
+        if args[0].eq_ignore_ascii_case(b"QUIT") {
+            break;
+        }
+        // answer a pipelined batch with a single write
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
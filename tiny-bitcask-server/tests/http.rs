@@ -0,0 +1,173 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tiny_bitcask::{BitCask, BitCaskHandle, Opts};
+use tiny_bitcask_server::HttpServer;
+
+fn start_server(name: &str, data_file_limit: u32) -> SocketAddr {
+    start_server_with_db(name, data_file_limit).0
+}
+
+fn start_server_with_db(
+    name: &str,
+    data_file_limit: u32,
+) -> (SocketAddr, Arc<Mutex<BitCaskHandle>>) {
+    let dir = PathBuf::from("/tmp").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = Arc::new(Mutex::new(
+        BitCaskHandle::open(dir, Opts::new(data_file_limit)).unwrap(),
+    ));
+    let server = HttpServer::bind("127.0.0.1:0", db.clone()).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    (addr, db)
+}
+
+/// Sends one request on its own connection, returns status and body.
+fn request(addr: SocketAddr, method: &str, target: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        target,
+        body.len()
+    )
+    .unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).unwrap();
+    let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let status = std::str::from_utf8(&response[9..12])
+        .unwrap()
+        .parse()
+        .unwrap();
+    (status, response[header_end + 4..].to_vec())
+}
+
+fn text(body: Vec<u8>) -> String {
+    String::from_utf8(body).unwrap()
+}
+
+fn data_files(addr: SocketAddr) -> u64 {
+    let stats = text(request(addr, "GET", "/stats", b"").1);
+    let (_, rest) = stats.split_once("\"data_files\":").unwrap();
+    rest.split(',').next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn test_kv() {
+    let addr = start_server("bitcask_http_kv_test", 1024 * 1024);
+    assert_eq!(request(addr, "PUT", "/kv/hello", b"world").0, 204);
+    assert_eq!(
+        request(addr, "GET", "/kv/hello", b""),
+        (200, b"world".to_vec())
+    );
+    assert_eq!(request(addr, "GET", "/kv/missing", b"").0, 404);
+
+    // binary keys and values
+    assert_eq!(request(addr, "PUT", "/kv/a%2Fb%00", &[0, 255, 10]).0, 204);
+    assert_eq!(
+        request(addr, "GET", "/kv/a%2fb%00", b""),
+        (200, vec![0, 255, 10])
+    );
+
+    assert_eq!(request(addr, "DELETE", "/kv/hello", b"").0, 204);
+    assert_eq!(request(addr, "DELETE", "/kv/hello", b"").0, 404);
+    assert_eq!(request(addr, "GET", "/kv/hello", b"").0, 404);
+
+    assert_eq!(request(addr, "PUT", "/kv/t?ttl=100", b"v").0, 204);
+    assert_eq!(request(addr, "PUT", "/kv/t?ttl=0", b"v").0, 400);
+    assert_eq!(request(addr, "PUT", "/kv/%zz", b"v").0, 400);
+    assert_eq!(request(addr, "POST", "/kv/hello", b"").0, 405);
+    assert_eq!(request(addr, "GET", "/nope", b"").0, 404);
+}
+
+#[test]
+fn test_list() {
+    let addr = start_server("bitcask_http_list_test", 1024 * 1024);
+    for key in ["a", "b 1", "b 2", "b 3", "c"] {
+        let target = format!("/kv/{}", key.replace(' ', "%20"));
+        assert_eq!(request(addr, "PUT", &target, b"v").0, 204);
+    }
+    let (status, body) = request(addr, "GET", "/kv?prefix=b+&limit=2", b"");
+    assert_eq!(status, 200);
+    assert_eq!(text(body), r#"{"keys":["b%201","b%202"],"cursor":"b%202"}"#);
+    let (_, body) = request(addr, "GET", "/kv?prefix=b%20&limit=2&cursor=b%202", b"");
+    assert_eq!(text(body), r#"{"keys":["b%203"],"cursor":null}"#);
+    let (_, body) = request(addr, "GET", "/kv", b"");
+    assert_eq!(
+        text(body),
+        r#"{"keys":["a","b%201","b%202","b%203","c"],"cursor":null}"#
+    );
+    assert_eq!(request(addr, "GET", "/kv?limit=0", b"").0, 400);
+}
+
+#[test]
+fn test_stats_and_merge() {
+    let addr = start_server("bitcask_http_merge_test", 64);
+    for i in 0..10 {
+        assert_eq!(
+            request(addr, "PUT", "/kv/key", format!("value{}", i).as_bytes()).0,
+            204
+        );
+    }
+    let (status, body) = request(addr, "GET", "/stats", b"");
+    assert_eq!(status, 200);
+    let body = text(body);
+    assert!(body.starts_with(r#"{"keys":1,"data_files":"#));
+    assert!(body.contains(r#""puts":10,"#));
+    assert!(body.ends_with(r#""last_merge":null}"#));
+    let before = data_files(addr);
+    assert!(before > 2);
+
+    assert_eq!(request(addr, "POST", "/merge", b"").0, 200);
+    assert_eq!(request(addr, "GET", "/merge", b"").0, 405);
+    assert!(data_files(addr) < before);
+    let (status, body) = request(addr, "GET", "/metrics", b"");
+    assert_eq!(status, 200);
+    let body = text(body);
+    assert!(body.contains("\nbitcask_keys 1\n"));
+    assert!(body.contains("bitcask_last_merge_success 1\n"));
+    assert_eq!(request(addr, "POST", "/metrics", b"").0, 405);
+    assert_eq!(
+        request(addr, "GET", "/kv/key", b""),
+        (200, b"value9".to_vec())
+    );
+}
+
+#[test]
+fn test_read_only() {
+    let (addr, db) = start_server_with_db("bitcask_http_read_only_test", 1024 * 1024);
+    assert_eq!(request(addr, "PUT", "/kv/k", b"v").0, 204);
+    db.lock().unwrap().set_read_only(true);
+    assert_eq!(request(addr, "PUT", "/kv/k", b"other").0, 403);
+    assert_eq!(request(addr, "DELETE", "/kv/k", b"").0, 403);
+    assert_eq!(request(addr, "GET", "/kv/k", b""), (200, b"v".to_vec()));
+}
+
+#[test]
+fn test_keep_alive() {
+    let addr = start_server("bitcask_http_keep_alive_test", 1024 * 1024);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"PUT /kv/k HTTP/1.1\r\nContent-Length: 1\r\n\r\nvGET /kv/k HTTP/1.1\r\n\r\n")
+        .unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "HTTP/1.1 204 No Content\r\n");
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+    }
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+}
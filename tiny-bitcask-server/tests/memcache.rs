@@ -0,0 +1,252 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tiny_bitcask::{BitCask, BitCaskHandle, Opts};
+use tiny_bitcask_server::MemcacheServer;
+
+fn start_server(name: &str) -> SocketAddr {
+    let dir = PathBuf::from("/tmp").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = BitCaskHandle::open(dir, Opts::new(1024 * 1024)).unwrap();
+    let server = MemcacheServer::bind("127.0.0.1:0", Arc::new(Mutex::new(db))).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        Self {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim_end_matches("\r\n").to_string()
+    }
+
+    /// Sends a request and returns the reply lines up to and including `last`.
+    fn call_until(&mut self, request: &str, last: &str) -> Vec<String> {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut lines = vec![];
+        loop {
+            let line = self.line();
+            let done = line == last;
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+
+    /// Sends a request with a single line reply.
+    fn call(&mut self, request: &str) -> String {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        self.line()
+    }
+}
+
+#[test]
+fn test_storage_commands() {
+    let mut client = Client::connect(start_server("bitcask_memcache_storage_test"));
+    assert_eq!(client.call("set k 0 0 5\r\nhello\r\n"), "STORED");
+    assert_eq!(
+        client.call_until("get k missing\r\n", "END"),
+        ["VALUE k 0 5", "hello", "END"]
+    );
+    assert_eq!(client.call("add k 0 0 1\r\nx\r\n"), "NOT_STORED");
+    assert_eq!(client.call("add n 0 0 1\r\nx\r\n"), "STORED");
+    assert_eq!(client.call("replace missing 0 0 1\r\nx\r\n"), "NOT_STORED");
+    assert_eq!(client.call("replace n 0 0 1\r\ny\r\n"), "STORED");
+    assert_eq!(client.call("delete n\r\n"), "DELETED");
+    assert_eq!(client.call("delete n\r\n"), "NOT_FOUND");
+
+    // noreply: only the get answers
+    client
+        .writer
+        .write_all(b"set q 0 0 1 noreply\r\nq\r\n")
+        .unwrap();
+    assert_eq!(
+        client.call_until("get q\r\n", "END"),
+        ["VALUE q 0 1", "q", "END"]
+    );
+
+    // client flags come back with the value
+    assert_eq!(client.call("set f 4294967295 0 1\r\nx\r\n"), "STORED");
+    assert_eq!(
+        client.call_until("get f\r\n", "END"),
+        ["VALUE f 4294967295 1", "x", "END"]
+    );
+    assert_eq!(client.call("set f 5 0 2\r\n42\r\n"), "STORED");
+    assert_eq!(client.call("incr f 1\r\n"), "43");
+    let lines = client.call_until("gets f\r\n", "END");
+    assert!(lines[0].starts_with("VALUE f 5 2 "));
+    assert_eq!(lines[1], "43");
+    assert_eq!(
+        client.call("set k 0 0 x\r\n"),
+        "CLIENT_ERROR bad command line format"
+    );
+    assert_eq!(client.call("bogus\r\n"), "ERROR");
+    assert!(client.call("version\r\n").starts_with("VERSION "));
+}
+
+#[test]
+fn test_cas() {
+    let mut client = Client::connect(start_server("bitcask_memcache_cas_test"));
+    assert_eq!(client.call("cas k 0 0 1 1\r\nx\r\n"), "NOT_FOUND");
+    client.call("set k 0 0 2\r\nv1\r\n");
+    let lines = client.call_until("gets k\r\n", "END");
+    let cas: u64 = lines[0].rsplit(' ').next().unwrap().parse().unwrap();
+    assert_eq!(lines[0], format!("VALUE k 0 2 {}", cas));
+
+    assert_eq!(
+        client.call(&format!("cas k 0 0 2 {}\r\nv2\r\n", cas)),
+        "STORED"
+    );
+    // the write changed the version, the same unique is stale now
+    assert_eq!(
+        client.call(&format!("cas k 0 0 2 {}\r\nv3\r\n", cas)),
+        "EXISTS"
+    );
+    assert_eq!(
+        client.call_until("get k\r\n", "END"),
+        ["VALUE k 0 2", "v2", "END"]
+    );
+}
+
+#[test]
+fn test_cas_across_merge() {
+    let dir = PathBuf::from("/tmp/bitcask_memcache_cas_merge_test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = Arc::new(Mutex::new(
+        BitCaskHandle::open(dir, Opts::new(256)).unwrap(),
+    ));
+    let server = MemcacheServer::bind("127.0.0.1:0", db.clone()).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut client = Client::connect(addr);
+    client.call("set k 3 0 2\r\nv1\r\n");
+    let lines = client.call_until("gets k\r\n", "END");
+    let cas: u64 = lines[0].rsplit(' ').next().unwrap().parse().unwrap();
+    for i in 0..20 {
+        client.call(&format!(
+            "set filler{} 0 0 40\r\n{}\r\n",
+            i % 4,
+            "x".repeat(40)
+        ));
+    }
+    {
+        let mut db = db.lock().unwrap();
+        db.merge().unwrap();
+        assert_eq!(db.stats().unwrap().data_files, 2);
+    }
+    // the item moved to the merged file, its unique stays valid
+    assert_eq!(
+        client.call_until("gets k\r\n", "END")[0],
+        format!("VALUE k 3 2 {}", cas)
+    );
+    assert_eq!(
+        client.call(&format!("cas k 3 0 2 {}\r\nv2\r\n", cas)),
+        "STORED"
+    );
+}
+
+#[test]
+fn test_incr_decr() {
+    let mut client = Client::connect(start_server("bitcask_memcache_incr_test"));
+    assert_eq!(client.call("incr n 1\r\n"), "NOT_FOUND");
+    client.call("set n 0 0 2\r\n10\r\n");
+    assert_eq!(client.call("incr n 5\r\n"), "15");
+    assert_eq!(client.call("decr n 20\r\n"), "0");
+    client.call("set n 0 0 20\r\n18446744073709551615\r\n");
+    assert_eq!(client.call("incr n 2\r\n"), "1");
+    client.call("set s 0 0 3\r\nabc\r\n");
+    assert_eq!(
+        client.call("incr s 1\r\n"),
+        "CLIENT_ERROR cannot increment or decrement non-numeric value"
+    );
+}
+
+#[test]
+fn test_expiration_and_flush() {
+    let mut client = Client::connect(start_server("bitcask_memcache_expire_test"));
+    client.call("set gone 0 -1 1\r\nx\r\n");
+    assert_eq!(client.call_until("get gone\r\n", "END"), ["END"]);
+    client.call("set later 0 100 1\r\nx\r\n");
+    client.call("set forever 0 0 1\r\nx\r\n");
+    assert_eq!(client.call_until("get later\r\n", "END").len(), 3);
+
+    let stats = client.call_until("stats\r\n", "END");
+    assert!(stats.contains(&"STAT curr_items 2".to_string()));
+    assert_eq!(client.call("flush_all\r\n"), "OK");
+    assert_eq!(client.call_until("get later forever\r\n", "END"), ["END"]);
+}
+
+#[test]
+fn test_delayed_flush() {
+    let dir = PathBuf::from("/tmp/bitcask_memcache_delayed_flush_test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = Arc::new(Mutex::new(
+        BitCaskHandle::open(dir, Opts::new(1024 * 1024)).unwrap(),
+    ));
+    let server = MemcacheServer::bind("127.0.0.1:0", db.clone()).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut client = Client::connect(addr);
+    client.call("set a 0 1000 1\r\nx\r\n");
+    client.call("set b 0 0 1\r\n1\r\n");
+    client.call("set c 0 0 1\r\nx\r\n");
+    assert_eq!(client.call("flush_all 1\r\n"), "OK");
+    // nothing is rewritten, the items are there until the deadline
+    assert!((999..=1000).contains(&db.lock().unwrap().ttl(b"a").unwrap().unwrap()));
+    assert_eq!(client.call_until("get a b c\r\n", "END").len(), 7);
+
+    thread::sleep(std::time::Duration::from_millis(2100));
+    client.call("set late 0 0 1\r\nx\r\n");
+    assert_eq!(
+        client.call_until("get a b c late\r\n", "END"),
+        ["VALUE late 0 1", "x", "END"]
+    );
+    assert_eq!(client.call("incr b 1\r\n"), "NOT_FOUND");
+    assert_eq!(client.call("replace c 0 0 1\r\ny\r\n"), "NOT_STORED");
+    assert_eq!(client.call("delete c\r\n"), "NOT_FOUND");
+    assert_eq!(client.call("add a 0 0 1\r\ny\r\n"), "STORED");
+    assert_eq!(
+        client.call_until("get a\r\n", "END"),
+        ["VALUE a 0 1", "y", "END"]
+    );
+    // the store itself still has them
+    assert_eq!(db.lock().unwrap().get(b"b").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_shared_with_handle() {
+    let dir = PathBuf::from("/tmp/bitcask_memcache_shared_test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = Arc::new(Mutex::new(
+        BitCaskHandle::open(dir, Opts::new(1024 * 1024)).unwrap(),
+    ));
+    let server = MemcacheServer::bind("127.0.0.1:0", db.clone()).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut client = Client::connect(addr);
+    client.call("set k 0 0 1\r\nv\r\n");
+    let db = db.lock().unwrap();
+    assert_eq!(db.get(b"k").unwrap(), Some(b"v".to_vec()));
+    assert!(db.ttl(b"k").unwrap().is_none());
+}
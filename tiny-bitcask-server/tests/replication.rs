@@ -0,0 +1,170 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tiny_bitcask::{BitCask, BitCaskError, BitCaskHandle, LogPosition, Opts, POSITION_FILE_NAME};
+use tiny_bitcask_server::{Follower, ReplicationServer};
+
+type Db = Arc<Mutex<BitCaskHandle>>;
+
+fn open(name: &str, fresh: bool) -> Db {
+    let dir = PathBuf::from("/tmp").join(name);
+    if fresh {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    let db = BitCaskHandle::open(dir, Opts::new(256)).unwrap();
+    Arc::new(Mutex::new(db))
+}
+
+fn start_leader(name: &str) -> (Db, String) {
+    let db = open(name, true);
+    let server = ReplicationServer::bind("127.0.0.1:0", db.clone()).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    thread::spawn(move || server.run());
+    (db, addr)
+}
+
+/// Waits until the follower has saved the leader's current position.
+fn wait_caught_up(leader: &Db, follower: &Follower) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let end = leader.lock().unwrap().log_end();
+        if follower.position() == Some(end) {
+            return;
+        }
+        assert!(Instant::now() < deadline, "follower did not catch up");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn assert_same(leader: &Db, follower: &Db) {
+    let leader = leader.lock().unwrap();
+    let follower = follower.lock().unwrap();
+    assert_eq!(follower.list_keys(), leader.list_keys());
+    for key in leader.list_keys() {
+        assert_eq!(follower.get(&key).unwrap(), leader.get(&key).unwrap());
+        assert_eq!(follower.version(&key), leader.version(&key));
+    }
+}
+
+#[test]
+fn test_follower_catches_up_and_restarts() {
+    let (leader, addr) = start_leader("bitcask_repl_leader");
+    for i in 0..20 {
+        leader
+            .lock()
+            .unwrap()
+            .put(
+                format!("key#{i}").as_bytes(),
+                format!("value#{i}").as_bytes(),
+            )
+            .unwrap();
+    }
+
+    let replica = open("bitcask_repl_follower", true);
+    let follower = Follower::start(addr.clone(), replica.clone()).unwrap();
+    wait_caught_up(&leader, &follower);
+    assert_same(&leader, &replica);
+    assert!(matches!(
+        replica.lock().unwrap().put(b"k", b"v"),
+        Err(BitCaskError::ReadOnly)
+    ));
+
+    // writes made while following are streamed
+    {
+        let mut db = leader.lock().unwrap();
+        db.delete(b"key#0").unwrap();
+        db.put_with_ttl(b"ttl", b"v", 100).unwrap();
+        db.put(b"key#1", b"updated").unwrap();
+    }
+    wait_caught_up(&leader, &follower);
+    assert_same(&leader, &replica);
+    assert!(replica.lock().unwrap().ttl(b"ttl").unwrap().is_some());
+
+    // a restarted follower continues from its saved position
+    let position = follower.position();
+    drop(follower);
+    drop(replica);
+    for i in 20..30 {
+        leader
+            .lock()
+            .unwrap()
+            .put(format!("key#{i}").as_bytes(), b"late")
+            .unwrap();
+    }
+    let replica = open("bitcask_repl_follower", false);
+    assert_eq!(
+        LogPosition::load(replica.lock().unwrap().dir()).unwrap(),
+        position
+    );
+    let follower = Follower::start(addr, replica.clone()).unwrap();
+    wait_caught_up(&leader, &follower);
+    assert_same(&leader, &replica);
+}
+
+#[test]
+fn test_full_sync_after_merge() {
+    let (leader, addr) = start_leader("bitcask_repl_merge_leader");
+    let replica = open("bitcask_repl_merge_follower", true);
+    replica.lock().unwrap().put(b"local", b"only").unwrap();
+    let follower = Follower::start(addr.clone(), replica.clone()).unwrap();
+    leader.lock().unwrap().put(b"key", b"value").unwrap();
+    wait_caught_up(&leader, &follower);
+    // keys the leader does not have are dropped by the first full sync
+    assert_same(&leader, &replica);
+    drop(follower);
+
+    // the follower's position is in a file the merge rewrites
+    {
+        let mut db = leader.lock().unwrap();
+        for i in 0..30 {
+            db.put(
+                format!("key#{i}").as_bytes(),
+                format!("value#{i}").as_bytes(),
+            )
+            .unwrap();
+        }
+        for i in 0..10 {
+            db.delete(format!("key#{i}").as_bytes()).unwrap();
+        }
+        db.merge().unwrap();
+    }
+    let follower = Follower::start(addr, replica.clone()).unwrap();
+    wait_caught_up(&leader, &follower);
+    assert_same(&leader, &replica);
+    assert_eq!(replica.lock().unwrap().list_keys().len(), 21);
+}
+
+#[test]
+fn test_promote() {
+    let (leader, addr) = start_leader("bitcask_repl_promote_leader");
+    leader.lock().unwrap().put(b"key", b"value").unwrap();
+    let replica = open("bitcask_repl_promote_follower", true);
+    let follower = Follower::start(addr, replica.clone()).unwrap();
+    wait_caught_up(&leader, &follower);
+    let version = leader.lock().unwrap().version(b"key").unwrap();
+
+    follower.promote().unwrap();
+    let mut db = replica.lock().unwrap();
+    assert!(LogPosition::load(db.dir()).unwrap().is_none());
+    // a compare and swap started against the old leader still goes through
+    assert!(db.put_if_version(b"key", version, b"new leader").unwrap());
+    assert_eq!(db.get(b"key").unwrap().unwrap(), b"new leader".to_vec());
+    // later writes of the old leader are not applied anymore
+    leader.lock().unwrap().put(b"other", b"value").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(db.get(b"other").unwrap().is_none());
+}
+
+#[test]
+fn test_start_with_unreadable_position() {
+    let replica = open("bitcask_repl_bad_position_follower", true);
+    let dir = replica.lock().unwrap().dir().to_path_buf();
+    std::fs::write(dir.join(POSITION_FILE_NAME), "garbage\n").unwrap();
+    assert!(Follower::start("127.0.0.1:1", replica.clone()).is_err());
+    // no follower runs, so the store is left writable
+    let mut db = replica.lock().unwrap();
+    assert!(!db.is_read_only());
+    db.put(b"key", b"value").unwrap();
+}
@@ -0,0 +1,308 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use tiny_bitcask::{BitCask, BitCaskHandle, Opts};
+use tiny_bitcask_server::{Follower, ReplicationServer, Reply, Server};
+
+fn start_server(name: &str) -> SocketAddr {
+    let dir = PathBuf::from("/tmp").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = BitCaskHandle::open(dir, Opts::new(1024 * 1024)).unwrap();
+    let server = Arc::new(Server::bind("127.0.0.1:0", db).unwrap());
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        Self {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn send(&mut self, args: &[&str]) {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(request.as_bytes()).unwrap();
+    }
+
+    /// Reads exactly the bytes of the expected reply and compares them.
+    fn expect(&mut self, expected: Reply) {
+        let mut bytes = vec![];
+        expected.write_to(&mut bytes).unwrap();
+        let mut actual = vec![0; bytes.len()];
+        self.reader.read_exact(&mut actual).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&actual),
+            String::from_utf8_lossy(&bytes)
+        );
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim_end_matches("\r\n").to_string()
+    }
+
+    /// Parses the next reply.
+    fn read_reply(&mut self) -> Reply {
+        let line = self.read_line();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" if rest == "-1" => Reply::nil(),
+            "$" => {
+                let mut bulk = vec![0; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut bulk).unwrap();
+                bulk.truncate(bulk.len() - 2);
+                Reply::bulk(bulk)
+            }
+            "*" => Reply::Array(
+                (0..rest.parse::<usize>().unwrap())
+                    .map(|_| self.read_reply())
+                    .collect(),
+            ),
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+
+    fn integer(&mut self, args: &[&str]) -> i64 {
+        self.send(args);
+        match self.read_reply() {
+            Reply::Integer(n) => n,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn call(&mut self, args: &[&str], expected: Reply) {
+        self.send(args);
+        self.expect(expected);
+    }
+}
+
+fn bulks(items: &[&str]) -> Reply {
+    Reply::Array(items.iter().map(|item| Reply::bulk(*item)).collect())
+}
+
+#[test]
+fn test_basic_commands() {
+    let mut client = Client::connect(start_server("bitcask_server_basic_test"));
+    client.call(&["PING"], Reply::Simple("PONG".to_string()));
+    client.call(&["set", "k1", "v1"], Reply::ok());
+    client.call(&["GET", "k1"], Reply::bulk("v1"));
+    client.call(&["GET", "nope"], Reply::nil());
+    client.call(&["MSET", "k2", "v2", "k3", "v3"], Reply::ok());
+    client.call(
+        &["MGET", "k1", "nope", "k3"],
+        Reply::Array(vec![Reply::bulk("v1"), Reply::nil(), Reply::bulk("v3")]),
+    );
+    client.call(&["EXISTS", "k1", "k2", "nope", "k1"], Reply::Integer(3));
+    client.call(&["DEL", "k1", "nope"], Reply::Integer(1));
+    client.call(&["KEYS", "k*"], bulks(&["k2", "k3"]));
+    client.call(&["DBSIZE"], Reply::Integer(2));
+
+    client.call(&["SET", "k2", "x", "NX"], Reply::nil());
+    client.call(&["SET", "k9", "x", "XX"], Reply::nil());
+    client.call(
+        &["SET", "k9", "x", "NX", "XX"],
+        Reply::error("ERR syntax error"),
+    );
+    client.call(
+        &["GET"],
+        Reply::error("ERR wrong number of arguments for 'get' command"),
+    );
+    client.call(&["FROB"], Reply::error("ERR unknown command 'FROB'"));
+
+    // inline commands, as typed over telnet
+    client.writer.write_all(b"get k2\r\n").unwrap();
+    client.expect(Reply::bulk("v2"));
+}
+
+#[test]
+fn test_expire_and_ttl() {
+    let mut client = Client::connect(start_server("bitcask_server_ttl_test"));
+    client.call(&["SET", "k", "v", "EX", "100"], Reply::ok());
+    assert!((99..=100).contains(&client.integer(&["TTL", "k"])));
+    client.call(&["PERSIST", "k"], Reply::Integer(1));
+    client.call(&["TTL", "k"], Reply::Integer(-1));
+    client.call(&["TTL", "nope"], Reply::Integer(-2));
+    client.call(&["EXPIRE", "k", "50"], Reply::Integer(1));
+    assert!((49..=50).contains(&client.integer(&["TTL", "k"])));
+    client.call(&["EXPIRE", "nope", "50"], Reply::Integer(0));
+    client.call(&["EXPIRE", "k", "0"], Reply::Integer(1));
+    client.call(&["GET", "k"], Reply::nil());
+    client.call(
+        &["SET", "k", "v", "EX", "0"],
+        Reply::error("ERR invalid expire time in 'set' command"),
+    );
+}
+
+#[test]
+fn test_scan() {
+    let mut client = Client::connect(start_server("bitcask_server_scan_test"));
+    for i in 0..25 {
+        client.call(&["SET", &format!("key:{:02}", i), "v"], Reply::ok());
+    }
+    client.call(&["SET", "other", "v"], Reply::ok());
+
+    let mut cursor = "0".to_string();
+    let mut seen = vec![];
+    loop {
+        client.send(&["SCAN", &cursor, "MATCH", "key:*", "COUNT", "10"]);
+        let Reply::Array(reply) = client.read_reply() else {
+            panic!("SCAN replies with an array");
+        };
+        let [Reply::Bulk(Some(next)), Reply::Array(keys)] = reply.as_slice() else {
+            panic!("unexpected {:?}", reply);
+        };
+        cursor = String::from_utf8(next.clone()).unwrap();
+        for key in keys {
+            let Reply::Bulk(Some(key)) = key else {
+                panic!("unexpected {:?}", key);
+            };
+            seen.push(String::from_utf8(key.clone()).unwrap());
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    let expected: Vec<_> = (0..25).map(|i| format!("key:{:02}", i)).collect();
+    assert_eq!(seen, expected);
+    client.call(&["SCAN", "12345"], Reply::error("ERR invalid cursor"));
+}
+
+#[test]
+fn test_concurrent_clients() {
+    let addr = start_server("bitcask_server_concurrent_test");
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            thread::spawn(move || {
+                let mut client = Client::connect(addr);
+                for i in 0..50 {
+                    let key = format!("t{}:{}", t, i);
+                    client.call(&["SET", &key, &key], Reply::ok());
+                }
+                // pipelined: all requests first, then all replies
+                for i in 0..50 {
+                    client.send(&["GET", &format!("t{}:{}", t, i)]);
+                }
+                for i in 0..50 {
+                    client.expect(Reply::bulk(format!("t{}:{}", t, i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let mut client = Client::connect(addr);
+    client.call(&["DBSIZE"], Reply::Integer(400));
+    client.send(&["INFO"]);
+    let Reply::Bulk(Some(info)) = client.read_reply() else {
+        panic!("INFO replies with a bulk string");
+    };
+    let info = String::from_utf8(info).unwrap();
+    assert!(info.contains("db0:keys=400"));
+    assert!(info.contains("total_connections_received:9"));
+}
+
+#[test]
+fn test_protocol_error_closes_connection() {
+    let mut client = Client::connect(start_server("bitcask_server_protocol_test"));
+    client.writer.write_all(b"*1\r\n:1\r\n").unwrap();
+    client.expect(Reply::error("ERR Protocol error: expected '$'"));
+    let mut rest = vec![];
+    assert_eq!(client.reader.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn test_data_survives_restart() {
+    let dir = PathBuf::from("/tmp/bitcask_server_restart_test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = Arc::new(
+        Server::bind(
+            "127.0.0.1:0",
+            BitCaskHandle::open(dir.clone(), Opts::new(1024 * 1024)).unwrap(),
+        )
+        .unwrap(),
+    );
+    let addr = server.local_addr().unwrap();
+    let db = server.db();
+    thread::spawn(move || server.run());
+    let mut client = Client::connect(addr);
+    client.call(&["SET", "k", "v"], Reply::ok());
+    db.lock().unwrap().close().unwrap();
+
+    let db = BitCaskHandle::open(dir, Opts::new(1024 * 1024)).unwrap();
+    assert_eq!(db.get(b"k").unwrap(), Some(b"v".to_vec()));
+}
+
+#[test]
+fn test_replica_is_read_only_until_promoted() {
+    let leader_dir = PathBuf::from("/tmp/bitcask_server_replica_leader");
+    let replica_dir = PathBuf::from("/tmp/bitcask_server_replica_follower");
+    let _ = std::fs::remove_dir_all(&leader_dir);
+    let _ = std::fs::remove_dir_all(&replica_dir);
+    let leader = Server::bind(
+        "127.0.0.1:0",
+        BitCaskHandle::open(leader_dir, Opts::default()).unwrap(),
+    )
+    .unwrap();
+    let replication = ReplicationServer::bind("127.0.0.1:0", leader.db()).unwrap();
+    let replication_addr = replication.local_addr().unwrap().to_string();
+    let leader_addr = leader.local_addr().unwrap();
+    thread::spawn(move || replication.run());
+    thread::spawn(move || leader.run());
+
+    let replica = Server::bind(
+        "127.0.0.1:0",
+        BitCaskHandle::open(replica_dir, Opts::default()).unwrap(),
+    )
+    .unwrap();
+    replica.set_follower(Follower::start(replication_addr, replica.db()).unwrap());
+    let replica_addr = replica.local_addr().unwrap();
+    thread::spawn(move || replica.run());
+
+    Client::connect(leader_addr).call(&["SET", "k", "v"], Reply::ok());
+    let mut client = Client::connect(replica_addr);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    loop {
+        client.send(&["GET", "k"]);
+        if client.read_reply() == Reply::bulk("v") {
+            break;
+        }
+        assert!(std::time::Instant::now() < deadline, "key not replicated");
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    client.send(&["INFO"]);
+    let Reply::Bulk(Some(info)) = client.read_reply() else {
+        panic!("INFO did not return a bulk string");
+    };
+    assert!(String::from_utf8(info).unwrap().contains("role:slave\r\n"));
+    client.call(
+        &["SET", "k", "x"],
+        Reply::error("READONLY You can't write against a read only replica."),
+    );
+    client.call(
+        &["REPLICAOF", "127.0.0.1", "1"],
+        Reply::error("ERR only REPLICAOF NO ONE is supported"),
+    );
+    client.call(&["REPLICAOF", "no", "one"], Reply::ok());
+    client.call(&["SET", "k", "x"], Reply::ok());
+    client.call(&["GET", "k"], Reply::bulk("x"));
+}
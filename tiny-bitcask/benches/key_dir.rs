@@ -0,0 +1,80 @@
+//! Memory and speed of the key dir kinds. Loads a store of `BITCASK_BENCH_KEYS` keys (a
+//! million by default) with each kind and reports the heap it holds, as counted by the
+//! allocator, next to the `key_dir_bytes` estimate of `stats`.
+//!
+//!     cargo bench -p tiny-bitcask --bench key_dir
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use tiny_bitcask::{BitCask, BitCaskHandle, KeyDirKind, Opts};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn key(i: usize) -> Vec<u8> {
+    format!("user:{:010}", i.wrapping_mul(2654435761) % 1_000_000_007).into_bytes()
+}
+
+fn main() {
+    let keys: usize = std::env::var("BITCASK_BENCH_KEYS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1_000_000);
+    let dir = PathBuf::from("/tmp/bitcask_bench_key_dir");
+    let _ = std::fs::remove_dir_all(&dir);
+    let opts = Opts::new(64 * 1024 * 1024);
+    {
+        let mut db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        for i in 0..keys {
+            db.put(&key(i), b"v").unwrap();
+        }
+        db.close().unwrap();
+    }
+
+    println!(
+        "{:<8} {:>14} {:>14} {:>10} {:>12}",
+        "kind", "heap bytes/key", "estimate/key", "open", "lookup"
+    );
+    for kind in [KeyDirKind::BTree, KeyDirKind::Compact] {
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        let started = Instant::now();
+        let db = BitCaskHandle::open(dir.clone(), opts.key_dir(kind)).unwrap();
+        let open = started.elapsed();
+        let heap = ALLOCATED.load(Ordering::Relaxed) - before;
+        let estimate = db.stats().unwrap().key_dir_bytes;
+
+        let started = Instant::now();
+        for i in (0..keys).step_by(7) {
+            assert!(db.version(&key(i)).is_some());
+        }
+        let lookup = started.elapsed() / (keys / 7).max(1) as u32;
+        println!(
+            "{:<8} {:>14.1} {:>14.1} {:>10.2?} {:>12.2?}",
+            format!("{:?}", kind),
+            heap as f64 / keys as f64,
+            estimate as f64 / keys as f64,
+            open,
+            lookup
+        );
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,170 @@
+use std::panic;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::Mutex;
+use tokio::task;
+
+use crate::bitcask::{BitCask, BitCaskHandle, BitCaskResult, Key, KeyRef, Opts, Value, ValueRef};
+use crate::errors::BitCaskError;
+
+struct Shared {
+    db: RwLock<BitCaskHandle>,
+    // writes made so far, and how many of them the last sync covered
+    writes: AtomicU64,
+    synced: Mutex<u64>,
+}
+
+/// A handle for async code: every operation runs on tokio's blocking thread pool, so file I/O
+/// never stalls a runtime thread. Clones share the same store.
+///
+/// Reads run concurrently, writes one at a time. Concurrent `sync` calls are committed as a
+/// group: one fsync covers every write made before it started, the callers arriving meanwhile
+/// wait for it or for the next one instead of each syncing on their own.
+#[derive(Clone)]
+pub struct AsyncBitCaskHandle {
+    shared: Arc<Shared>,
+}
+
+/// Runs `f` on the blocking pool, a panic in it is resumed in the caller.
+async fn blocking<R, F>(f: F) -> BitCaskResult<R>
+where
+    F: FnOnce() -> BitCaskResult<R> + Send + 'static,
+    R: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+        // the runtime is shutting down
+        Err(_) => Err(BitCaskError::IoError),
+    }
+}
+
+impl AsyncBitCaskHandle {
+    pub async fn open(dir: PathBuf, opts: Opts) -> BitCaskResult<Self> {
+        let db = blocking(move || BitCaskHandle::open(dir, opts)).await?;
+        Ok(Self::new(db))
+    }
+
+    /// Wraps a store opened already.
+    pub fn new(db: BitCaskHandle) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                db: RwLock::new(db),
+                writes: AtomicU64::new(0),
+                synced: Mutex::new(0),
+            }),
+        }
+    }
+
+    pub async fn get(&self, key: &KeyRef) -> BitCaskResult<Option<Value>> {
+        let key = key.to_vec();
+        self.read(move |db| db.get(&key)).await
+    }
+
+    /// The values of `keys`, in the same order, see `BitCaskHandle::multi_get`.
+    pub async fn multi_get(&self, keys: &[&KeyRef]) -> BitCaskResult<Vec<Option<Value>>> {
+        let keys: Vec<Key> = keys.iter().map(|key| key.to_vec()).collect();
+        self.read(move |db| {
+            let keys: Vec<_> = keys.iter().map(Vec::as_slice).collect();
+            db.multi_get(&keys)
+        })
+        .await
+    }
+
+    pub async fn contains_key(&self, key: &KeyRef) -> BitCaskResult<bool> {
+        let key = key.to_vec();
+        self.read(move |db| Ok(db.contains_key(&key))).await
+    }
+
+    pub async fn put(&self, key: &KeyRef, value: &ValueRef) -> BitCaskResult<()> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.write(move |db| db.put(&key, &value)).await
+    }
+
+    pub async fn delete(&self, key: &KeyRef) -> BitCaskResult<bool> {
+        let key = key.to_vec();
+        self.write(move |db| db.delete(&key)).await
+    }
+
+    pub async fn put_if_absent(&self, key: &KeyRef, value: &ValueRef) -> BitCaskResult<bool> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.write(move |db| db.put_if_absent(&key, &value)).await
+    }
+
+    pub async fn put_if_version(
+        &self,
+        key: &KeyRef,
+        expected: u64,
+        value: &ValueRef,
+    ) -> BitCaskResult<bool> {
+        let (key, value) = (key.to_vec(), value.to_vec());
+        self.write(move |db| db.put_if_version(&key, expected, &value))
+            .await
+    }
+
+    pub async fn delete_if_version(&self, key: &KeyRef, expected: u64) -> BitCaskResult<bool> {
+        let key = key.to_vec();
+        self.write(move |db| db.delete_if_version(&key, expected))
+            .await
+    }
+
+    /// The live pairs whose key starts with `prefix`, in key order, collected in memory.
+    pub async fn scan(&self, prefix: &KeyRef) -> BitCaskResult<Vec<(Key, Value)>> {
+        let prefix = prefix.to_vec();
+        self.read(move |db| db.scan(&prefix).collect()).await
+    }
+
+    /// Merges the sealed data files, writes wait until it is done.
+    pub async fn merge(&self) -> BitCaskResult<()> {
+        self.write(|db| db.merge()).await
+    }
+
+    /// Makes every write finished before the call durable, see the type's docs on grouping.
+    pub async fn sync(&self) -> BitCaskResult<()> {
+        let target = self.shared.writes.load(Ordering::SeqCst);
+        let mut synced = self.shared.synced.lock().await;
+        if *synced >= target {
+            // a sync started after our writes finished while we were waiting
+            return Ok(());
+        }
+        let covered = self.shared.writes.load(Ordering::SeqCst);
+        self.read(|db| db.sync()).await?;
+        *synced = covered;
+        Ok(())
+    }
+
+    /// Runs `f` with exclusive access to the store, for the operations not wrapped here.
+    pub async fn with<R, F>(&self, f: F) -> BitCaskResult<R>
+    where
+        F: FnOnce(&mut BitCaskHandle) -> BitCaskResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.write(f).await
+    }
+
+    async fn read<R, F>(&self, f: F) -> BitCaskResult<R>
+    where
+        F: FnOnce(&BitCaskHandle) -> BitCaskResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let shared = self.shared.clone();
+        blocking(move || f(&shared.db.read().unwrap())).await
+    }
+
+    async fn write<R, F>(&self, f: F) -> BitCaskResult<R>
+    where
+        F: FnOnce(&mut BitCaskHandle) -> BitCaskResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let shared = self.shared.clone();
+        blocking(move || {
+            let result = f(&mut shared.db.write().unwrap());
+            // counted even if it failed, part of it may have reached the file
+            shared.writes.fetch_add(1, Ordering::SeqCst);
+            result
+        })
+        .await
+    }
+}
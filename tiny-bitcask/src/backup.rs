@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::bitcask::{BitCaskHandle, BitCaskResult};
+use crate::errors::BitCaskError;
+use crate::replication::MERGE_FLOOR_FILE_NAME;
+use crate::utils::*;
+
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const MANIFEST_HEADER: &str = "bitcask-backup v1";
+
+/// A data or hint file as it was when a backup was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    /// modification time in nanoseconds, merge rewrites a file under the same name
+    pub modified: u64,
+    /// whether the file was copied into this backup or is inherited from its parent
+    pub captured: bool,
+}
+
+/// Describes one backup: the complete set of files of the store at backup time, which of
+/// them live in this backup's directory, and which files of the parent have since been
+/// deleted by a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub id: u64,
+    pub parent: Option<u64>,
+    pub files: Vec<BackupFile>,
+    pub deleted: Vec<String>,
+}
+
+impl BackupManifest {
+    pub fn load(backup_dir: &Path) -> BitCaskResult<Self> {
+        let content = fs::read_to_string(backup_dir.join(MANIFEST_FILE_NAME))?;
+        let mut lines = content.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(BitCaskError::InvalidBackup);
+        }
+        let mut manifest = BackupManifest {
+            id: 0,
+            parent: None,
+            files: vec![],
+            deleted: vec![],
+        };
+        for line in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["id", id] => manifest.id = id.parse()?,
+                ["parent", parent] => manifest.parent = Some(parent.parse()?),
+                ["file", name, size, modified, captured] => manifest.files.push(BackupFile {
+                    name: name.to_string(),
+                    size: size.parse()?,
+                    modified: modified.parse()?,
+                    captured: *captured == "captured",
+                }),
+                ["deleted", name] => manifest.deleted.push(name.to_string()),
+                _ => return Err(BitCaskError::InvalidBackup),
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn save(&self, backup_dir: &Path) -> BitCaskResult<()> {
+        let mut content = format!("{}\nid {}\n", MANIFEST_HEADER, self.id);
+        if let Some(parent) = self.parent {
+            content.push_str(&format!("parent {}\n", parent));
+        }
+        for file in &self.files {
+            let location = if file.captured {
+                "captured"
+            } else {
+                "inherited"
+            };
+            content.push_str(&format!(
+                "file {} {} {} {}\n",
+                file.name, file.size, file.modified, location
+            ));
+        }
+        for name in &self.deleted {
+            content.push_str(&format!("deleted {}\n", name));
+        }
+        // write the manifest last and atomically: a backup dir without one is incomplete
+        let tmp_path = backup_dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, backup_dir.join(MANIFEST_FILE_NAME))?;
+        sync_dir(backup_dir)?;
+        Ok(())
+    }
+}
+
+fn ensure_empty_dir(dir: &Path) -> BitCaskResult<()> {
+    if dir.exists() && dir.read_dir()?.next().is_some() {
+        return Err(std::io::Error::from(ErrorKind::AlreadyExists).into());
+    }
+    create_base_dir_if_not_exists(dir)?;
+    Ok(())
+}
+
+/// Lists every data file of `base_dir` together with its hint file, if any, the blob files
+/// and the merge floor, without which a copy would serve log positions a merge removed.
+fn store_files(base_dir: &Path) -> BitCaskResult<Vec<PathBuf>> {
+    let mut files = vec![];
+    for dat_path in get_dat_files(base_dir)? {
+        let hint_path = get_hint_from_dat_path(&dat_path);
+        files.push(dat_path);
+        if hint_path.exists() {
+            files.push(hint_path);
+        }
+    }
+    files.extend(get_blob_files(base_dir)?);
+    // replaced through a rename, never written in place, so it is safe to hard-link
+    let merge_floor = base_dir.join(MERGE_FLOOR_FILE_NAME);
+    if merge_floor.exists() {
+        files.push(merge_floor);
+    }
+    Ok(files)
+}
+
+impl BitCaskHandle {
+    /// Writes a consistent copy of the store into `dest_dir`, which must be empty or absent.
+    ///
+    /// The active data file is synced and sealed first, so every file that gets captured is
+    /// immutable; the files are then hard-linked (or copied across filesystems) into the
+    /// destination. The result can be opened directly with `BitCaskHandle::open`, and the
+    /// source handle keeps accepting writes, which land in a fresh data file.
+    pub fn checkpoint(&mut self, dest_dir: &Path) -> BitCaskResult<()> {
+        ensure_empty_dir(dest_dir)?;
+        self.seal_active_file()?;
+
+        for path in store_files(&self.base_dir)? {
+            let file_name = path.file_name().unwrap();
+            link_or_copy(&path, &dest_dir.join(file_name))?;
+        }
+        sync_dir(dest_dir)?;
+        Ok(())
+    }
+
+    /// Takes a backup into `dest_dir` and records it in a manifest.
+    ///
+    /// Without a `parent` this is a full backup. Given the directory of a previous backup,
+    /// only files that are new or were rewritten by a merge since then are captured, and
+    /// files that a merge has deleted are noted, so `restore_backup` can replay the chain.
+    /// Files are copied rather than hard-linked, so the backup survives damage to the store.
+    pub fn backup(
+        &mut self,
+        dest_dir: &Path,
+        parent: Option<&Path>,
+    ) -> BitCaskResult<BackupManifest> {
+        let parent = parent.map(BackupManifest::load).transpose()?;
+        ensure_empty_dir(dest_dir)?;
+        self.seal_active_file()?;
+
+        let previous: BTreeMap<&str, &BackupFile> = parent
+            .iter()
+            .flat_map(|manifest| manifest.files.iter())
+            .map(|file| (file.name.as_str(), file))
+            .collect();
+
+        let mut files = vec![];
+        for path in store_files(&self.base_dir)? {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let metadata = fs::metadata(&path)?;
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            let unchanged = previous
+                .get(name.as_str())
+                .is_some_and(|file| file.size == metadata.len() && file.modified == modified);
+            if !unchanged {
+                copy_synced(&path, &dest_dir.join(&name))?;
+            }
+            files.push(BackupFile {
+                name,
+                size: metadata.len(),
+                modified,
+                captured: !unchanged,
+            });
+        }
+        sync_dir(dest_dir)?;
+        let deleted = previous
+            .keys()
+            .filter(|name| !files.iter().any(|file| file.name == **name))
+            .map(|name| name.to_string())
+            .collect();
+
+        let manifest = BackupManifest {
+            id: now_nanos().max(parent.as_ref().map_or(0, |p| p.id + 1)),
+            parent: parent.as_ref().map(|p| p.id),
+            files,
+            deleted,
+        };
+        manifest.save(dest_dir)?;
+        Ok(manifest)
+    }
+}
+
+/// Rebuilds a store in `dest_dir` from a full backup followed by its incremental backups,
+/// in the order they were taken. The result can be opened with `BitCaskHandle::open`, and
+/// writing to it leaves the backups untouched.
+pub fn restore_backup(backup_dirs: &[PathBuf], dest_dir: &Path) -> BitCaskResult<()> {
+    let manifests = backup_dirs
+        .iter()
+        .map(|dir| BackupManifest::load(dir))
+        .collect::<BitCaskResult<Vec<_>>>()?;
+    let Some(base) = manifests.first() else {
+        return Err(BitCaskError::InvalidBackup);
+    };
+    if base.parent.is_some() {
+        return Err(BitCaskError::InvalidBackup);
+    }
+    for pair in manifests.windows(2) {
+        if pair[1].parent != Some(pair[0].id) {
+            return Err(BitCaskError::InvalidBackup);
+        }
+    }
+    ensure_empty_dir(dest_dir)?;
+
+    for (dir, manifest) in backup_dirs.iter().zip(&manifests) {
+        for name in &manifest.deleted {
+            if let Err(err) = delete_file(&dest_dir.join(name)) {
+                if err.kind() != ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+        }
+        for file in manifest.files.iter().filter(|file| file.captured) {
+            let dst = dest_dir.join(&file.name);
+            // an older backup may have captured the file under the same name
+            if let Err(err) = delete_file(&dst) {
+                if err.kind() != ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+            copy_synced(&dir.join(&file.name), &dst)?;
+        }
+    }
+
+    // the replayed directory must hold exactly the files of the newest backup
+    let last = manifests.last().unwrap();
+    let mut restored = fs::read_dir(dest_dir)?
+        .map(|entry| entry.map(|e| e.file_name().to_string_lossy().to_string()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    restored.sort();
+    let mut expected: Vec<_> = last.files.iter().map(|file| file.name.clone()).collect();
+    expected.sort();
+    if restored != expected {
+        return Err(BitCaskError::InvalidBackup);
+    }
+    for file in &last.files {
+        if fs::metadata(dest_dir.join(&file.name))?.len() != file.size {
+            return Err(BitCaskError::InvalidBackup);
+        }
+    }
+    sync_dir(dest_dir)?;
+    Ok(())
+}
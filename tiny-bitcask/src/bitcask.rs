@@ -1,24 +1,51 @@
-use std::collections::BTreeMap;
-use std::fs;
-use std::fs::create_dir_all;
-use std::io::ErrorKind;
+use std::collections::{hash_map, HashMap, HashSet};
+use std::fs::{self, create_dir_all, File};
+use std::io::{ErrorKind, Read};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-use crate::block::HEADER_SIZE;
+use tracing::{debug, info, info_span, warn};
+
+use crate::blob::{delete_blob, open_blob, read_blob, write_blob, BlobRef, BLOB_REF_SIZE};
+use crate::block::{check_key_size, Block, HEADER_SIZE};
+use crate::changes::{ChangeEvent, Subscribers};
 use crate::dat_file::DatFile;
 use crate::errors::BitCaskError;
-use crate::index_file::HintFile;
+use crate::index_file::{self, HintFile};
+use crate::key_dir::{KeyDir, KeyDirIter, KeyDirKind};
+use crate::key_dir_file::{remove_checkpoint, KeyDirCheckpoint};
+use crate::load::{self, LoadProgress};
+use crate::replication::{self, LogPosition, LogRecord};
+use crate::snapshot::{self, FilePins};
+use crate::stats::{data_file_usage, MergeStats, Metrics};
 use crate::utils::*;
+use crate::value_reader::ValueReader;
 
 pub trait BitCask {
     fn open(dir_name: std::path::PathBuf, opts: Opts) -> BitCaskResult<Self>
     where
         Self: Sized;
-    fn get(&self, key: &KeyRef) -> Option<Value>;
+    /// The value of `key`, `None` if it does not exist. Failing to read the value, say because
+    /// its data file is gone, is an error rather than `None`.
+    fn get(&self, key: &KeyRef) -> BitCaskResult<Option<Value>>;
+    /// Whether `key` exists, from the key dir alone: no data file is read.
+    fn contains_key(&self, key: &KeyRef) -> bool;
     fn put(&mut self, key: &KeyRef, value: &ValueRef) -> BitCaskResult<()>;
+    /// Like `put`, the key expires `ttl_secs` seconds from now.
+    fn put_with_ttl(&mut self, key: &KeyRef, value: &ValueRef, ttl_secs: u32) -> BitCaskResult<()>;
     fn delete(&mut self, key: &KeyRef) -> BitCaskResult<bool>;
+    /// Sets (`Some`) or removes (`None`) the time to live of an existing key, returns false if
+    /// the key does not exist. A ttl of 0 deletes the key.
+    fn expire(&mut self, key: &KeyRef, ttl_secs: Option<u32>) -> BitCaskResult<bool>;
+    /// Remaining seconds to live: `None` if the key does not exist, `Some(None)` if it never
+    /// expires.
+    fn ttl(&self, key: &KeyRef) -> Option<Option<u32>>;
 
     fn list_keys(&self) -> Vec<Key>;
-    fn merge(&self) -> BitCaskResult<()>;
+    fn scan(&self, prefix: &KeyRef) -> ScanIter<'_>;
+    fn merge(&mut self) -> BitCaskResult<()>;
     fn sync(&self) -> BitCaskResult<()>;
     fn close(&self) -> BitCaskResult<()>;
 }
@@ -26,132 +53,212 @@ pub trait BitCask {
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Opts {
     data_file_limit: u32,
+    key_dir: KeyDirKind,
+    // 0 for one per cpu
+    load_threads: usize,
+    // minimum time between key dir checkpoints, None if they are not used
+    key_dir_checkpoint: Option<Duration>,
+    // values larger than this go to blob files
+    blob_threshold: Option<u32>,
 }
 
 impl Opts {
+    /// Data files are rotated once they would grow past `data_file_limit` bytes, 0 (the
+    /// default) for no limit other than the largest offset a record can have.
     pub fn new(data_file_limit: u32) -> Self {
-        Self { data_file_limit }
+        Self {
+            data_file_limit,
+            key_dir: KeyDirKind::default(),
+            load_threads: 0,
+            key_dir_checkpoint: None,
+            blob_threshold: None,
+        }
+    }
+
+    /// Stores values larger than `bytes` in blob files of their own, the data files only
+    /// keep a reference to them. Merges then copy the reference instead of the value. Values
+    /// that would not fit in a data file go to blob files in any case.
+    pub fn blob_threshold(mut self, bytes: u32) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// Keeps a checkpoint of the key dir, so `open` loads it and only replays the log written
+    /// after it. One is written on `close`, and when a data file is sealed if `interval` has
+    /// passed since the last one.
+    pub fn key_dir_checkpoint(mut self, interval: Duration) -> Self {
+        self.key_dir_checkpoint = Some(interval);
+        self
+    }
+
+    /// Number of threads reading data and hint files when the store is opened, 0 (the
+    /// default) for one per cpu.
+    pub fn load_threads(mut self, threads: usize) -> Self {
+        self.load_threads = threads;
+        self
+    }
+
+    /// Selects how the key dir is kept in memory, `KeyDirKind::Compact` for stores with more
+    /// keys than fit in memory otherwise.
+    pub fn key_dir(mut self, kind: KeyDirKind) -> Self {
+        self.key_dir = kind;
+        self
     }
-}
 
-pub type KeyDir = BTreeMap<Vec<u8>, KeyDirEntry>;
+    fn max_data_file_len(&self) -> u64 {
+        match self.data_file_limit {
+            0 => u32::MAX as u64,
+            limit => limit as u64,
+        }
+    }
+}
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct KeyDirEntry {
     pub file_id: u32,
     pub value_sz: u32,
     pub value_pos: u32,
     pub tstamp: u32,
+    // 0 if the key never expires
+    pub expire_at: u32,
+    /// the record's value is a reference to a blob file holding the actual value
+    pub blob: bool,
+}
+
+impl KeyDirEntry {
+    pub fn is_expired(&self, now: u32) -> bool {
+        self.expire_at != 0 && self.expire_at <= now
+    }
+}
+
+/// Version of a record written at `value_pos` in data file `file_id`.
+pub(crate) fn version_at(file_id: u32, value_pos: u32) -> u64 {
+    LogPosition::new(file_id, value_pos).seq()
 }
 
 pub struct BitCaskHandle {
-    opts: Opts,
-    base_dir: std::path::PathBuf,
-    next_file_id: u32,
-    key_dir: KeyDir,
-    active_data_file: Option<DatFile>,
+    pub(crate) opts: Opts,
+    pub(crate) base_dir: std::path::PathBuf,
+    pub(crate) next_file_id: u32,
+    pub(crate) key_dir: KeyDir,
+    pub(crate) active_data_file: Option<DatFile>,
+    pub(crate) pins: FilePins,
+    // highest file id rewritten by a merge, log positions up to it are gone
+    pub(crate) merge_floor: Option<u32>,
+    pub(crate) read_only: bool,
+    pub(crate) subscribers: Subscribers,
+    pub(crate) metrics: Metrics,
+    last_checkpoint: Instant,
+    // data files opened without a usable hint, which get one once this handle starts writing
+    // and they are known to be sealed
+    unhinted: Vec<u32>,
 }
 
-impl BitCaskHandle {
-    fn load_files_in_dir(&mut self, dat_files: &mut Vec<std::path::PathBuf>) -> BitCaskResult<()> {
-        if dat_files.is_empty() {
-            return Ok(());
-        }
+/// Opens the value `entry` points at, see `BitCaskHandle::get_reader`.
+pub(crate) fn open_entry_value(base_dir: &Path, entry: &KeyDirEntry) -> BitCaskResult<ValueReader> {
+    if entry.blob {
+        let mut file = DatFile::new(base_dir, entry.file_id, true)?;
+        let pointer = file.read_value(entry.value_sz, entry.value_pos as u64)?;
+        return open_blob(base_dir, &BlobRef::decode(&pointer)?);
+    }
+    let file = File::open(base_dir.join(format_dat_file_name(entry.file_id)))?;
+    Ok(ValueReader::new(
+        file,
+        entry.value_pos as u64,
+        entry.value_sz as u64,
+        None,
+    )?)
+}
 
-        for path in dat_files {
-            let dat_file = DatFile::from_path(path, true)?;
-            let file_id = dat_file.id;
-            let index_path = self.base_dir.join(format_idx_file_name(file_id));
-            if index_path.exists() {
-                let hint_file = HintFile::open_by_path(index_path, true)?;
-                for record in hint_file.iter() {
-                    self.key_dir.insert(
-                        record.key,
-                        KeyDirEntry {
-                            file_id,
-                            value_sz: record.value_sz,
-                            value_pos: record.value_pos,
-                            tstamp: record.tstamp,
-                        },
-                    );
-                }
-            } else {
-                for (offset, block) in dat_file.iter() {
-                    if block.is_removed() {
-                        self.key_dir.remove(&block.key);
-                        continue;
-                    }
-                    let maybe_entry = self.key_dir.get(&block.key);
-                    match maybe_entry {
-                        None => {
-                            let entry = KeyDirEntry {
-                                file_id,
-                                value_sz: block.value_sz,
-                                value_pos: offset + HEADER_SIZE as u32 + block.ksz,
-                                tstamp: block.tstamp,
-                            };
-                            self.key_dir.insert(block.key, entry);
-                        }
-                        Some(entry) => {
-                            let entry_ts = entry.tstamp;
-                            if entry_ts < block.tstamp {
-                                let entry = KeyDirEntry {
-                                    file_id,
-                                    value_sz: block.value_sz,
-                                    value_pos: offset + HEADER_SIZE as u32 + block.ksz,
-                                    tstamp: block.tstamp,
-                                };
-                                self.key_dir.insert(block.key, entry);
-                            }
-                        }
-                    }
-                }
-            }
+pub(crate) fn read_entry_value(base_dir: &Path, entry: &KeyDirEntry) -> BitCaskResult<Value> {
+    let mut file = DatFile::new(base_dir, entry.file_id, true)?;
+    let value = file.read_value(entry.value_sz, entry.value_pos as u64)?;
+    if entry.blob {
+        return read_blob(base_dir, &BlobRef::decode(&value)?);
+    }
+    Ok(value)
+}
+
+/// Iterates over the key/value pairs of a key dir whose keys start with `prefix`, in key order.
+/// Values are read lazily from the data files as the iterator advances.
+pub struct ScanIter<'a> {
+    base_dir: &'a Path,
+    // counts the bytes read when scanning through a handle
+    metrics: Option<&'a Metrics>,
+    key_dir: &'a KeyDir,
+    range: KeyDirIter<'a>,
+    prefix: Key,
+    now: u32,
+}
+
+impl<'a> ScanIter<'a> {
+    pub(crate) fn new(base_dir: &'a Path, key_dir: &'a KeyDir, prefix: &KeyRef) -> Self {
+        let range = key_dir.range_from(Bound::Included(prefix));
+        Self {
+            base_dir,
+            metrics: None,
+            key_dir,
+            range,
+            prefix: prefix.to_vec(),
+            now: now_ts(),
         }
+    }
 
-        Ok(())
+    /// Skips the keys up to and including `key`, to resume a scan where an earlier one stopped.
+    pub fn after(mut self, key: &KeyRef) -> Self {
+        if key >= self.prefix.as_slice() {
+            self.range = self.key_dir.range_from(Bound::Excluded(key));
+        }
+        self
     }
 
-    fn create_new_dat_file(&mut self, file_id: u32) -> BitCaskResult<()> {
-        let dat_file = DatFile::new(&self.base_dir, file_id, false)?;
-        self.active_data_file = Some(dat_file);
-        Ok(())
+    /// Iterates over the keys only, without reading any value.
+    pub fn keys(self) -> impl Iterator<Item = Key> + 'a {
+        let ScanIter {
+            range, prefix, now, ..
+        } = self;
+        range
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.into_owned())
     }
+}
 
-    fn check_write(&mut self, data_len: u32) -> BitCaskResult<()> {
-        match self.active_data_file {
-            None => {
-                self.create_new_dat_file(self.next_file_id)?;
+impl Iterator for ScanIter<'_> {
+    type Item = BitCaskResult<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, entry) = self.range.next()?;
+            if !key.starts_with(&self.prefix) {
+                return None;
             }
-            Some(_) => {}
-        }
-        if self.active_data_file.is_none() {
-        } else {
-            let dat_file = self.active_data_file.as_mut().unwrap();
-            // rotate
-            if dat_file.get_offset() + HEADER_SIZE as u32 + data_len > self.opts.data_file_limit {
-                self.next_file_id += 1;
-                self.create_new_dat_file(self.next_file_id)?;
+            if entry.is_expired(self.now) {
+                continue;
             }
+            let value = read_entry_value(self.base_dir, &entry);
+            if let (Some(metrics), Ok(value)) = (self.metrics, &value) {
+                metrics
+                    .bytes_read
+                    .fetch_add(value.len() as u64, Ordering::Relaxed);
+            }
+            return Some(value.map(|value| (key.into_owned(), value)));
         }
-        Ok(())
     }
 }
 
-pub type BitCaskResult<T> = Result<T, BitCaskError>;
-
-pub type Key = Vec<u8>;
-pub type KeyRef = [u8];
-pub type Value = Vec<u8>;
-pub type ValueRef = [u8];
-
-pub const REMOVE_TOMBSTONE: &[u8] = b"%_%_%_%<!(R|E|M|O|V|E|D)!>%_%_%_%_";
-
-impl BitCask for BitCaskHandle {
-    fn open(base_dir: std::path::PathBuf, opts: Opts) -> BitCaskResult<Self> {
+impl BitCaskHandle {
+    /// Like `BitCask::open`, calls `progress` after each data file is loaded instead of
+    /// logging how far it got.
+    pub fn open_with_progress(
+        base_dir: std::path::PathBuf,
+        opts: Opts,
+        progress: impl FnMut(&LoadProgress),
+    ) -> BitCaskResult<Self> {
+        let _span = info_span!("open", dir = %base_dir.display()).entered();
         create_base_dir_if_not_exists(&base_dir)?;
 
-        let mut dat_files = get_dat_files(&base_dir)?;
+        let dat_files = get_dat_files(&base_dir)?;
 
         let next_id = get_next_id(&dat_files);
 
@@ -159,115 +266,714 @@ impl BitCask for BitCaskHandle {
             opts,
             base_dir,
             active_data_file: None,
-            key_dir: Default::default(),
+            key_dir: KeyDir::new(opts.key_dir),
             next_file_id: next_id,
+            pins: Default::default(),
+            merge_floor: None,
+            read_only: false,
+            subscribers: Default::default(),
+            metrics: Default::default(),
+            last_checkpoint: Instant::now(),
+            unhinted: vec![],
         };
+        db.merge_floor = replication::read_merge_floor(&db.base_dir)?;
 
-        db.load_files_in_dir(&mut dat_files)?;
+        // the files to load in full, those after the checkpoint if there is a usable one
+        let mut to_load = &dat_files[..];
+        if opts.key_dir_checkpoint.is_some() {
+            match KeyDirCheckpoint::load(&db.base_dir, &dat_files)? {
+                Some(Ok(checkpoint)) => {
+                    let position = checkpoint.position;
+                    checkpoint.restore(&mut db.key_dir);
+                    let mut first = dat_files.len();
+                    for (i, path) in dat_files.iter().enumerate() {
+                        let file_id = get_file_id_from_path(path)?;
+                        if file_id == position.file_id && position.offset > 0 {
+                            load::replay_from(path, position.offset, &mut db.key_dir)?;
+                        } else if file_id >= position.file_id {
+                            first = i;
+                            break;
+                        }
+                    }
+                    to_load = &dat_files[first..];
+                    info!(?position, "loaded key dir checkpoint");
+                }
+                Some(Err(reason)) => warn!(%reason, "ignoring stale key dir checkpoint"),
+                None => {}
+            }
+        }
+        db.unhinted = load::load_files(
+            &db.base_dir,
+            to_load,
+            opts.load_threads,
+            &mut db.key_dir,
+            progress,
+        )?;
+        info!(
+            keys = db.key_dir.len(),
+            data_files = dat_files.len(),
+            "opened store"
+        );
         Ok(db)
     }
-    fn get(&self, key: &KeyRef) -> Option<Value> {
+
+    /// The data directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    fn create_new_dat_file(&mut self, file_id: u32) -> BitCaskResult<()> {
+        let dat_file = DatFile::new(&self.base_dir, file_id, false)?;
+        self.active_data_file = Some(dat_file);
+        Ok(())
+    }
+
+    /// Syncs the active data file and retires it, so the next write starts a new file and
+    /// every file currently in the directory is immutable.
+    pub(crate) fn seal_active_file(&mut self) -> BitCaskResult<()> {
+        if let Some(dat_file) = self.active_data_file.take() {
+            dat_file.sync()?;
+            self.next_file_id = dat_file.id + 1;
+            self.write_hint(dat_file.id);
+        }
+        Ok(())
+    }
+
+    /// Writes the hint file of a data file done with, so the next open can skip scanning it.
+    /// A failure only costs that scan, it is logged and otherwise ignored.
+    fn write_hint(&self, file_id: u32) {
+        if let Err(err) = index_file::write_hint(&self.base_dir, file_id) {
+            warn!(file_id, %err, "failed to write hint file");
+        }
+    }
+
+    /// Version of the current value of `key`, which identifies the write of the value: the
+    /// position in the log its value was first written to, see `version_at`. It changes with
+    /// every write of the key and stays the same when a merge moves the record.
+    pub fn version(&self, key: &KeyRef) -> Option<u64> {
+        self.live_entry(key)
+            .map(|entry| self.key_dir.version(&entry))
+    }
+
+    /// Puts `value` under `key` only if the key does not exist, returns whether it did.
+    pub fn put_if_absent(&mut self, key: &KeyRef, value: &ValueRef) -> BitCaskResult<bool> {
+        self.put_if(key, value, |version| version.is_none())
+    }
+
+    /// Puts `value` under `key` only if the current version of the key is `expected`, returns
+    /// whether it did. A caller that lost the race reads the key again.
+    pub fn put_if_version(
+        &mut self,
+        key: &KeyRef,
+        expected: u64,
+        value: &ValueRef,
+    ) -> BitCaskResult<bool> {
+        self.put_if(key, value, |version| version == Some(expected))
+    }
+
+    /// Deletes `key` only if its current version is `expected`, returns whether it did.
+    pub fn delete_if_version(&mut self, key: &KeyRef, expected: u64) -> BitCaskResult<bool> {
+        let started = Instant::now();
+        if self.version(key) == Some(expected) {
+            // timed by `delete`
+            return self.delete(key);
+        }
+        self.metrics.record_delete(started);
+        Ok(false)
+    }
+
+    // the check and the write happen under the same `&mut self`, no other write can come between
+    fn put_if(
+        &mut self,
+        key: &KeyRef,
+        value: &ValueRef,
+        check: impl FnOnce(Option<u64>) -> bool,
+    ) -> BitCaskResult<bool> {
+        let started = Instant::now();
+        let result = if check(self.version(key)) {
+            self.write_entry(key, value, 0).map(|()| true)
+        } else {
+            Ok(false)
+        };
+        self.metrics.record_put(started);
+        result
+    }
+
+    fn live_entry(&self, key: &KeyRef) -> Option<KeyDirEntry> {
         self.key_dir
             .get(key)
-            .map(|entry| {
-                let file = DatFile::new(&self.base_dir, entry.file_id, true);
-                if file.is_err() {
-                    return None;
-                }
-                let mut file = file.unwrap();
-                file.read_value(entry.value_sz, entry.value_pos as u64).ok()
-            })
-            .unwrap_or(None)
+            .filter(|entry| !entry.is_expired(now_ts()))
     }
 
-    fn put(&mut self, key: &KeyRef, value: &ValueRef) -> BitCaskResult<()> {
-        let data_len = key.len() + value.len();
-        self.check_write(data_len as u32)?;
+    fn write_entry(&mut self, key: &KeyRef, value: &ValueRef, expire_at: u32) -> BitCaskResult<()> {
+        if self.read_only {
+            return Err(BitCaskError::ReadOnly);
+        }
+        self.append(key, value, now_ts(), expire_at)
+    }
+
+    /// Appends a record and points the key dir at it, read only or not.
+    pub(crate) fn append(
+        &mut self,
+        key: &KeyRef,
+        value: &ValueRef,
+        tstamp: u32,
+        expire_at: u32,
+    ) -> BitCaskResult<()> {
+        self.append_versioned(key, value, tstamp, expire_at, None)
+    }
 
+    /// Like `append`, for a record that keeps the `version` of the write it copies instead of
+    /// taking that of its position.
+    pub(crate) fn append_versioned(
+        &mut self,
+        key: &KeyRef,
+        value: &ValueRef,
+        tstamp: u32,
+        expire_at: u32,
+        version: Option<u64>,
+    ) -> BitCaskResult<()> {
+        check_key_size(key)?;
+        let entry = if value != REMOVE_TOMBSTONE && self.goes_to_blob(key, value.len() as u64) {
+            self.append_blob(key, value, value.len() as u64, tstamp, expire_at, version)?
+        } else {
+            let version_len = if version.is_some() { 8 } else { 0 };
+            self.check_write((key.len() + value.len()) as u32 + version_len)?;
+            let active_file = self.active_data_file.as_mut().unwrap();
+            let start = active_file.get_offset();
+            let value_pos = active_file.write(tstamp, expire_at, key, value, version)?;
+            self.metrics
+                .record_write((active_file.get_offset() - start) as usize);
+            let entry = KeyDirEntry {
+                file_id: active_file.id,
+                value_sz: value.len() as u32,
+                value_pos,
+                tstamp,
+                expire_at,
+                blob: false,
+            };
+            if let Some(version) = version {
+                self.key_dir.set_version(&entry, version);
+            }
+            self.key_dir.insert(key.to_vec(), entry);
+            entry
+        };
+        if !self.subscribers.is_empty() {
+            let value = (value != REMOVE_TOMBSTONE).then(|| value.to_vec());
+            let version = self.key_dir.version(&entry);
+            self.publish_write(key, value, tstamp, expire_at, version);
+        }
+        Ok(())
+    }
+
+    /// Whether a value of `len` bytes for `key` is kept in a blob file: if it is over the blob
+    /// threshold, or its record would not fit in a data file.
+    fn goes_to_blob(&self, key: &KeyRef, len: u64) -> bool {
+        let record_len = (HEADER_SIZE + 4 + key.len()) as u64 + len;
+        self.opts
+            .blob_threshold
+            .is_some_and(|threshold| len > threshold as u64)
+            || record_len > self.opts.max_data_file_len()
+    }
+
+    /// Writes the `len` bytes read from `value` to a blob file, then the record pointing at it,
+    /// and returns the key dir entry of the record. See `append_versioned` for `version`.
+    fn append_blob(
+        &mut self,
+        key: &KeyRef,
+        value: impl Read,
+        len: u64,
+        tstamp: u32,
+        expire_at: u32,
+        version: Option<u64>,
+    ) -> BitCaskResult<KeyDirEntry> {
+        check_key_size(key)?;
+        let version_len = if version.is_some() { 8 } else { 0 };
+        self.check_write((key.len() + BLOB_REF_SIZE) as u32 + version_len)?;
         let active_file = self.active_data_file.as_mut().unwrap();
+        let start = active_file.get_offset();
+        let id = LogPosition::new(active_file.id, start).seq();
+        let blob = write_blob(&self.base_dir, id, value, len)?;
+        let value_pos = active_file.write_blob_ref(tstamp, expire_at, key, &blob, version)?;
+        self.metrics
+            .record_write((active_file.get_offset() - start) as usize + len as usize);
+        let entry = KeyDirEntry {
+            file_id: active_file.id,
+            value_sz: BLOB_REF_SIZE as u32,
+            value_pos,
+            tstamp,
+            expire_at,
+            blob: true,
+        };
+        if let Some(version) = version {
+            self.key_dir.set_version(&entry, version);
+        }
+        self.key_dir.insert(key.to_vec(), entry);
+        Ok(entry)
+    }
+
+    /// Tells subscribers about the record just appended.
+    fn publish_write(
+        &mut self,
+        key: &KeyRef,
+        value: Option<Value>,
+        tstamp: u32,
+        expire_at: u32,
+        version: u64,
+    ) {
+        let active_file = self.active_data_file.as_ref().unwrap();
+        let seq = LogPosition::new(active_file.id, active_file.get_offset()).seq();
+        let record = LogRecord {
+            tstamp,
+            expire_at,
+            version,
+            key: key.to_vec(),
+            value,
+        };
+        self.publish(ChangeEvent { seq, record });
+    }
+
+    /// Like `publish_write` for the value of `key` just streamed to the store, which
+    /// subscribers read themselves.
+    fn publish_streamed_write(&mut self, key: &KeyRef, tstamp: u32) {
+        let active_file = self.active_data_file.as_ref().unwrap();
+        let seq = LogPosition::new(active_file.id, active_file.get_offset()).seq();
+        let entry = self.key_dir.get(key).unwrap();
+        let record = LogRecord {
+            tstamp,
+            expire_at: 0,
+            version: self.key_dir.version(&entry),
+            key: key.to_vec(),
+            value: None,
+        };
+        self.publish_pending(ChangeEvent { seq, record }, entry);
+    }
+
+    /// The values of `keys`, in the same order, `None` for the keys that do not exist. The key
+    /// dir is consulted for all of them first, then the values are read file by file in the
+    /// order they are laid out, each data file opened once.
+    pub fn multi_get(&self, keys: &[&KeyRef]) -> BitCaskResult<Vec<Option<Value>>> {
+        let started = Instant::now();
+        let mut reads: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.live_entry(key).map(|entry| (entry, i)))
+            .collect();
+        reads.sort_unstable_by_key(|(entry, _)| (entry.file_id, entry.value_pos));
+
+        let mut values = vec![None; keys.len()];
+        let mut file: Option<DatFile> = None;
+        for (entry, i) in reads {
+            let file = match &mut file {
+                Some(file) if file.id == entry.file_id => file,
+                file => file.insert(DatFile::new(&self.base_dir, entry.file_id, true)?),
+            };
+            let value = file.read_value(entry.value_sz, entry.value_pos as u64)?;
+            values[i] = Some(if entry.blob {
+                read_blob(&self.base_dir, &BlobRef::decode(&value)?)?
+            } else {
+                value
+            });
+        }
+        for value in &values {
+            self.metrics
+                .record_get(started, value.as_ref().map(Vec::len));
+        }
+        Ok(values)
+    }
+
+    /// Opens the current value of `key` for reading without loading it into memory, `None` if
+    /// the key is absent or expired.
+    pub fn get_reader(&self, key: &KeyRef) -> BitCaskResult<Option<ValueReader>> {
+        match self.live_entry(key) {
+            Some(entry) => open_entry_value(&self.base_dir, &entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Puts the `len` bytes read from `value` under `key`. The value is streamed to its data
+    /// or blob file, without ever being held in memory whole. Fails, writing nothing, if
+    /// `value` ends before `len` bytes.
+    pub fn put_from_reader(
+        &mut self,
+        key: &KeyRef,
+        value: impl Read,
+        len: u64,
+    ) -> BitCaskResult<()> {
+        let started = Instant::now();
+        let result = self.write_from_reader(key, value, len);
+        self.metrics.record_put(started);
+        result
+    }
+
+    fn write_from_reader(&mut self, key: &KeyRef, value: impl Read, len: u64) -> BitCaskResult<()> {
+        if self.read_only {
+            return Err(BitCaskError::ReadOnly);
+        }
+        // before any of the value is consumed
+        check_key_size(key)?;
         let tstamp = now_ts();
-        let offset = active_file.write(tstamp, key, value)?;
+        if self.goes_to_blob(key, len) {
+            self.append_blob(key, value, len, tstamp, 0, None)?;
+        } else {
+            self.append_streamed(key, value, len as u32, tstamp)?;
+        }
+        if !self.subscribers.is_empty() {
+            self.publish_streamed_write(key, tstamp);
+        }
+        Ok(())
+    }
+
+    /// Writes a record whose value is streamed from `value`, see `DatFile::write_from_reader`.
+    fn append_streamed(
+        &mut self,
+        key: &KeyRef,
+        value: impl Read,
+        len: u32,
+        tstamp: u32,
+    ) -> BitCaskResult<()> {
+        self.check_write(key.len() as u32 + len)?;
+        let active_file = self.active_data_file.as_mut().unwrap();
+        let start = active_file.get_offset();
+        let value_pos = active_file.write_from_reader(tstamp, 0, key, value, len)?;
+        self.metrics
+            .record_write((active_file.get_offset() - start) as usize);
         self.key_dir.insert(
             key.to_vec(),
             KeyDirEntry {
                 file_id: active_file.id,
-                value_sz: value.len() as u32,
-                value_pos: offset + (HEADER_SIZE + key.len()) as u32,
+                value_sz: len,
+                value_pos,
                 tstamp,
+                expire_at: 0,
+                blob: false,
             },
         );
         Ok(())
     }
 
+    fn check_write(&mut self, data_len: u32) -> BitCaskResult<()> {
+        if self.active_data_file.is_none() {
+            self.create_new_dat_file(self.next_file_id)?;
+            for file_id in std::mem::take(&mut self.unhinted) {
+                self.write_hint(file_id);
+            }
+        }
+        let dat_file = self.active_data_file.as_mut().unwrap();
+        // rotate, leaving room for an expiry timestamp
+        let record_end = dat_file.get_offset() as u64 + (HEADER_SIZE + 4) as u64 + data_len as u64;
+        if record_end > self.opts.max_data_file_len() {
+            // `sync` only covers the active file, so the one retired has to be durable already
+            dat_file.sync()?;
+            let sealed = dat_file.id;
+            self.next_file_id += 1;
+            debug!(
+                sealed = dat_file.id,
+                file_id = self.next_file_id,
+                "rotating data file"
+            );
+            self.create_new_dat_file(self.next_file_id)?;
+            self.write_hint(sealed);
+            if self
+                .opts
+                .key_dir_checkpoint
+                .is_some_and(|interval| self.last_checkpoint.elapsed() >= interval)
+            {
+                self.last_checkpoint = Instant::now();
+                // the log is replayed from the previous checkpoint instead
+                if let Err(err) = self.write_key_dir_checkpoint() {
+                    warn!(%err, "failed to write key dir checkpoint");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub type BitCaskResult<T> = Result<T, BitCaskError>;
+
+pub type Key = Vec<u8>;
+pub type KeyRef = [u8];
+pub type Value = Vec<u8>;
+pub type ValueRef = [u8];
+
+/// How often `open` logs its progress through a slow load.
+const LOAD_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const REMOVE_TOMBSTONE: &[u8] = b"%_%_%_%<!(R|E|M|O|V|E|D)!>%_%_%_%_";
+
+impl BitCask for BitCaskHandle {
+    fn open(base_dir: std::path::PathBuf, opts: Opts) -> BitCaskResult<Self> {
+        let mut logged = Instant::now();
+        Self::open_with_progress(base_dir, opts, |progress| {
+            if logged.elapsed() >= LOAD_PROGRESS_INTERVAL {
+                logged = Instant::now();
+                info!(
+                    files = progress.files_loaded,
+                    of = progress.files_total,
+                    "loading data files"
+                );
+            }
+        })
+    }
+    fn get(&self, key: &KeyRef) -> BitCaskResult<Option<Value>> {
+        let started = Instant::now();
+        let value = match self.live_entry(key) {
+            Some(entry) => read_entry_value(&self.base_dir, &entry).map(Some),
+            None => Ok(None),
+        };
+        self.metrics.record_get(
+            started,
+            value
+                .as_ref()
+                .ok()
+                .and_then(|value| value.as_ref().map(Vec::len)),
+        );
+        value
+    }
+
+    fn contains_key(&self, key: &KeyRef) -> bool {
+        self.live_entry(key).is_some()
+    }
+
+    fn put(&mut self, key: &KeyRef, value: &ValueRef) -> BitCaskResult<()> {
+        let started = Instant::now();
+        let result = self.write_entry(key, value, 0);
+        self.metrics.record_put(started);
+        result
+    }
+
+    fn put_with_ttl(&mut self, key: &KeyRef, value: &ValueRef, ttl_secs: u32) -> BitCaskResult<()> {
+        let started = Instant::now();
+        let result = self.write_entry(key, value, now_ts().saturating_add(ttl_secs));
+        self.metrics.record_put(started);
+        result
+    }
+
     fn delete(&mut self, key: &KeyRef) -> BitCaskResult<bool> {
-        if !self.key_dir.contains_key(key) {
+        let started = Instant::now();
+        if self.live_entry(key).is_none() {
+            self.metrics.record_delete(started);
+            return Ok(false);
+        }
+        let result = self.write_entry(key, REMOVE_TOMBSTONE, 0);
+        if result.is_ok() {
+            self.key_dir.remove(key);
+        }
+        self.metrics.record_delete(started);
+        result.map(|()| true)
+    }
+
+    fn expire(&mut self, key: &KeyRef, ttl_secs: Option<u32>) -> BitCaskResult<bool> {
+        let Some(entry) = self.live_entry(key) else {
             return Ok(false);
+        };
+        if ttl_secs == Some(0) {
+            return self.delete(key);
         }
-        self.put(key, REMOVE_TOMBSTONE)?;
-        self.key_dir.remove(key);
+        // the expiry lives in the record, so the value is written again with the new one
+        let value = read_entry_value(&self.base_dir, &entry)?;
+        let expire_at = ttl_secs.map_or(0, |ttl| now_ts().saturating_add(ttl));
+        self.write_entry(key, &value, expire_at)?;
         Ok(true)
     }
 
+    fn ttl(&self, key: &KeyRef) -> Option<Option<u32>> {
+        self.live_entry(key).map(|entry| match entry.expire_at {
+            0 => None,
+            expire_at => Some(expire_at.saturating_sub(now_ts())),
+        })
+    }
+
     fn list_keys(&self) -> Vec<Key> {
-        self.key_dir.keys().cloned().collect()
+        let now = now_ts();
+        self.key_dir
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.into_owned())
+            .collect()
     }
 
-    fn merge(&self) -> BitCaskResult<()> {
+    fn scan(&self, prefix: &KeyRef) -> ScanIter<'_> {
+        ScanIter {
+            metrics: Some(&self.metrics),
+            ..ScanIter::new(&self.base_dir, &self.key_dir, prefix)
+        }
+    }
+
+    fn merge(&mut self) -> BitCaskResult<()> {
+        let _span = info_span!("merge", dir = %self.base_dir.display()).entered();
+        let started = Instant::now();
+        let usage = |dir: &Path| data_file_usage(dir).map_or(0, |(_, bytes)| bytes);
+        let before = usage(&self.base_dir);
+        let result = self.merge_sealed_files();
+        self.metrics.record_merge(MergeStats {
+            finished_at: now_ts(),
+            duration: started.elapsed(),
+            reclaimed_bytes: before.saturating_sub(usage(&self.base_dir)),
+            error: result.as_ref().err().map(ToString::to_string),
+        });
+        match &result {
+            Ok(()) => info!(elapsed = ?started.elapsed(), "merge finished"),
+            Err(err) => warn!(%err, "merge failed"),
+        }
+        result
+    }
+
+    fn sync(&self) -> BitCaskResult<()> {
+        if let Some(ref f) = self.active_data_file {
+            f.sync()?
+        }
+        Ok(())
+    }
+
+    /// Syncs and writes the hint of the active file. Writes made afterwards still count, they
+    /// are scanned on open.
+    fn close(&self) -> BitCaskResult<()> {
+        self.sync()?;
+        if let Some(dat_file) = &self.active_data_file {
+            self.write_hint(dat_file.id);
+        }
+        if self.opts.key_dir_checkpoint.is_some() {
+            self.write_key_dir_checkpoint()?;
+        }
+        Ok(())
+    }
+}
+
+impl BitCaskHandle {
+    /// Merges every sealed data file not pinned by a snapshot into the last of them.
+    fn merge_sealed_files(&mut self) -> BitCaskResult<()> {
         let dat_files = get_dat_files(&self.base_dir)?;
-        println!("all dat files, size : {:?}", dat_files.len());
         if dat_files.len() <= 1 {
             return Ok(());
         }
-        // Leave last file where current writes are landing
-        let dat_files_to_merge = &dat_files[0..dat_files.len() - 1];
-        println!("to merge dat files, size : {:?}", dat_files_to_merge.len());
-        for path in dat_files_to_merge {
-            println!("merging file {}", path.display());
+        // Leave last file where current writes are landing, and stop before the first file
+        // pinned by a live snapshot: the merged output replaces the last merged file in place.
+        let min_pinned = snapshot::min_pinned_file(&self.pins);
+        let mut dat_files_to_merge = vec![];
+        for path in &dat_files[0..dat_files.len() - 1] {
+            if min_pinned
+                .is_some_and(|pinned| get_file_id_from_path(path).is_ok_and(|id| id >= pinned))
+            {
+                break;
+            }
+            dat_files_to_merge.push(path.clone());
+        }
+        debug!(
+            data_files = dat_files.len(),
+            to_merge = dat_files_to_merge.len(),
+            ?min_pinned,
+            "selected files to merge"
+        );
+        if dat_files_to_merge.is_empty() {
+            return Ok(());
         }
 
         let last_id = get_file_id_from_path(dat_files_to_merge.last().unwrap())?;
 
-        println!("last id {}", last_id);
-        // process latest file first
-        let mut key_dir = KeyDir::new();
-
         let tmp_dir = self.base_dir.join("tmp");
-        if tmp_dir.exists() && !tmp_dir.is_dir() {
-            let _ = fs::remove_file(&tmp_dir);
-        }
-        if !tmp_dir.exists() {
-            create_dir_all(&tmp_dir)?;
+        // a leftover tmp dir belongs to an interrupted merge, nothing in it is referenced
+        if tmp_dir.is_dir() {
+            warn!(path = %tmp_dir.display(), "removing leftover merge directory");
+            fs::remove_dir_all(&tmp_dir)?;
+        } else if tmp_dir.exists() {
+            warn!(path = %tmp_dir.display(), "removing leftover merge file");
+            fs::remove_file(&tmp_dir)?;
         }
-        let tmp_file_path = tmp_dir.join(format_dat_file_name(last_id));
+        create_dir_all(&tmp_dir)?;
 
-        let mut tmp_dat_file = DatFile::from_path(&tmp_file_path, false)?;
-        let tmp_hint_path = self.base_dir.join(format_idx_file_name(last_id));
-        let mut tmp_hint_file = HintFile::open_by_path(tmp_hint_path, false)?;
+        let mut tmp_dat_file = DatFile::new(&tmp_dir, last_id, false)?;
+        let tmp_hint_path = tmp_dir.join(format_idx_file_name(last_id));
+        let mut tmp_hint_file = HintFile::create(tmp_hint_path)?;
 
-        for path in dat_files_to_merge.iter().rev() {
-            let fid = get_file_id_from_path(path)?;
-            let dat_file_iter = DatFile::from_path(path, true)?.iter();
-            for (offset, block) in dat_file_iter {
-                if key_dir.contains_key(&block.key) {
-                    continue;
-                }
-                let key_dir_entry = KeyDirEntry {
-                    file_id: fid,
-                    value_sz: block.value_sz,
-                    value_pos: offset + HEADER_SIZE as u32 + block.ksz,
-                    tstamp: block.tstamp,
-                };
-                tmp_dat_file.write(block.tstamp, &block.key, &block.value)?;
-                tmp_hint_file.put(&block.key, key_dir_entry.clone())?;
-                key_dir.insert(block.key, key_dir_entry);
+        // every file up to last_id takes part in the merge, so the live records are exactly
+        // the key dir entries pointing at those files
+        let now = now_ts();
+        let mut readers: HashMap<u32, DatFile> = HashMap::new();
+        let mut merged = vec![];
+        let mut expired = vec![];
+        let mut kept_blobs = HashSet::new();
+        for (key, entry) in self.key_dir.iter().filter(|(_, e)| e.file_id <= last_id) {
+            if entry.is_expired(now) {
+                expired.push(key.into_owned());
+                continue;
             }
+            let reader = match readers.entry(entry.file_id) {
+                hash_map::Entry::Occupied(e) => e.into_mut(),
+                hash_map::Entry::Vacant(e) => {
+                    e.insert(DatFile::new(&self.base_dir, entry.file_id, true)?)
+                }
+            };
+            let value = reader.read_value(entry.value_sz, entry.value_pos as u64)?;
+            let block = if entry.blob {
+                // the blob itself stays where it is
+                let blob = BlobRef::decode(&value)?;
+                kept_blobs.insert(blob.id);
+                Block::new_blob_ref(entry.tstamp, entry.expire_at, key.to_vec(), &blob)
+            } else {
+                Block::new(entry.tstamp, entry.expire_at, key.to_vec(), value)
+            };
+            // the copy keeps the version, so a compare and swap racing the merge still works
+            let version = self.key_dir.version(&entry);
+            let value_pos = tmp_dat_file.append(block.with_version(version))?;
+            let merged_entry = KeyDirEntry {
+                file_id: last_id,
+                value_sz: entry.value_sz,
+                value_pos,
+                tstamp: entry.tstamp,
+                expire_at: entry.expire_at,
+                blob: entry.blob,
+            };
+            let moved_version = (version != version_at(last_id, value_pos)).then_some(version);
+            tmp_hint_file.put(&key, merged_entry, moved_version)?;
+            merged.push((key.into_owned(), merged_entry, version));
         }
+        drop(readers);
 
         tmp_dat_file.sync()?;
-        tmp_hint_file.sync()?;
+        tmp_hint_file.finish(tmp_dat_file.get_offset())?;
+        debug!(
+            file_id = last_id,
+            live = merged.len(),
+            expired = expired.len(),
+            "rewrote live records"
+        );
 
+        // stores without blobs skip reading through the merged files
+        let dead_blobs = if get_blob_files(&self.base_dir)?.is_empty() {
+            vec![]
+        } else {
+            dead_blobs(&dat_files_to_merge, &kept_blobs)?
+        };
+
+        // followers positioned in the rewritten files can no longer tail them, record that
+        // before the old contents go away
+        replication::write_merge_floor(&self.base_dir, last_id)?;
+        self.merge_floor = Some(last_id);
+
+        // a checkpoint points into the files about to be replaced
+        remove_checkpoint(&self.base_dir)?;
+        // drop the old hint first: if we crash between the renames, the merged data file is
+        // left without a hint and gets scanned on open instead of trusting a stale hint
+        let hint_path = self.base_dir.join(format_idx_file_name(last_id));
+        if let Err(err) = delete_file(&hint_path) {
+            if err.kind() != ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
         tmp_dat_file.rename(&self.base_dir.join(format_dat_file_name(last_id)))?;
-        tmp_hint_file.rename(&self.base_dir.join(format_idx_file_name(last_id)))?;
+        tmp_hint_file.rename(&hint_path)?;
+        self.key_dir.forget_versions(last_id);
+        for (key, entry, version) in merged {
+            self.key_dir.set_version(&entry, version);
+            self.key_dir.insert(key, entry);
+        }
+        for key in expired {
+            self.key_dir.remove(&key);
+        }
+        debug!(file_id = last_id, "replaced merged file");
+        self.unhinted.retain(|file_id| *file_id > last_id);
 
         // last file is the the to reserve file, so we don't delete it
         let files_to_delete = &dat_files_to_merge[0..dat_files_to_merge.len() - 1];
@@ -275,40 +981,48 @@ impl BitCask for BitCaskHandle {
         for path in files_to_delete {
             if let Err(err) = delete_file(path) {
                 if err.kind() != ErrorKind::NotFound {
-                    println!("failed to delete file {}, err: {}", path.display(), err);
+                    warn!(path = %path.display(), %err, "failed to delete merged file");
                 }
             }
             let hint_file_path = get_hint_from_dat_path(path);
             if let Err(err) = delete_file(&hint_file_path) {
                 if err.kind() != ErrorKind::NotFound {
-                    println!(
-                        "failed to delete file {}, err: {}",
-                        hint_file_path.display(),
-                        err
-                    );
+                    warn!(path = %hint_file_path.display(), %err, "failed to delete merged file");
                 }
             }
         }
         if let Err(err) = fs::remove_dir_all(&tmp_dir) {
             if err.kind() != ErrorKind::NotFound {
-                println!(
-                    "failed to delete tmp dir {}, err: {}",
-                    tmp_dir.display(),
-                    err
-                );
+                warn!(path = %tmp_dir.display(), %err, "failed to delete merge directory");
             }
         }
-        Ok(())
-    }
-
-    fn sync(&self) -> BitCaskResult<()> {
-        if let Some(ref f) = self.active_data_file {
-            f.sync()?
+        // only now that no record refers to them any more
+        for id in &dead_blobs {
+            if let Err(err) = delete_blob(&self.base_dir, *id) {
+                warn!(blob = id, %err, "failed to delete blob file");
+            }
         }
+        debug!(blobs = dead_blobs.len(), "deleted dead blob files");
         Ok(())
     }
+}
 
-    fn close(&self) -> BitCaskResult<()> {
-        self.sync()
+/// The blobs referred to by the records of `dat_files` other than `kept`. A blob only ever has
+/// one record referring to it: the one written with it, or the copy a merge made and then
+/// deleted the original. So those of records dropped by a merge are garbage.
+fn dead_blobs(dat_files: &[std::path::PathBuf], kept: &HashSet<u64>) -> BitCaskResult<Vec<u64>> {
+    let mut dead = vec![];
+    for path in dat_files {
+        for (_, block) in DatFile::from_path(path, true)?.iter() {
+            if !block.blob || block_crc(&block) != block.crc {
+                continue;
+            }
+            if let Ok(blob) = BlobRef::decode(&block.value) {
+                if !kept.contains(&blob.id) {
+                    dead.push(blob.id);
+                }
+            }
+        }
     }
+    Ok(dead)
 }
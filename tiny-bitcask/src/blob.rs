@@ -0,0 +1,117 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::bitcask::{BitCaskResult, Value};
+use crate::errors::BitCaskError;
+use crate::utils::*;
+use crate::value_reader::ValueReader;
+
+/// Size of an encoded `BlobRef`, the value of a record pointing at a blob.
+pub(crate) const BLOB_REF_SIZE: usize = 20;
+
+/// Where a value too large for the data files is kept: a blob file of its own, named after
+/// `id`, with the value's length and crc32 to check it against when read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlobRef {
+    /// the log position (`LogPosition::seq`) the record pointing at the blob was first
+    /// written to, which makes it unique in the store
+    pub id: u64,
+    pub len: u64,
+    pub crc: u32,
+}
+
+impl BlobRef {
+    pub fn encode(&self) -> Value {
+        let mut bytes = Vec::with_capacity(BLOB_REF_SIZE);
+        bytes.write_u64::<LittleEndian>(self.id).unwrap();
+        bytes.write_u64::<LittleEndian>(self.len).unwrap();
+        bytes.write_u32::<LittleEndian>(self.crc).unwrap();
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> BitCaskResult<Self> {
+        if bytes.len() != BLOB_REF_SIZE {
+            return Err(BitCaskError::ParseError);
+        }
+        Ok(Self {
+            id: LittleEndian::read_u64(&bytes[0..8]),
+            len: LittleEndian::read_u64(&bytes[8..16]),
+            crc: LittleEndian::read_u32(&bytes[16..20]),
+        })
+    }
+}
+
+/// Writes the `len` bytes read from `value` to a new blob file and syncs it, so it is durable
+/// before any record pointing at it. Nothing is left behind if `value` fails or ends early.
+pub(crate) fn write_blob(
+    base_dir: &Path,
+    id: u64,
+    value: impl Read,
+    len: u64,
+) -> BitCaskResult<BlobRef> {
+    let path = base_dir.join(format_blob_file_name(id));
+    let mut writer = HashingWriter {
+        file: File::create(&path)?,
+        hasher: crc32fast::Hasher::new(),
+    };
+    let written = io::copy(&mut value.take(len), &mut writer).and_then(|written| {
+        if written < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        writer.file.sync_all()?;
+        Ok(written)
+    });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&path);
+        return Err(err.into());
+    }
+    Ok(BlobRef {
+        id,
+        len,
+        crc: writer.hasher.finalize(),
+    })
+}
+
+/// Passes writes on to `file`, adding what was written to `hasher`.
+pub(crate) struct HashingWriter<W> {
+    pub file: W,
+    pub hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Opens the value of a blob file for reading, checked against its crc when read through.
+pub(crate) fn open_blob(base_dir: &Path, blob: &BlobRef) -> BitCaskResult<ValueReader> {
+    let file = File::open(base_dir.join(format_blob_file_name(blob.id)))?;
+    if file.metadata()?.len() != blob.len {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(ValueReader::new(file, 0, blob.len, Some(blob.crc))?)
+}
+
+pub(crate) fn read_blob(base_dir: &Path, blob: &BlobRef) -> BitCaskResult<Value> {
+    let mut value = Vec::with_capacity(blob.len as usize);
+    open_blob(base_dir, blob)?.read_to_end(&mut value)?;
+    Ok(value)
+}
+
+/// Removes the blob file `id`, if it is still there.
+pub(crate) fn delete_blob(base_dir: &Path, id: u64) -> io::Result<()> {
+    match fs::remove_file(base_dir.join(format_blob_file_name(id))) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
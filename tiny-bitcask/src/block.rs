@@ -2,48 +2,138 @@ use std::io::Write;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
-use crate::bitcask::{Key, Value, REMOVE_TOMBSTONE};
+use crate::bitcask::{BitCaskResult, Key, KeyRef, Value, REMOVE_TOMBSTONE};
+use crate::blob::BlobRef;
+use crate::errors::BitCaskError;
 use crate::utils;
 
 pub const HEADER_SIZE: usize = 16;
 
+/// The top byte of the on-disk key size carries record flags, which limits keys to 16MiB.
+/// Files written before flags existed have it zeroed.
+pub const KEY_SIZE_MASK: u32 = 0x00ff_ffff;
+/// Longest key a record can hold.
+pub const MAX_KEY_SIZE: usize = KEY_SIZE_MASK as usize;
+/// The header is followed by a u32 expiry timestamp.
+pub const FLAG_EXPIRES: u32 = 1 << 24;
+/// Hint records only: the data record is a delete.
+pub const FLAG_REMOVED: u32 = 1 << 25;
+/// The value is a `BlobRef`, the actual value is in a blob file.
+pub const FLAG_BLOB: u32 = 1 << 26;
+/// The header is followed by the u64 version of the write the record was copied from by a
+/// merge, after the expiry timestamp if there is one. Records without it have the version of
+/// their own position, see `BitCaskHandle::version`.
+pub const FLAG_VERSION: u32 = 1 << 27;
+
 pub struct Block {
     pub crc: u32,
     // u32 will cover time to 2106, it's enough
     pub tstamp: u32,
     pub ksz: u32,
     pub value_sz: u32,
+    // 0 if the record never expires
+    pub expire_at: u32,
+    // the value refers to a blob file
+    pub blob: bool,
+    // set on the copies merges make, the version of the original record
+    pub version: Option<u64>,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
 }
 
 impl Block {
-    pub fn new(tstamp: u32, key: Key, value: Value) -> Self {
+    pub fn new(tstamp: u32, expire_at: u32, key: Key, value: Value) -> Self {
         let mut block = Self {
             crc: 0,
             tstamp,
             ksz: key.len() as u32,
             value_sz: value.len() as u32,
+            expire_at,
+            blob: false,
+            version: None,
             key,
             value,
         };
         block.crc = utils::block_crc(&block);
         block
     }
+    /// A record whose value is stored in the blob file `blob` refers to.
+    pub(crate) fn new_blob_ref(tstamp: u32, expire_at: u32, key: Key, blob: &BlobRef) -> Self {
+        let mut block = Self::new(tstamp, expire_at, key, blob.encode());
+        block.blob = true;
+        block.crc = utils::block_crc(&block);
+        block
+    }
+    /// The copy of a record whose version is `version`, as a merge writes it.
+    pub(crate) fn with_version(mut self, version: u64) -> Self {
+        self.version = Some(version);
+        self.crc = utils::block_crc(&self);
+        self
+    }
     pub fn is_removed(&self) -> bool {
         (self.value_sz == REMOVE_TOMBSTONE.len() as u32) && self.value == REMOVE_TOMBSTONE
     }
+    pub fn is_expired(&self, now: u32) -> bool {
+        self.expire_at != 0 && self.expire_at <= now
+    }
+    /// The key size field as stored, with the flags in its top byte.
+    pub fn raw_ksz(&self) -> u32 {
+        let mut raw_ksz = self.ksz;
+        if self.expire_at != 0 {
+            raw_ksz |= FLAG_EXPIRES;
+        }
+        if self.blob {
+            raw_ksz |= FLAG_BLOB;
+        }
+        if self.version.is_some() {
+            raw_ksz |= FLAG_VERSION;
+        }
+        raw_ksz
+    }
+    pub fn header_size(&self) -> usize {
+        header_size(self.raw_ksz())
+    }
+    /// Offset of the value relative to the start of the record.
+    pub fn value_offset(&self) -> usize {
+        self.header_size() + self.key.len()
+    }
     pub fn size(&self) -> usize {
-        4 * 4 + self.key.len() + self.value.len()
+        self.value_offset() + self.value.len()
     }
     pub fn serialize(&self) -> Vec<u8> {
         let mut vec = Vec::with_capacity(self.size());
         vec.write_u32::<LittleEndian>(self.crc).unwrap();
         vec.write_u32::<LittleEndian>(self.tstamp).unwrap();
-        vec.write_u32::<LittleEndian>(self.ksz).unwrap();
+        vec.write_u32::<LittleEndian>(self.raw_ksz()).unwrap();
         vec.write_u32::<LittleEndian>(self.value_sz).unwrap();
+        if self.expire_at != 0 {
+            vec.write_u32::<LittleEndian>(self.expire_at).unwrap();
+        }
+        if let Some(version) = self.version {
+            vec.write_u64::<LittleEndian>(version).unwrap();
+        }
         vec.write_all(&self.key).unwrap();
         vec.write_all(&self.value).unwrap();
         vec
     }
 }
+
+/// Size of the header of a record whose raw key size field is `raw_ksz`.
+pub fn header_size(raw_ksz: u32) -> usize {
+    let mut size = HEADER_SIZE;
+    if raw_ksz & FLAG_EXPIRES != 0 {
+        size += 4;
+    }
+    if raw_ksz & FLAG_VERSION != 0 {
+        size += 8;
+    }
+    size
+}
+
+/// Fails with `KeyTooLarge` if `key` does not fit in a record, before anything is written.
+pub fn check_key_size(key: &KeyRef) -> BitCaskResult<()> {
+    if key.len() > MAX_KEY_SIZE {
+        return Err(BitCaskError::KeyTooLarge);
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::bitcask::{read_entry_value, BitCaskHandle, BitCaskResult, KeyDirEntry};
+use crate::errors::BitCaskError;
+use crate::replication::{read_log_range, LogPosition, LogRecord};
+use crate::snapshot::{pin_file, unpin_file, FilePins};
+
+/// Log bytes replayed from the data files in one go.
+const REPLAY_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A write to the store, as delivered to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// The log position right after the write, see `LogPosition::seq`. It increases from one
+    /// event to the next; saving the last one handled is enough to resume later.
+    pub seq: u64,
+    pub record: LogRecord,
+}
+
+pub(crate) type Subscribers = Vec<Sender<Live>>;
+
+/// An event queued for a subscriber.
+pub(crate) enum Live {
+    Event(ChangeEvent),
+    /// A write of a value streamed to the store, read by the subscriber once it gets to the
+    /// event rather than held in memory by the writer.
+    Pending(PendingValue),
+}
+
+pub(crate) struct PendingValue {
+    // the value is left out
+    event: ChangeEvent,
+    entry: KeyDirEntry,
+    // the data file of `entry` is pinned until the event is dropped, so merges keep the record
+    // and its blob
+    pins: FilePins,
+}
+
+impl Live {
+    fn read(self, base_dir: &Path) -> BitCaskResult<ChangeEvent> {
+        match self {
+            Live::Event(event) => Ok(event),
+            Live::Pending(pending) => {
+                let mut event = pending.event.clone();
+                event.record.value = Some(read_entry_value(base_dir, &pending.entry)?);
+                Ok(event)
+            }
+        }
+    }
+}
+
+impl Drop for PendingValue {
+    fn drop(&mut self) {
+        unpin_file(&self.pins, self.entry.file_id);
+    }
+}
+
+/// An ordered stream of the writes made to a store, see `BitCaskHandle::subscribe`.
+///
+/// Writes made before the subscription are read back from the data files first, later ones
+/// are queued by the handle as they happen, without bound: a subscriber that stops reading
+/// should be dropped.
+pub struct Subscription {
+    base_dir: PathBuf,
+    // replay position, the live events take over at replay_end
+    pos: LogPosition,
+    replay_end: LogPosition,
+    replayed: VecDeque<ChangeEvent>,
+    live: Receiver<Live>,
+    pins: FilePins,
+    // the file the replay is in, merges leave it and the later files alone
+    pinned: Option<u32>,
+}
+
+impl Subscription {
+    /// Waits for the next event. `None` once the handle is dropped and every event before
+    /// that has been delivered.
+    pub fn recv(&mut self) -> BitCaskResult<Option<ChangeEvent>> {
+        match self.next_replayed()? {
+            Some(event) => Ok(Some(event)),
+            None => self
+                .live
+                .recv()
+                .ok()
+                .map(|live| live.read(&self.base_dir))
+                .transpose(),
+        }
+    }
+
+    /// Like `recv`, `None` if no event shows up within `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> BitCaskResult<Option<ChangeEvent>> {
+        match self.next_replayed()? {
+            Some(event) => Ok(Some(event)),
+            None => match self.live.recv_timeout(timeout) {
+                Ok(live) => live.read(&self.base_dir).map(Some),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => Ok(None),
+            },
+        }
+    }
+
+    /// Like `recv` without waiting, `None` if no event is available right now.
+    pub fn try_recv(&mut self) -> BitCaskResult<Option<ChangeEvent>> {
+        match self.next_replayed()? {
+            Some(event) => Ok(Some(event)),
+            None => self
+                .live
+                .try_recv()
+                .ok()
+                .map(|live| live.read(&self.base_dir))
+                .transpose(),
+        }
+    }
+
+    fn next_replayed(&mut self) -> BitCaskResult<Option<ChangeEvent>> {
+        while self.replayed.is_empty() && self.pos < self.replay_end {
+            let (records, pos) =
+                read_log_range(&self.base_dir, self.pos, self.replay_end, REPLAY_CHUNK_SIZE)?;
+            self.replayed
+                .extend(records.into_iter().map(|(record, after)| ChangeEvent {
+                    seq: after.seq(),
+                    record,
+                }));
+            self.pos = pos;
+            // move the pin along, so merges can reclaim the files replayed already
+            let pinned = (self.pos < self.replay_end).then_some(self.pos.file_id);
+            if pinned != self.pinned {
+                if let Some(file_id) = pinned {
+                    pin_file(&self.pins, file_id);
+                }
+                if let Some(file_id) = self.pinned {
+                    unpin_file(&self.pins, file_id);
+                }
+                self.pinned = pinned;
+            }
+        }
+        Ok(self.replayed.pop_front())
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(file_id) = self.pinned {
+            unpin_file(&self.pins, file_id);
+        }
+    }
+}
+
+impl BitCaskHandle {
+    /// Subscribes to the writes made after the event with sequence number `after`, or from now
+    /// on if `None`. `Some(0)` replays the whole log.
+    ///
+    /// Every put and delete is delivered once, in log order, including the writes `apply`
+    /// makes on a follower. Keys reaching their expiry produce no event, the `expire_at` of
+    /// their last write tells when that happens. Merges produce none either, they change no
+    /// value.
+    ///
+    /// Values written by `put_from_reader` are read by the subscriber when it gets to their
+    /// event, not by the write: the data file the write went to is kept from merges until then.
+    ///
+    /// Fails with `UnknownLogPosition` once a merge has rewritten the part of the log to
+    /// replay; the consumer has to start over from a `snapshot` taken together with a
+    /// subscription from now on.
+    pub fn subscribe(&mut self, after: Option<u64>) -> BitCaskResult<Subscription> {
+        let end = self.log_end();
+        let from = after.map_or(end, LogPosition::from_seq);
+        if from > end || self.merge_floor.is_some_and(|floor| from.file_id <= floor) {
+            return Err(BitCaskError::UnknownLogPosition);
+        }
+        let (sender, live) = mpsc::channel();
+        self.subscribers.push(sender);
+        let pinned = (from < end).then_some(from.file_id);
+        if let Some(file_id) = pinned {
+            pin_file(&self.pins, file_id);
+        }
+        Ok(Subscription {
+            base_dir: self.base_dir.clone(),
+            pos: from,
+            replay_end: end,
+            replayed: VecDeque::new(),
+            live,
+            pins: self.pins.clone(),
+            pinned,
+        })
+    }
+
+    /// Hands a write to the subscribers, dropping those that went away.
+    pub(crate) fn publish(&mut self, event: ChangeEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(Live::Event(event.clone())).is_ok());
+    }
+
+    /// Like `publish` for a write whose value is left where `entry` points at.
+    pub(crate) fn publish_pending(&mut self, event: ChangeEvent, entry: KeyDirEntry) {
+        let pins = self.pins.clone();
+        self.subscribers.retain(|subscriber| {
+            pin_file(&pins, entry.file_id);
+            let pending = PendingValue {
+                event: event.clone(),
+                entry,
+                pins: pins.clone(),
+            };
+            subscriber.send(Live::Pending(pending)).is_ok()
+        });
+    }
+}
@@ -1,8 +1,9 @@
-use std::io::{Read, Seek};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
 use crate::bitcask::{BitCaskResult, KeyRef, Value, ValueRef};
-use crate::block::Block;
+use crate::blob::{BlobRef, HashingWriter};
+use crate::block::{check_key_size, Block};
 use crate::file_ext::{ReadExt, WriteBlock};
 use crate::utils::*;
 
@@ -16,6 +17,10 @@ impl DatFileIter {
         file.rewind().unwrap();
         Self { pos: 0, file }
     }
+
+    fn starting_at(file: std::fs::File, pos: u32) -> Self {
+        Self { pos, file }
+    }
 }
 
 impl Iterator for DatFileIter {
@@ -25,13 +30,13 @@ impl Iterator for DatFileIter {
         let block = self.file.read_block_at(self.pos as u64).ok();
 
         let pos = self.pos;
-        return match block {
+        match block {
             None => None,
             Some(block) => {
                 self.pos += block.size() as u32;
                 Some((pos, block))
             }
-        };
+        }
     }
 }
 
@@ -71,12 +76,95 @@ impl DatFile {
     pub fn iter(self) -> DatFileIter {
         DatFileIter::new(self.file)
     }
-    pub fn write(&mut self, tstamp: u32, key: &KeyRef, value: &ValueRef) -> BitCaskResult<u32> {
-        let block = Block::new(tstamp, key.to_vec(), value.to_vec());
+    /// Iterates over the records from `offset` on, which has to be a record boundary.
+    pub fn iter_from(self, offset: u32) -> DatFileIter {
+        DatFileIter::starting_at(self.file, offset)
+    }
+    /// Appends a record and returns the position of its value in the file. A `version` is
+    /// stored in the record, for a write copied from another store.
+    pub fn write(
+        &mut self,
+        tstamp: u32,
+        expire_at: u32,
+        key: &KeyRef,
+        value: &ValueRef,
+        version: Option<u64>,
+    ) -> BitCaskResult<u32> {
+        let block = Block::new(tstamp, expire_at, key.to_vec(), value.to_vec());
+        self.append(match version {
+            Some(version) => block.with_version(version),
+            None => block,
+        })
+    }
+
+    /// Appends a record whose value is stored in the blob file `blob` refers to.
+    pub(crate) fn write_blob_ref(
+        &mut self,
+        tstamp: u32,
+        expire_at: u32,
+        key: &KeyRef,
+        blob: &BlobRef,
+        version: Option<u64>,
+    ) -> BitCaskResult<u32> {
+        let block = Block::new_blob_ref(tstamp, expire_at, key.to_vec(), blob);
+        self.append(match version {
+            Some(version) => block.with_version(version),
+            None => block,
+        })
+    }
+
+    /// Appends a record whose `len` byte value is streamed from `value`, and returns the
+    /// position of the value in the file. The crc is filled in once the value is written; if
+    /// `value` fails or ends early, the file is cut back to where it was.
+    pub(crate) fn write_from_reader(
+        &mut self,
+        tstamp: u32,
+        expire_at: u32,
+        key: &KeyRef,
+        value: impl Read,
+        len: u32,
+    ) -> BitCaskResult<u32> {
+        check_key_size(key)?;
+        let mut block = Block::new(tstamp, expire_at, key.to_vec(), vec![]);
+        block.value_sz = len;
+        let header = block.serialize();
+        let start = self.offset as u64;
+        let end = start + header.len() as u64 + len as u64;
+        let mut writer = HashingWriter {
+            file: &mut self.file,
+            hasher: header_crc(&block),
+        };
+        let written = writer
+            .file
+            .write_all(&header)
+            .and_then(|()| io::copy(&mut value.take(len as u64), &mut writer))
+            .and_then(|written| {
+                if written < len as u64 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                let crc = writer.hasher.clone().finalize();
+                writer.file.seek(SeekFrom::Start(start))?;
+                writer.file.write_all(&crc.to_le_bytes())?;
+                writer.file.seek(SeekFrom::Start(end))?;
+                Ok(())
+            });
+        if let Err(err) = written {
+            // what made it to the file is no record
+            self.file.set_len(start)?;
+            self.file.seek(SeekFrom::Start(start))?;
+            return Err(err.into());
+        }
+        self.offset = end as u32;
+        Ok(start as u32 + block.value_offset() as u32)
+    }
+
+    /// Appends `block` and returns the position of its value in the file.
+    pub(crate) fn append(&mut self, block: Block) -> BitCaskResult<u32> {
+        check_key_size(&block.key)?;
         let file_offset = self.offset;
         let _ = self.file.write_block(&block)?;
         self.offset += block.size() as u32;
-        Ok(file_offset)
+        Ok(file_offset + block.value_offset() as u32)
     }
 
     pub fn read_value(&mut self, value_sz: u32, offset: u64) -> BitCaskResult<Value> {
@@ -96,7 +184,7 @@ impl DatFile {
         Ok(())
     }
 
-    pub fn get_offset(&mut self) -> u32 {
+    pub fn get_offset(&self) -> u32 {
         self.offset
     }
 }
@@ -0,0 +1,574 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+
+use crate::bitcask::{BitCaskResult, Key, Value};
+use crate::block::{
+    header_size, Block, FLAG_BLOB, FLAG_EXPIRES, FLAG_REMOVED, FLAG_VERSION, HEADER_SIZE,
+    KEY_SIZE_MASK,
+};
+use crate::index_file::{HINT_FOOTER_SIZE, HINT_MAGIC};
+use crate::utils::block_crc;
+
+/// Size of the fixed part of a hint record: crc, key size, value size, value position,
+/// timestamp. Records of expiring keys carry an extra u32 expiry timestamp, those of records
+/// copied by a merge a u64 version after it.
+const HINT_HEADER_SIZE: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Put,
+    Delete,
+    /// a put whose value is in a blob file, the record holding a reference to it
+    Blob,
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordKind::Put => write!(f, "put"),
+            RecordKind::Delete => write!(f, "delete"),
+            RecordKind::Blob => write!(f, "blob"),
+        }
+    }
+}
+
+/// One physical record of a `.dat` or `.idx` file, as found on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpRecord {
+    Data {
+        offset: u64,
+        crc: u32,
+        crc_valid: bool,
+        tstamp: u32,
+        expire_at: u32,
+        /// set on records copied by a merge
+        version: Option<u64>,
+        key_sz: u32,
+        value_sz: u32,
+        kind: RecordKind,
+        key: Key,
+        value: Value,
+    },
+    Hint {
+        offset: u64,
+        kind: RecordKind,
+        crc: u32,
+        crc_valid: bool,
+        tstamp: u32,
+        expire_at: u32,
+        version: Option<u64>,
+        key: Key,
+        value_sz: u32,
+        value_pos: u32,
+    },
+    /// The footer of a complete hint file.
+    HintFooter {
+        offset: u64,
+        /// length of the data file the hint covers
+        data_len: u32,
+        records: u32,
+        crc: u32,
+        crc_valid: bool,
+    },
+    /// The file ends with a record that cannot be read completely: a torn write or a
+    /// corrupted header claiming more bytes than the file holds.
+    Truncated { offset: u64, remaining: u64 },
+}
+
+impl DumpRecord {
+    pub fn offset(&self) -> u64 {
+        match self {
+            DumpRecord::Data { offset, .. }
+            | DumpRecord::Hint { offset, .. }
+            | DumpRecord::HintFooter { offset, .. }
+            | DumpRecord::Truncated { offset, .. } => *offset,
+        }
+    }
+
+    /// Renders the record as a single JSON object, with keys and values shortened to at most
+    /// `preview_len` bytes and given both as (lossy) utf8 and hex.
+    pub fn to_json(&self, preview_len: usize) -> String {
+        match self {
+            DumpRecord::Data {
+                offset,
+                crc,
+                crc_valid,
+                tstamp,
+                expire_at,
+                version,
+                key_sz,
+                value_sz,
+                kind,
+                key,
+                value,
+            } => format!(
+                "{{\"type\":\"{}\",\"offset\":{},\"crc\":{},\"crc_valid\":{},\"tstamp\":{},\"expire_at\":{},\"version\":{},\"key_size\":{},\"value_size\":{},{},{}}}",
+                kind,
+                offset,
+                crc,
+                crc_valid,
+                tstamp,
+                expire_at,
+                json_version(*version),
+                key_sz,
+                value_sz,
+                json_preview("key", key, preview_len),
+                json_preview("value", value, preview_len)
+            ),
+            DumpRecord::Hint {
+                offset,
+                kind,
+                crc,
+                crc_valid,
+                tstamp,
+                expire_at,
+                version,
+                key,
+                value_sz,
+                value_pos,
+            } => format!(
+                "{{\"type\":\"hint\",\"offset\":{},\"kind\":\"{}\",\"crc\":{},\"crc_valid\":{},\"tstamp\":{},\"expire_at\":{},\"version\":{},\"key_size\":{},\"value_size\":{},\"value_pos\":{},{}}}",
+                offset,
+                kind,
+                crc,
+                crc_valid,
+                tstamp,
+                expire_at,
+                json_version(*version),
+                key.len(),
+                value_sz,
+                value_pos,
+                json_preview("key", key, preview_len)
+            ),
+            DumpRecord::HintFooter {
+                offset,
+                data_len,
+                records,
+                crc,
+                crc_valid,
+            } => format!(
+                "{{\"type\":\"footer\",\"offset\":{},\"data_len\":{},\"records\":{},\"crc\":{},\"crc_valid\":{}}}",
+                offset, data_len, records, crc, crc_valid
+            ),
+            DumpRecord::Truncated { offset, remaining } => format!(
+                "{{\"type\":\"truncated\",\"offset\":{},\"remaining\":{}}}",
+                offset, remaining
+            ),
+        }
+    }
+
+    /// Renders the record as one human readable line, see `to_json` for `preview_len`.
+    pub fn to_text(&self, preview_len: usize) -> String {
+        match self {
+            DumpRecord::Data {
+                offset,
+                crc,
+                crc_valid,
+                tstamp,
+                expire_at,
+                version,
+                key_sz,
+                value_sz,
+                kind,
+                key,
+                value,
+            } => format!(
+                "{:>10} {:<6} crc={:08x}({}) tstamp={}{}{} ksz={} vsz={} key={} value={}",
+                offset,
+                kind.to_string(),
+                crc,
+                if *crc_valid { "ok" } else { "BAD" },
+                tstamp,
+                text_expiry(*expire_at),
+                text_version(*version),
+                key_sz,
+                value_sz,
+                text_preview(key, preview_len),
+                text_preview(value, preview_len)
+            ),
+            DumpRecord::Hint {
+                offset,
+                kind,
+                crc,
+                crc_valid,
+                tstamp,
+                expire_at,
+                version,
+                key,
+                value_sz,
+                value_pos,
+            } => format!(
+                "{:>10} hint   {} crc={:08x}({}) tstamp={}{}{} ksz={} vsz={} vpos={} key={}",
+                offset,
+                kind,
+                crc,
+                if *crc_valid { "ok" } else { "BAD" },
+                tstamp,
+                text_expiry(*expire_at),
+                text_version(*version),
+                key.len(),
+                value_sz,
+                value_pos,
+                text_preview(key, preview_len)
+            ),
+            DumpRecord::HintFooter {
+                offset,
+                data_len,
+                records,
+                crc,
+                crc_valid,
+            } => format!(
+                "{:>10} footer data_len={} records={} crc={:08x}({})",
+                offset,
+                data_len,
+                records,
+                crc,
+                if *crc_valid { "ok" } else { "BAD" }
+            ),
+            DumpRecord::Truncated { offset, remaining } => format!(
+                "{:>10} TRUNCATED {} trailing bytes are not a complete record",
+                offset, remaining
+            ),
+        }
+    }
+}
+
+fn text_expiry(expire_at: u32) -> String {
+    if expire_at == 0 {
+        String::new()
+    } else {
+        format!(" expire_at={}", expire_at)
+    }
+}
+
+fn text_version(version: Option<u64>) -> String {
+    version.map_or(String::new(), |version| format!(" version={}", version))
+}
+
+fn json_version(version: Option<u64>) -> String {
+    version.map_or("null".to_string(), |version| version.to_string())
+}
+
+/// Shows printable utf8 as a quoted string and anything else as hex.
+fn text_preview(bytes: &[u8], preview_len: usize) -> String {
+    let shown = &bytes[..bytes.len().min(preview_len)];
+    let ellipsis = if shown.len() < bytes.len() { "..." } else { "" };
+    match std::str::from_utf8(shown) {
+        Ok(s) if !s.chars().any(char::is_control) => format!("{:?}{}", s, ellipsis),
+        _ => format!("0x{}{}", to_hex(shown), ellipsis),
+    }
+}
+
+fn json_preview(name: &str, bytes: &[u8], preview_len: usize) -> String {
+    let shown = &bytes[..bytes.len().min(preview_len)];
+    format!(
+        "\"{}\":{},\"{}_hex\":\"{}\"",
+        name,
+        json_string(&String::from_utf8_lossy(shown)),
+        name,
+        to_hex(shown)
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    Data,
+    Hint,
+}
+
+/// Iterates over the records of a data or hint file without trusting it: sizes are checked
+/// against the file length before anything is allocated, and a damaged tail is reported as
+/// `DumpRecord::Truncated` instead of ending the iteration silently.
+pub struct DumpIter {
+    kind: FileKind,
+    reader: BufReader<File>,
+    pos: u64,
+    // end of the records, before the footer of a hint file
+    len: u64,
+    footer: Option<DumpRecord>,
+    done: bool,
+}
+
+/// Opens a `.dat` or `.idx` file for dumping, the format is picked by the extension.
+pub fn dump_file(path: &Path) -> BitCaskResult<DumpIter> {
+    let kind = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("idx") => FileKind::Hint,
+        _ => FileKind::Data,
+    };
+    let file = File::open(path)?;
+    let mut len = file.metadata()?.len();
+    let footer = match kind {
+        FileKind::Hint => read_hint_footer(path)?,
+        FileKind::Data => None,
+    };
+    if let Some(footer) = &footer {
+        len = footer.offset();
+    }
+    Ok(DumpIter {
+        kind,
+        reader: BufReader::new(file),
+        pos: 0,
+        len,
+        footer,
+        done: false,
+    })
+}
+
+/// The footer ending a hint file, if it has one.
+fn read_hint_footer(path: &Path) -> std::io::Result<Option<DumpRecord>> {
+    let bytes = std::fs::read(path)?;
+    let Some(offset) = bytes.len().checked_sub(HINT_FOOTER_SIZE) else {
+        return Ok(None);
+    };
+    let field = |i: usize| LittleEndian::read_u32(&bytes[offset + i * 4..]);
+    if field(3) != HINT_MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(DumpRecord::HintFooter {
+        offset: offset as u64,
+        data_len: field(0),
+        records: field(1),
+        crc: field(2),
+        crc_valid: crc32fast::hash(&bytes[..offset + 8]) == field(2),
+    }))
+}
+
+impl DumpIter {
+    fn read_data_record(&mut self) -> std::io::Result<Option<DumpRecord>> {
+        let remaining = self.len - self.pos;
+        if remaining < HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        let crc = self.reader.read_u32::<LittleEndian>()?;
+        let tstamp = self.reader.read_u32::<LittleEndian>()?;
+        let raw_ksz = self.reader.read_u32::<LittleEndian>()?;
+        let value_sz = self.reader.read_u32::<LittleEndian>()?;
+        let ksz = raw_ksz & KEY_SIZE_MASK;
+        if header_size(raw_ksz) as u64 + ksz as u64 + value_sz as u64 > remaining {
+            return Ok(None);
+        }
+        let expire_at = if raw_ksz & FLAG_EXPIRES != 0 {
+            self.reader.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+        let version = if raw_ksz & FLAG_VERSION != 0 {
+            Some(self.reader.read_u64::<LittleEndian>()?)
+        } else {
+            None
+        };
+        let mut key = vec![0; ksz as usize];
+        self.reader.read_exact(&mut key)?;
+        let mut value = vec![0; value_sz as usize];
+        self.reader.read_exact(&mut value)?;
+        let block = Block {
+            crc,
+            tstamp,
+            ksz,
+            value_sz,
+            expire_at,
+            blob: raw_ksz & FLAG_BLOB != 0,
+            version,
+            key,
+            value,
+        };
+        let kind = if block.is_removed() {
+            RecordKind::Delete
+        } else if block.blob {
+            RecordKind::Blob
+        } else {
+            RecordKind::Put
+        };
+        let offset = self.pos;
+        self.pos += block.size() as u64;
+        Ok(Some(DumpRecord::Data {
+            offset,
+            crc,
+            crc_valid: block_crc(&block) == crc,
+            tstamp,
+            expire_at,
+            version,
+            key_sz: ksz,
+            value_sz,
+            kind,
+            key: block.key,
+            value: block.value,
+        }))
+    }
+
+    fn read_hint_record(&mut self) -> std::io::Result<Option<DumpRecord>> {
+        let remaining = self.len - self.pos;
+        if remaining < HINT_HEADER_SIZE {
+            return Ok(None);
+        }
+        let crc = self.reader.read_u32::<LittleEndian>()?;
+        let raw_ksz = self.reader.read_u32::<LittleEndian>()?;
+        let ksz = raw_ksz & KEY_SIZE_MASK;
+        let size = (header_size(raw_ksz) - HEADER_SIZE) as u64 + HINT_HEADER_SIZE + ksz as u64;
+        if size > remaining {
+            return Ok(None);
+        }
+        // the rest of the record is read whole, it is what the crc covers
+        let mut rest = vec![0; size as usize - 8];
+        self.reader.read_exact(&mut rest)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&raw_ksz.to_le_bytes());
+        hasher.update(&rest);
+        let crc_valid = hasher.finalize() == crc;
+        let key = rest[..ksz as usize].to_vec();
+        let field = |i: usize| LittleEndian::read_u32(&rest[ksz as usize + i * 4..]);
+        let (value_sz, value_pos, tstamp) = (field(0), field(1), field(2));
+        let fields = if raw_ksz & FLAG_EXPIRES != 0 { 4 } else { 3 };
+        let expire_at = if fields == 4 { field(3) } else { 0 };
+        let version = (raw_ksz & FLAG_VERSION != 0)
+            .then(|| LittleEndian::read_u64(&rest[ksz as usize + fields * 4..]));
+        let offset = self.pos;
+        self.pos += size;
+        let kind = if raw_ksz & FLAG_REMOVED != 0 {
+            RecordKind::Delete
+        } else if raw_ksz & FLAG_BLOB != 0 {
+            RecordKind::Blob
+        } else {
+            RecordKind::Put
+        };
+        Ok(Some(DumpRecord::Hint {
+            offset,
+            kind,
+            crc,
+            crc_valid,
+            tstamp,
+            expire_at,
+            version,
+            key,
+            value_sz,
+            value_pos,
+        }))
+    }
+}
+
+impl Iterator for DumpIter {
+    type Item = BitCaskResult<DumpRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.pos >= self.len {
+            self.done = true;
+            return self.footer.take().map(Ok);
+        }
+        let record = match self.kind {
+            FileKind::Data => self.read_data_record(),
+            FileKind::Hint => self.read_hint_record(),
+        };
+        match record {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                let truncated = DumpRecord::Truncated {
+                    offset: self.pos,
+                    remaining: self.len - self.pos,
+                };
+                // a hint footer after the damage is still shown
+                self.pos = self.len;
+                Some(Ok(truncated))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err.into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::*;
+    use crate::bitcask::{BitCask, BitCaskHandle, Opts};
+
+    #[test]
+    fn test_dump_dat_file() {
+        let dir = std::path::PathBuf::from("/tmp/bitcask_dump_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        {
+            let mut db = BitCaskHandle::open(dir.clone(), Opts::new(1024)).unwrap();
+            db.put(b"hello", b"world").unwrap();
+            db.put(b"bin", &[0, 1, 2]).unwrap();
+            db.delete(b"hello").unwrap();
+        }
+        let path = dir.join("000000000.dat");
+        // flip a byte of the second value and append half a header
+        let mut bytes = std::fs::read(&path).unwrap();
+        let second = HEADER_SIZE + 10 + HEADER_SIZE + 3;
+        bytes[second + 1] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3, 4, 5, 6]).unwrap();
+
+        let records: Vec<_> = dump_file(&path).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 4);
+        match &records[0] {
+            DumpRecord::Data {
+                crc_valid, kind, ..
+            } => assert!(*crc_valid && *kind == RecordKind::Put),
+            other => panic!("unexpected {:?}", other),
+        }
+        match &records[1] {
+            DumpRecord::Data { crc_valid, .. } => assert!(!crc_valid),
+            other => panic!("unexpected {:?}", other),
+        }
+        match &records[2] {
+            DumpRecord::Data { kind, .. } => assert_eq!(*kind, RecordKind::Delete),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            records[3],
+            DumpRecord::Truncated {
+                offset: bytes.len() as u64,
+                remaining: 6
+            }
+        );
+
+        let json = records[0].to_json(3);
+        assert!(json.starts_with("{\"type\":\"put\",\"offset\":0,"));
+        assert!(json.ends_with(
+            "\"key\":\"hel\",\"key_hex\":\"68656c\",\"value\":\"wor\",\"value_hex\":\"776f72\"}"
+        ));
+        assert!(records[0]
+            .to_text(16)
+            .contains("key=\"hello\" value=\"world\""));
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
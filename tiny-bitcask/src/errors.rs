@@ -2,6 +2,11 @@
 pub enum BitCaskError {
     IoError,
     ParseError,
+    InvalidBackup,
+    ReadOnly,
+    UnknownLogPosition,
+    /// the key is longer than `MAX_KEY_SIZE`, its size would spill into the record flags
+    KeyTooLarge,
 }
 
 impl From<std::io::Error> for BitCaskError {
@@ -15,3 +20,20 @@ impl From<std::num::ParseIntError> for BitCaskError {
         BitCaskError::ParseError
     }
 }
+
+impl std::fmt::Display for BitCaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BitCaskError::IoError => write!(f, "io error"),
+            BitCaskError::ParseError => write!(f, "parse error"),
+            BitCaskError::InvalidBackup => write!(f, "invalid backup"),
+            BitCaskError::ReadOnly => write!(f, "store is read only"),
+            BitCaskError::UnknownLogPosition => write!(f, "log position no longer available"),
+            BitCaskError::KeyTooLarge => {
+                write!(f, "key longer than {} bytes", crate::block::MAX_KEY_SIZE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BitCaskError {}
@@ -1,5 +1,5 @@
 use crate::bitcask::BitCaskResult;
-use crate::block::Block;
+use crate::block::{Block, FLAG_BLOB, FLAG_EXPIRES, FLAG_VERSION, KEY_SIZE_MASK};
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::io::Write;
 
@@ -28,8 +28,19 @@ where
 
         let crc = self.read_u32::<LittleEndian>()?;
         let tstamp = self.read_u32::<LittleEndian>()?;
-        let ksz = self.read_u32::<LittleEndian>()?;
+        let raw_ksz = self.read_u32::<LittleEndian>()?;
         let value_sz = self.read_u32::<LittleEndian>()?;
+        let expire_at = if raw_ksz & FLAG_EXPIRES != 0 {
+            self.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+        let version = if raw_ksz & FLAG_VERSION != 0 {
+            Some(self.read_u64::<LittleEndian>()?)
+        } else {
+            None
+        };
+        let ksz = raw_ksz & KEY_SIZE_MASK;
         let mut key = vec![0; ksz as usize];
         self.read_exact(&mut key)?;
         let mut value = vec![0; value_sz as usize];
@@ -40,6 +51,9 @@ where
             tstamp,
             ksz,
             value_sz,
+            expire_at,
+            blob: raw_ksz & FLAG_BLOB != 0,
+            version,
             key,
             value,
         })
@@ -1,83 +1,268 @@
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 
-use crate::bitcask::{BitCaskResult, Key, KeyDirEntry, KeyRef};
+use crate::bitcask::{version_at, BitCaskResult, Key, KeyDirEntry, KeyRef};
+use crate::block::{FLAG_BLOB, FLAG_EXPIRES, FLAG_REMOVED, FLAG_VERSION, KEY_SIZE_MASK};
+use crate::dat_file::DatFile;
+use crate::utils::format_idx_file_name;
 
+/// Size of the footer closing a complete hint file: the length of the data file it covers,
+/// the number of records, a crc32 of everything before the crc, and `HINT_MAGIC`.
+pub(crate) const HINT_FOOTER_SIZE: usize = 16;
+/// Each hint record also starts with a crc32 of the rest of it, files of the first format
+/// (`BCH1`) had none and are rebuilt like damaged ones.
+pub(crate) const HINT_MAGIC: u32 = u32::from_le_bytes(*b"BCH2");
+
+/// Why a hint file was not used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HintDamage {
+    /// no valid footer: the file was cut short, or written in an older format
+    NoFooter,
+    /// the footer checksum does not match the file
+    Checksum,
+    /// the record at this offset fails its checksum or runs into the footer
+    BadRecord { offset: u64 },
+    /// the footer counts a different number of records than the file holds
+    Count { footer: u32, found: u32 },
+    /// the hint covers more bytes than its data file has
+    Stale { data_len: u32, file_len: u64 },
+}
+
+impl fmt::Display for HintDamage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HintDamage::NoFooter => write!(f, "no footer"),
+            HintDamage::Checksum => write!(f, "checksum mismatch"),
+            HintDamage::BadRecord { offset } => write!(f, "bad record at offset {}", offset),
+            HintDamage::Count { footer, found } => {
+                write!(f, "footer counts {} records, not {}", footer, found)
+            }
+            HintDamage::Stale { data_len, file_len } => write!(
+                f,
+                "covers {} bytes of a {} byte data file",
+                data_len, file_len
+            ),
+        }
+    }
+}
+
+/// Writes a hint file. It only becomes valid once `finish` adds the footer, a hint cut short
+/// before that is rebuilt from its data file on open.
 pub struct HintFile {
     path: PathBuf,
-    file: std::fs::File,
+    writer: BufWriter<File>,
+    crc: crc32fast::Hasher,
+    records: u32,
 }
 
 impl HintFile {
-    pub fn open_by_path(path: PathBuf, readonly: bool) -> BitCaskResult<Self> {
-        let file = if readonly {
-            OpenOptions::new().read(true).open(path.clone())?
-        } else {
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(path.clone())?
-        };
+    /// Creates the file, replacing any previous one.
+    pub fn create(path: PathBuf) -> BitCaskResult<Self> {
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            crc: crc32fast::Hasher::new(),
+            records: 0,
+        })
+    }
 
-        Ok(Self { path, file })
+    /// Records `key` at `entry`, with the version of the record if a merge copied it there.
+    pub fn put(
+        &mut self,
+        key: &KeyRef,
+        entry: KeyDirEntry,
+        moved_version: Option<u64>,
+    ) -> BitCaskResult<()> {
+        self.write_record(key, entry, moved_version, 0)
     }
 
-    pub fn put(&mut self, key: &KeyRef, entry: KeyDirEntry) -> BitCaskResult<()> {
-        self.file.write_u32::<LittleEndian>(key.len() as u32)?;
-        self.file.write_all(key)?;
-        self.file.write_u32::<LittleEndian>(entry.value_sz)?;
-        self.file.write_u32::<LittleEndian>(entry.value_pos)?;
-        self.file.write_u32::<LittleEndian>(entry.tstamp)?;
-        Ok(())
+    /// Records a delete of `key`, `entry` pointing at the tombstone.
+    pub fn put_removed(&mut self, key: &KeyRef, entry: KeyDirEntry) -> BitCaskResult<()> {
+        self.write_record(key, entry, None, FLAG_REMOVED)
     }
-    pub fn next_record(&mut self) -> BitCaskResult<Option<IndexRecord>> {
-        let key_len = self.file.read_u32::<LittleEndian>()?;
-        let mut key = vec![0; key_len as usize];
-        self.file.read_exact(&mut key)?;
-        let value_sz = self.file.read_u32::<LittleEndian>()?;
-        let value_pos = self.file.read_u32::<LittleEndian>()?;
-        let tstamp = self.file.read_u32::<LittleEndian>()?;
-        Ok(Some(IndexRecord {
-            key,
-            value_sz,
-            value_pos,
-            tstamp,
-        }))
+
+    fn write_record(
+        &mut self,
+        key: &KeyRef,
+        entry: KeyDirEntry,
+        moved_version: Option<u64>,
+        flags: u32,
+    ) -> BitCaskResult<()> {
+        // same flags as the key size of a data record
+        let mut raw_ksz = key.len() as u32 | flags;
+        if entry.expire_at != 0 {
+            raw_ksz |= FLAG_EXPIRES;
+        }
+        if entry.blob {
+            raw_ksz |= FLAG_BLOB;
+        }
+        // only copies made by a merge need theirs, the others have that of their position
+        if moved_version.is_some() {
+            raw_ksz |= FLAG_VERSION;
+        }
+        let mut record = Vec::with_capacity(32 + key.len());
+        // room for the crc of the rest
+        record.write_u32::<LittleEndian>(0)?;
+        record.write_u32::<LittleEndian>(raw_ksz)?;
+        record.write_all(key)?;
+        record.write_u32::<LittleEndian>(entry.value_sz)?;
+        record.write_u32::<LittleEndian>(entry.value_pos)?;
+        record.write_u32::<LittleEndian>(entry.tstamp)?;
+        if entry.expire_at != 0 {
+            record.write_u32::<LittleEndian>(entry.expire_at)?;
+        }
+        if let Some(version) = moved_version {
+            record.write_u64::<LittleEndian>(version)?;
+        }
+        let crc = crc32fast::hash(&record[4..]);
+        LittleEndian::write_u32(&mut record[..4], crc);
+        self.crc.update(&record);
+        self.writer.write_all(&record)?;
+        self.records += 1;
+        Ok(())
     }
-    pub fn iter(self) -> HintFileIter {
-        HintFileIter { file: self }
+
+    /// Writes the footer, saying the hint describes the first `data_len` bytes of its data
+    /// file, and syncs the file.
+    pub fn finish(&mut self, data_len: u32) -> BitCaskResult<()> {
+        let mut footer = Vec::with_capacity(HINT_FOOTER_SIZE);
+        footer.write_u32::<LittleEndian>(data_len)?;
+        footer.write_u32::<LittleEndian>(self.records)?;
+        self.crc.update(&footer);
+        footer.write_u32::<LittleEndian>(self.crc.clone().finalize())?;
+        footer.write_u32::<LittleEndian>(HINT_MAGIC)?;
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
     }
 
     pub fn rename(&self, new_path: &PathBuf) -> BitCaskResult<()> {
-        std::fs::rename(&self.path, new_path)?;
+        fs::rename(&self.path, new_path)?;
         Ok(())
     }
-    pub fn sync(&self) -> BitCaskResult<()> {
-        self.file.sync_all()?;
-        Ok(())
+
+    /// Reads a complete hint file, checking the footer and every record. An I/O error is
+    /// returned as such, anything wrong with the contents as the `HintDamage` found.
+    pub fn load(path: &Path) -> BitCaskResult<Result<Hint, HintDamage>> {
+        let bytes = fs::read(path)?;
+        Ok(Self::parse(&bytes))
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Hint, HintDamage> {
+        let body_len = bytes
+            .len()
+            .checked_sub(HINT_FOOTER_SIZE)
+            .ok_or(HintDamage::NoFooter)?;
+        let footer = &bytes[body_len..];
+        if LittleEndian::read_u32(&footer[12..16]) != HINT_MAGIC {
+            return Err(HintDamage::NoFooter);
+        }
+        let data_len = LittleEndian::read_u32(&footer[0..4]);
+        let count = LittleEndian::read_u32(&footer[4..8]);
+        let crc = LittleEndian::read_u32(&footer[8..12]);
+        let mut records = Vec::with_capacity(count as usize);
+        let mut body = &bytes[..body_len];
+        while !body.is_empty() {
+            let offset = (body_len - body.len()) as u64;
+            let (record, size) = parse_record(body).ok_or(HintDamage::BadRecord { offset })?;
+            records.push(record);
+            body = &body[size..];
+        }
+        // checked after the records, so a damaged record is reported as such
+        if crc32fast::hash(&bytes[..body_len + 8]) != crc {
+            return Err(HintDamage::Checksum);
+        }
+        if records.len() != count as usize {
+            return Err(HintDamage::Count {
+                footer: count,
+                found: records.len() as u32,
+            });
+        }
+        Ok(Hint { data_len, records })
     }
 }
 
+/// Parses the hint record at the start of `bytes`, with its size. `None` if it does not fit
+/// or fails its checksum.
+fn parse_record(bytes: &[u8]) -> Option<(IndexRecord, usize)> {
+    let raw_ksz = LittleEndian::read_u32(bytes.get(4..8)?);
+    let ksz = (raw_ksz & KEY_SIZE_MASK) as usize;
+    let fields = if raw_ksz & FLAG_EXPIRES != 0 { 4 } else { 3 };
+    let version_size = if raw_ksz & FLAG_VERSION != 0 { 8 } else { 0 };
+    let size = 8 + ksz + fields * 4 + version_size;
+    let record = bytes.get(..size)?;
+    if crc32fast::hash(&record[4..]) != LittleEndian::read_u32(record) {
+        return None;
+    }
+    let field = |i: usize| LittleEndian::read_u32(&record[8 + ksz + i * 4..]);
+    Some((
+        IndexRecord {
+            key: record[8..8 + ksz].to_vec(),
+            value_sz: field(0),
+            value_pos: field(1),
+            tstamp: field(2),
+            expire_at: if fields == 4 { field(3) } else { 0 },
+            version: (version_size != 0)
+                .then(|| LittleEndian::read_u64(&record[8 + ksz + fields * 4..])),
+            removed: raw_ksz & FLAG_REMOVED != 0,
+            blob: raw_ksz & FLAG_BLOB != 0,
+        },
+        size,
+    ))
+}
+
+/// The contents of a complete hint file.
+pub struct Hint {
+    /// how much of the data file the records describe, anything after was appended later
+    pub data_len: u32,
+    pub records: Vec<IndexRecord>,
+}
+
 pub struct IndexRecord {
     pub key: Key,
     pub value_sz: u32,
     pub value_pos: u32,
     pub tstamp: u32,
+    pub expire_at: u32,
+    /// set if the record is a copy made by a merge, see `BitCaskHandle::version`
+    pub version: Option<u64>,
+    pub removed: bool,
+    pub blob: bool,
 }
 
-pub struct HintFileIter {
-    file: HintFile,
-}
-
-impl Iterator for HintFileIter {
-    type Item = IndexRecord;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.file.next_record().ok().flatten()
+/// Writes the hint file of a data file no longer written to, one hint record per data
+/// record so loading the hint has the same effect as scanning the data file.
+pub(crate) fn write_hint(base_dir: &Path, file_id: u32) -> BitCaskResult<()> {
+    let path = base_dir.join(format_idx_file_name(file_id));
+    let tmp_path = path.with_extension("idx.tmp");
+    let mut hint_file = HintFile::create(tmp_path)?;
+    let mut data_len = 0;
+    for (offset, block) in DatFile::new(base_dir, file_id, true)?.iter() {
+        let value_pos = offset + block.value_offset() as u32;
+        let entry = KeyDirEntry {
+            file_id,
+            value_sz: block.value_sz,
+            value_pos,
+            tstamp: block.tstamp,
+            expire_at: block.expire_at,
+            blob: block.blob,
+        };
+        if block.is_removed() {
+            hint_file.put_removed(&block.key, entry)?;
+        } else {
+            let moved_version = block
+                .version
+                .filter(|version| *version != version_at(file_id, value_pos));
+            hint_file.put(&block.key, entry, moved_version)?;
+        }
+        data_len = offset + block.size() as u32;
     }
+    hint_file.finish(data_len)?;
+    hint_file.rename(&path)
 }
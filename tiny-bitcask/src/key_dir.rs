@@ -0,0 +1,589 @@
+use std::borrow::Cow;
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::mem::{replace, size_of};
+use std::ops::Bound;
+
+use crate::bitcask::{version_at, Key, KeyDirEntry, KeyRef};
+
+/// Most keys a leaf of the compact key dir holds, a full leaf is split in two.
+const LEAF_CAPACITY: usize = 64;
+
+/// How the key dir is kept in memory, see `Opts::key_dir`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDirKind {
+    /// A `BTreeMap` with one heap allocated key per entry: every key costs about 65 bytes on
+    /// top of its length.
+    #[default]
+    BTree,
+    /// Keys packed into sorted leaves of up to 64 entries, each storing the prefix its keys
+    /// share once. Costs about 30 bytes per key on top of the unshared part of it; opening is a
+    /// bit slower and reading keys back allocates them. See `benches/key_dir.rs`.
+    Compact,
+}
+
+/// The in-memory index from key to the position of its latest value.
+#[derive(Clone)]
+pub(crate) struct KeyDir {
+    entries: Entries,
+    // versions of the records a merge copied, by position: every other record has the
+    // version of its own position, see `BitCaskHandle::version`
+    moved: HashMap<(u32, u32), u64>,
+}
+
+#[derive(Clone)]
+enum Entries {
+    BTree(BTreeMap<Key, KeyDirEntry>),
+    Compact(CompactKeyDir),
+}
+
+impl KeyDir {
+    pub(crate) fn new(kind: KeyDirKind) -> Self {
+        let entries = match kind {
+            KeyDirKind::BTree => Entries::BTree(BTreeMap::new()),
+            KeyDirKind::Compact => Entries::Compact(CompactKeyDir::default()),
+        };
+        KeyDir {
+            entries,
+            moved: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &KeyRef) -> Option<KeyDirEntry> {
+        match &self.entries {
+            Entries::BTree(map) => map.get(key).copied(),
+            Entries::Compact(dir) => dir.get(key),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &KeyRef) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub(crate) fn insert(&mut self, key: Key, entry: KeyDirEntry) -> Option<KeyDirEntry> {
+        let old = match &mut self.entries {
+            Entries::BTree(map) => map.insert(key, entry),
+            Entries::Compact(dir) => dir.insert(key, entry),
+        };
+        self.forget_version(old)
+    }
+
+    pub(crate) fn remove(&mut self, key: &KeyRef) -> Option<KeyDirEntry> {
+        let old = match &mut self.entries {
+            Entries::BTree(map) => map.remove(key),
+            Entries::Compact(dir) => dir.remove(key),
+        };
+        self.forget_version(old)
+    }
+
+    /// Drops the version of a record no entry points at any more.
+    fn forget_version(&mut self, old: Option<KeyDirEntry>) -> Option<KeyDirEntry> {
+        if let Some(old) = &old {
+            if !self.moved.is_empty() {
+                self.moved.remove(&(old.file_id, old.value_pos));
+            }
+        }
+        old
+    }
+
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&KeyRef, &KeyDirEntry) -> bool) {
+        match &mut self.entries {
+            Entries::BTree(map) => map.retain(|key, entry| f(key, entry)),
+            Entries::Compact(dir) => dir.retain(f),
+        }
+    }
+
+    /// Version of the record `entry` points at.
+    pub(crate) fn version(&self, entry: &KeyDirEntry) -> u64 {
+        self.moved_version(entry)
+            .unwrap_or_else(|| version_at(entry.file_id, entry.value_pos))
+    }
+
+    /// Version of the record `entry` points at if a merge copied it there.
+    pub(crate) fn moved_version(&self, entry: &KeyDirEntry) -> Option<u64> {
+        self.moved.get(&(entry.file_id, entry.value_pos)).copied()
+    }
+
+    /// Records that the record `entry` points at has `version`, kept only if that is not the
+    /// version of its position.
+    pub(crate) fn set_version(&mut self, entry: &KeyDirEntry, version: u64) {
+        if version != version_at(entry.file_id, entry.value_pos) {
+            self.moved.insert((entry.file_id, entry.value_pos), version);
+        }
+    }
+
+    /// Forgets the versions of records in the data files up to `file_id`, which a merge has
+    /// just replaced, including those of records that expired.
+    pub(crate) fn forget_versions(&mut self, file_id: u32) {
+        self.moved.retain(|(id, _), _| *id > file_id);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.entries {
+            Entries::BTree(map) => map.len(),
+            Entries::Compact(dir) => dir.len,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every entry in key order.
+    pub(crate) fn iter(&self) -> KeyDirIter<'_> {
+        self.range_from(Bound::Unbounded)
+    }
+
+    /// The entries from `start` on, in key order.
+    pub(crate) fn range_from(&self, start: Bound<&KeyRef>) -> KeyDirIter<'_> {
+        match &self.entries {
+            Entries::BTree(map) => {
+                KeyDirIter::BTree(map.range::<KeyRef, _>((start, Bound::Unbounded)))
+            }
+            Entries::Compact(dir) => KeyDirIter::Compact(dir.range_from(start)),
+        }
+    }
+
+    /// Rough heap size of the key dir.
+    pub(crate) fn heap_bytes(&self) -> usize {
+        // a byte of control data per bucket
+        let moved = self.moved.capacity() * (size_of::<((u32, u32), u64)>() + 1);
+        moved
+            + match &self.entries {
+                // nodes are about two thirds full
+                Entries::BTree(map) => map
+                    .keys()
+                    .map(|key| {
+                        key.capacity() + (size_of::<Key>() + size_of::<KeyDirEntry>()) * 3 / 2
+                    })
+                    .sum(),
+                Entries::Compact(dir) => dir.heap_bytes(),
+            }
+    }
+}
+
+pub(crate) enum KeyDirIter<'a> {
+    BTree(btree_map::Range<'a, Key, KeyDirEntry>),
+    Compact(CompactIter<'a>),
+}
+
+impl<'a> Iterator for KeyDirIter<'a> {
+    type Item = (Cow<'a, KeyRef>, KeyDirEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            KeyDirIter::BTree(range) => range
+                .next()
+                .map(|(key, entry)| (Cow::Borrowed(key.as_slice()), *entry)),
+            KeyDirIter::Compact(iter) => iter.next(),
+        }
+    }
+}
+
+/// Sorted keys with a shared prefix stored once and the rest of each key back to back.
+#[derive(Clone, Default)]
+struct Leaf {
+    prefix: Vec<u8>,
+    suffixes: Vec<u8>,
+    // where the suffix of each key ends in `suffixes`
+    ends: Vec<u32>,
+    entries: Vec<KeyDirEntry>,
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+impl Leaf {
+    /// Packs sorted pairs tightly.
+    fn build(pairs: &[(Key, KeyDirEntry)]) -> Self {
+        let prefix_len = match (pairs.first(), pairs.last()) {
+            (Some((first, _)), Some((last, _))) => common_prefix_len(first, last),
+            _ => 0,
+        };
+        let suffix_bytes = pairs.iter().map(|(key, _)| key.len() - prefix_len).sum();
+        let mut leaf = Leaf {
+            prefix: pairs
+                .first()
+                .map_or(vec![], |(key, _)| key[..prefix_len].to_vec()),
+            suffixes: Vec::with_capacity(suffix_bytes),
+            ends: Vec::with_capacity(pairs.len()),
+            entries: Vec::with_capacity(pairs.len()),
+        };
+        for (key, entry) in pairs {
+            leaf.suffixes.extend_from_slice(&key[prefix_len..]);
+            leaf.ends.push(leaf.suffixes.len() as u32);
+            leaf.entries.push(*entry);
+        }
+        leaf
+    }
+
+    fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    fn start(&self, i: usize) -> usize {
+        if i == 0 {
+            0
+        } else {
+            self.ends[i - 1] as usize
+        }
+    }
+
+    fn suffix(&self, i: usize) -> &[u8] {
+        &self.suffixes[self.start(i)..self.ends[i] as usize]
+    }
+
+    fn key(&self, i: usize) -> Key {
+        let suffix = self.suffix(i);
+        let mut key = Vec::with_capacity(self.prefix.len() + suffix.len());
+        key.extend_from_slice(&self.prefix);
+        key.extend_from_slice(suffix);
+        key
+    }
+
+    fn pairs(&self) -> Vec<(Key, KeyDirEntry)> {
+        (0..self.len())
+            .map(|i| (self.key(i), self.entries[i]))
+            .collect()
+    }
+
+    /// Like `slice::binary_search`.
+    fn search(&self, key: &KeyRef) -> Result<usize, usize> {
+        let Some(rest) = key.strip_prefix(self.prefix.as_slice()) else {
+            // all keys of the leaf start with the prefix, so they are all on one side of key
+            return Err(if key < self.prefix.as_slice() {
+                0
+            } else {
+                self.len()
+            });
+        };
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.suffix(mid).cmp(rest) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    fn insert_at(&mut self, i: usize, key: &KeyRef, entry: KeyDirEntry) {
+        if !key.starts_with(&self.prefix) {
+            self.shorten_prefix(common_prefix_len(&self.prefix, key));
+        }
+        let suffix = &key[self.prefix.len()..];
+        let start = self.start(i);
+        self.suffixes.splice(start..start, suffix.iter().copied());
+        for end in &mut self.ends[i..] {
+            *end += suffix.len() as u32;
+        }
+        self.ends.insert(i, (start + suffix.len()) as u32);
+        self.entries.insert(i, entry);
+    }
+
+    fn remove_at(&mut self, i: usize) -> KeyDirEntry {
+        let (start, end) = (self.start(i), self.ends[i] as usize);
+        self.suffixes.drain(start..end);
+        self.ends.remove(i);
+        for later in &mut self.ends[i..] {
+            *later -= (end - start) as u32;
+        }
+        self.entries.remove(i)
+    }
+
+    /// Moves the end of the prefix back into every suffix.
+    fn shorten_prefix(&mut self, len: usize) {
+        let moved = self.prefix.split_off(len);
+        let mut suffixes = Vec::with_capacity(self.suffixes.len() + moved.len() * self.len());
+        let mut start = 0;
+        for end in &mut self.ends {
+            suffixes.extend_from_slice(&moved);
+            suffixes.extend_from_slice(&self.suffixes[start..*end as usize]);
+            start = *end as usize;
+            *end = suffixes.len() as u32;
+        }
+        self.suffixes = suffixes;
+    }
+
+    fn heap_bytes(&self) -> usize {
+        self.prefix.capacity()
+            + self.suffixes.capacity()
+            + self.ends.capacity() * size_of::<u32>()
+            + self.entries.capacity() * size_of::<KeyDirEntry>()
+    }
+}
+
+/// The `KeyDirKind::Compact` key dir: leaves indexed by their first key.
+#[derive(Clone, Default)]
+pub(crate) struct CompactKeyDir {
+    leaves: BTreeMap<Box<KeyRef>, Leaf>,
+    len: usize,
+}
+
+impl CompactKeyDir {
+    /// First key of the leaf `key` belongs in: the last one starting at or before it, or the
+    /// first one if `key` comes before every leaf.
+    fn leaf_key(&self, key: &KeyRef) -> Option<&KeyRef> {
+        self.leaves
+            .range::<KeyRef, _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .or_else(|| self.leaves.iter().next())
+            .map(|(first, _)| first.as_ref())
+    }
+
+    fn get(&self, key: &KeyRef) -> Option<KeyDirEntry> {
+        let leaf = &self.leaves[self.leaf_key(key)?];
+        leaf.search(key).ok().map(|i| leaf.entries[i])
+    }
+
+    /// Puts `leaf` under the key it now starts with, if that changed.
+    fn rekey(&mut self, first: &KeyRef) {
+        let leaf = self.leaves.remove(first).unwrap();
+        let new_first = leaf.key(0).into_boxed_slice();
+        self.leaves.insert(new_first, leaf);
+    }
+
+    fn insert(&mut self, key: Key, entry: KeyDirEntry) -> Option<KeyDirEntry> {
+        let Some(first) = self.leaf_key(&key).map(Box::<KeyRef>::from) else {
+            self.leaves
+                .insert(key.clone().into_boxed_slice(), Leaf::build(&[(key, entry)]));
+            self.len = 1;
+            return None;
+        };
+        let leaf = self.leaves.get_mut(&first).unwrap();
+        let i = match leaf.search(&key) {
+            Ok(i) => return Some(replace(&mut leaf.entries[i], entry)),
+            Err(i) => i,
+        };
+        self.len += 1;
+        if leaf.len() < LEAF_CAPACITY {
+            leaf.insert_at(i, &key, entry);
+            if i == 0 {
+                self.rekey(&first);
+            }
+        } else if i == leaf.len() {
+            // appending in key order, e.g. while loading a merged file: start a new leaf and
+            // leave this one full
+            self.leaves
+                .insert(key.clone().into_boxed_slice(), Leaf::build(&[(key, entry)]));
+        } else {
+            let mut pairs = leaf.pairs();
+            pairs.insert(i, (key, entry));
+            let right = pairs.split_off(pairs.len() / 2);
+            *leaf = Leaf::build(&pairs);
+            if i == 0 {
+                self.rekey(&first);
+            }
+            self.leaves
+                .insert(right[0].0.clone().into_boxed_slice(), Leaf::build(&right));
+        }
+        None
+    }
+
+    fn remove(&mut self, key: &KeyRef) -> Option<KeyDirEntry> {
+        let first = Box::<KeyRef>::from(self.leaf_key(key)?);
+        let leaf = self.leaves.get_mut(&first).unwrap();
+        let i = leaf.search(key).ok()?;
+        let entry = leaf.remove_at(i);
+        self.len -= 1;
+        if leaf.len() == 0 {
+            self.leaves.remove(&first);
+        } else if i == 0 {
+            self.rekey(&first);
+        }
+        Some(entry)
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&KeyRef, &KeyDirEntry) -> bool) {
+        let mut kept = vec![];
+        for leaf in self.leaves.values() {
+            for (key, entry) in leaf.pairs() {
+                if f(&key, &entry) {
+                    kept.push((key, entry));
+                }
+            }
+        }
+        self.leaves = kept
+            .chunks(LEAF_CAPACITY)
+            .map(|pairs| (pairs[0].0.clone().into_boxed_slice(), Leaf::build(pairs)))
+            .collect();
+        self.len = kept.len();
+    }
+
+    fn range_from(&self, start: Bound<&KeyRef>) -> CompactIter<'_> {
+        let (key, excluded) = match start {
+            Bound::Included(key) => (key, false),
+            Bound::Excluded(key) => (key, true),
+            Bound::Unbounded => {
+                return CompactIter {
+                    leaves: self.leaves.range::<KeyRef, _>(..),
+                    leaf: None,
+                    pos: 0,
+                }
+            }
+        };
+        let Some(first) = self.leaf_key(key) else {
+            return CompactIter {
+                leaves: self.leaves.range::<KeyRef, _>(..),
+                leaf: None,
+                pos: 0,
+            };
+        };
+        let leaf = &self.leaves[first];
+        let pos = match leaf.search(key) {
+            Ok(i) if excluded => i + 1,
+            Ok(i) | Err(i) => i,
+        };
+        CompactIter {
+            leaves: self
+                .leaves
+                .range::<KeyRef, _>((Bound::Excluded(first), Bound::Unbounded)),
+            leaf: Some(leaf),
+            pos,
+        }
+    }
+
+    fn heap_bytes(&self) -> usize {
+        self.leaves
+            .iter()
+            .map(|(first, leaf)| {
+                first.len() + size_of::<Box<KeyRef>>() + size_of::<Leaf>() + leaf.heap_bytes()
+            })
+            .sum()
+    }
+}
+
+pub(crate) struct CompactIter<'a> {
+    leaves: btree_map::Range<'a, Box<KeyRef>, Leaf>,
+    leaf: Option<&'a Leaf>,
+    pos: usize,
+}
+
+impl<'a> Iterator for CompactIter<'a> {
+    type Item = (Cow<'a, KeyRef>, KeyDirEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(leaf) = self.leaf {
+                if self.pos < leaf.len() {
+                    self.pos += 1;
+                    let i = self.pos - 1;
+                    return Some((Cow::Owned(leaf.key(i)), leaf.entries[i]));
+                }
+            }
+            self.leaf = Some(self.leaves.next()?.1);
+            self.pos = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: u32) -> KeyDirEntry {
+        KeyDirEntry {
+            file_id: n,
+            value_sz: n,
+            value_pos: n,
+            tstamp: n,
+            expire_at: 0,
+            blob: false,
+        }
+    }
+
+    #[test]
+    fn test_compact_matches_btree() {
+        let mut compact = KeyDir::new(KeyDirKind::Compact);
+        let mut model = BTreeMap::new();
+        // a small LCG, so the test is deterministic
+        let mut seed = 42u64;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) as u32
+        };
+        for n in 0..20_000 {
+            let r = next();
+            // keys sharing long prefixes, some of them prefixes of others
+            let key = match r % 3 {
+                0 => format!("user:{:05}", r % 3000),
+                1 => format!("user:{:05}:name", r % 3000),
+                _ => format!("{}", r % 500),
+            }
+            .into_bytes();
+            if next() % 4 == 0 {
+                assert_eq!(compact.remove(&key), model.remove(&key));
+            } else {
+                assert_eq!(
+                    compact.insert(key.clone(), entry(n)),
+                    model.insert(key, entry(n))
+                );
+            }
+        }
+        // sequential inserts after the last key
+        for n in 0..1000 {
+            let key = format!("zz{:06}", n).into_bytes();
+            compact.insert(key.clone(), entry(n));
+            model.insert(key, entry(n));
+        }
+
+        let pairs = |dir: &KeyDir, start| {
+            dir.range_from(start)
+                .map(|(key, entry)| (key.into_owned(), entry))
+                .collect::<Vec<_>>()
+        };
+        let btree = KeyDir {
+            entries: Entries::BTree(model),
+            moved: HashMap::new(),
+        };
+        assert_eq!(compact.len(), btree.len());
+        assert_eq!(
+            pairs(&compact, Bound::Unbounded),
+            pairs(&btree, Bound::Unbounded)
+        );
+        for probe in [
+            &b""[..],
+            b"1",
+            b"250",
+            b"user:01000",
+            b"user:01000:",
+            b"zz",
+            b"~",
+        ] {
+            assert_eq!(compact.get(probe), btree.get(probe));
+            for start in [Bound::Included(probe), Bound::Excluded(probe)] {
+                assert_eq!(pairs(&compact, start), pairs(&btree, start));
+            }
+        }
+        assert!(compact.heap_bytes() < btree.heap_bytes());
+
+        let mut btree = btree;
+        compact.retain(|key, _| key.starts_with(b"user"));
+        btree.retain(|key, _| key.starts_with(b"user"));
+        assert_eq!(
+            pairs(&compact, Bound::Unbounded),
+            pairs(&btree, Bound::Unbounded)
+        );
+    }
+
+    #[test]
+    fn test_moved_versions() {
+        let mut dir = KeyDir::new(KeyDirKind::BTree);
+        dir.set_version(&entry(1), 7);
+        dir.insert(b"k".to_vec(), entry(1));
+        assert_eq!(dir.version(&entry(1)), 7);
+        // the version of a record's own position is not kept
+        dir.set_version(&entry(2), version_at(2, 2));
+        assert!(dir.moved_version(&entry(2)).is_none());
+
+        // overwriting or removing the key drops the version
+        dir.insert(b"k".to_vec(), entry(2));
+        assert!(dir.moved_version(&entry(1)).is_none());
+        dir.set_version(&entry(3), 9);
+        dir.insert(b"k".to_vec(), entry(3));
+        dir.remove(b"k");
+        assert!(dir.moved.is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::bitcask::{BitCask, BitCaskHandle, BitCaskResult, Key, KeyDirEntry};
+use crate::block::{FLAG_BLOB, FLAG_VERSION, KEY_SIZE_MASK};
+use crate::key_dir::KeyDir;
+use crate::replication::LogPosition;
+use crate::utils::*;
+
+/// Name of the file holding the last key dir checkpoint of a store.
+pub const KEY_DIR_CHECKPOINT_FILE_NAME: &str = "keydir.ckpt";
+/// Entries of records a merge copied also carry their version since `BCK2`, checkpoints of the
+/// first format are not used.
+const CHECKPOINT_MAGIC: u32 = u32::from_le_bytes(*b"BCK2");
+
+/// A copy of the key dir as of a position in the log. Loading it and replaying what was
+/// written after that position gives the same key dir as loading every data file.
+///
+/// The lengths of the data files before the position are recorded too: if any of them
+/// changed, was removed or a file appeared among them, the checkpoint is stale. A merge also
+/// removes the checkpoint before replacing the files it rewrites.
+pub(crate) struct KeyDirCheckpoint {
+    pub position: LogPosition,
+    files: Vec<(u32, u64)>,
+    // with the version of the record if a merge copied it
+    entries: Vec<(Key, KeyDirEntry, Option<u64>)>,
+}
+
+fn checkpoint_path(dir: &Path) -> PathBuf {
+    dir.join(KEY_DIR_CHECKPOINT_FILE_NAME)
+}
+
+impl KeyDirCheckpoint {
+    /// Reads the checkpoint in `dir`. `Ok(None)` if there is none, `Err` with the reason if it
+    /// cannot be used for loading `dat_files`.
+    pub fn load(dir: &Path, dat_files: &[PathBuf]) -> BitCaskResult<Option<Result<Self, String>>> {
+        let bytes = match fs::read(checkpoint_path(dir)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut lens = HashMap::new();
+        for path in dat_files {
+            lens.insert(get_file_id_from_path(path)?, path.metadata()?.len());
+        }
+        Ok(Some(Self::parse(&bytes).and_then(|checkpoint| {
+            checkpoint.validate(&lens).map(|_| checkpoint)
+        })))
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 8 || LittleEndian::read_u32(bytes) != CHECKPOINT_MAGIC {
+            return Err("not a key dir checkpoint".to_string());
+        }
+        let body_len = bytes.len() - 4;
+        if crc32fast::hash(&bytes[..body_len]) != LittleEndian::read_u32(&bytes[body_len..]) {
+            return Err("checksum mismatch".to_string());
+        }
+        let mut reader = Reader {
+            bytes: &bytes[4..body_len],
+        };
+        let truncated = || "truncated".to_string();
+        let position = LogPosition::new(
+            reader.u32().ok_or_else(truncated)?,
+            reader.u32().ok_or_else(truncated)?,
+        );
+        let file_count = reader.u32().ok_or_else(truncated)?;
+        let mut files = Vec::with_capacity(file_count as usize);
+        for _ in 0..file_count {
+            let file_id = reader.u32().ok_or_else(truncated)?;
+            let len = reader.u64().ok_or_else(truncated)?;
+            files.push((file_id, len));
+        }
+        let entry_count = reader.u32().ok_or_else(truncated)?;
+        let mut entries = Vec::with_capacity(entry_count as usize);
+        for _ in 0..entry_count {
+            // the key size carries the blob and version flags, like in data and hint records
+            let raw_ksz = reader.u32().ok_or_else(truncated)?;
+            let ksz = raw_ksz & KEY_SIZE_MASK;
+            let key = reader.take(ksz as usize).ok_or_else(truncated)?.to_vec();
+            let mut field = || reader.u32().ok_or_else(truncated);
+            let (file_id, value_sz, value_pos, tstamp, expire_at) =
+                (field()?, field()?, field()?, field()?, field()?);
+            let entry = KeyDirEntry {
+                file_id,
+                value_sz,
+                value_pos,
+                tstamp,
+                expire_at,
+                blob: raw_ksz & FLAG_BLOB != 0,
+            };
+            let version = if raw_ksz & FLAG_VERSION != 0 {
+                Some(reader.u64().ok_or_else(truncated)?)
+            } else {
+                None
+            };
+            entries.push((key, entry, version));
+        }
+        if !reader.bytes.is_empty() {
+            return Err("trailing bytes".to_string());
+        }
+        Ok(Self {
+            position,
+            files,
+            entries,
+        })
+    }
+
+    /// Checks the checkpoint against the data files now in the directory, `lens` by id.
+    fn validate(&self, lens: &HashMap<u32, u64>) -> Result<(), String> {
+        let position = self.position;
+        let mut recorded = HashMap::new();
+        for &(file_id, len) in &self.files {
+            if lens.get(&file_id) != Some(&len) {
+                return Err(format!("data file {} changed since", file_id));
+            }
+            recorded.insert(file_id, len);
+        }
+        if let Some(file_id) = lens
+            .keys()
+            .find(|&&file_id| file_id < position.file_id && !recorded.contains_key(&file_id))
+        {
+            return Err(format!("data file {} appeared since", file_id));
+        }
+        if position.offset > 0 {
+            if lens.get(&position.file_id).unwrap_or(&0) < &(position.offset as u64) {
+                return Err(format!("data file {} is shorter", position.file_id));
+            }
+            recorded.insert(position.file_id, position.offset as u64);
+        }
+        let points_into_log = |entry: &KeyDirEntry| {
+            recorded
+                .get(&entry.file_id)
+                .is_some_and(|&len| entry.value_pos as u64 + entry.value_sz as u64 <= len)
+        };
+        if let Some((key, _, _)) = self
+            .entries
+            .iter()
+            .find(|(_, entry, _)| !points_into_log(entry))
+        {
+            return Err(format!(
+                "entry of {:?} points past the log",
+                String::from_utf8_lossy(key)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fills `key_dir`, leaving out the keys expired since.
+    pub fn restore(self, key_dir: &mut KeyDir) {
+        let now = now_ts();
+        for (key, entry, version) in self.entries {
+            if !entry.is_expired(now) {
+                if let Some(version) = version {
+                    key_dir.set_version(&entry, version);
+                }
+                key_dir.insert(key, entry);
+            }
+        }
+    }
+}
+
+/// Removes the checkpoint in `dir`, if any.
+pub(crate) fn remove_checkpoint(dir: &Path) -> BitCaskResult<()> {
+    match fs::remove_file(checkpoint_path(dir)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(LittleEndian::read_u32)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(LittleEndian::read_u64)
+    }
+}
+
+impl BitCaskHandle {
+    /// Writes a checkpoint of the key dir, for `open` to load instead of replaying the whole
+    /// log when `Opts::key_dir_checkpoint` is set. The active file is synced first, the
+    /// position covered is returned.
+    pub fn write_key_dir_checkpoint(&self) -> BitCaskResult<LogPosition> {
+        self.sync()?;
+        let position = self.log_end();
+        let mut files = vec![];
+        for path in get_dat_files(&self.base_dir)? {
+            let file_id = get_file_id_from_path(&path)?;
+            if file_id < position.file_id {
+                files.push((file_id, path.metadata()?.len()));
+            }
+        }
+
+        let mut bytes = vec![];
+        bytes.write_u32::<LittleEndian>(CHECKPOINT_MAGIC)?;
+        bytes.write_u32::<LittleEndian>(position.file_id)?;
+        bytes.write_u32::<LittleEndian>(position.offset)?;
+        bytes.write_u32::<LittleEndian>(files.len() as u32)?;
+        for (file_id, len) in files {
+            bytes.write_u32::<LittleEndian>(file_id)?;
+            bytes.write_u64::<LittleEndian>(len)?;
+        }
+        bytes.write_u32::<LittleEndian>(self.key_dir.len() as u32)?;
+        for (key, entry) in self.key_dir.iter() {
+            let moved_version = self.key_dir.moved_version(&entry);
+            let mut flags = if entry.blob { FLAG_BLOB } else { 0 };
+            if moved_version.is_some() {
+                flags |= FLAG_VERSION;
+            }
+            bytes.write_u32::<LittleEndian>(key.len() as u32 | flags)?;
+            bytes.write_all(&key)?;
+            for field in [
+                entry.file_id,
+                entry.value_sz,
+                entry.value_pos,
+                entry.tstamp,
+                entry.expire_at,
+            ] {
+                bytes.write_u32::<LittleEndian>(field)?;
+            }
+            if let Some(version) = moved_version {
+                bytes.write_u64::<LittleEndian>(version)?;
+            }
+        }
+        let crc = crc32fast::hash(&bytes);
+        bytes.write_u32::<LittleEndian>(crc)?;
+
+        let tmp_path = self
+            .base_dir
+            .join(format!("{}.tmp", KEY_DIR_CHECKPOINT_FILE_NAME));
+        fs::write(&tmp_path, &bytes)?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, checkpoint_path(&self.base_dir))?;
+        sync_dir(&self.base_dir)?;
+        Ok(position)
+    }
+}
@@ -1,14 +1,53 @@
+#[cfg(feature = "async")]
+mod async_handle;
+mod backup;
 mod bitcask;
+mod blob;
 mod block;
+mod changes;
 mod dat_file;
+mod dump;
 mod errors;
 mod file_ext;
 mod index_file;
+mod key_dir;
+mod key_dir_file;
+mod load;
+mod replication;
+mod snapshot;
+mod stats;
 mod utils;
+mod value_reader;
+mod verify;
+
+#[cfg(feature = "async")]
+pub use async_handle::AsyncBitCaskHandle;
+pub use backup::{restore_backup, BackupFile, BackupManifest};
+pub use bitcask::{
+    BitCask, BitCaskHandle, BitCaskResult, Key, KeyDirEntry, KeyRef, Opts, ScanIter, Value,
+    ValueRef,
+};
+pub use block::MAX_KEY_SIZE;
+pub use changes::{ChangeEvent, Subscription};
+pub use dump::{dump_file, DumpIter, DumpRecord, RecordKind};
+pub use errors::BitCaskError;
+pub use key_dir::KeyDirKind;
+pub use key_dir_file::KEY_DIR_CHECKPOINT_FILE_NAME;
+pub use load::LoadProgress;
+pub use replication::{LogPosition, LogRecord, POSITION_FILE_NAME};
+pub use snapshot::Snapshot;
+pub use stats::{Histogram, MergeStats, Stats};
+pub use value_reader::ValueReader;
+pub use verify::{verify, Issue, Repair, VerifyReport, QUARANTINE_DIR_NAME};
 
 #[cfg(test)]
 mod tests {
+    use crate::backup::restore_backup;
     use crate::bitcask::{BitCask, BitCaskHandle, Opts};
+    use crate::errors::BitCaskError;
+    use crate::index_file::HintDamage;
+    use crate::key_dir::KeyDirKind;
+    use crate::replication::LogPosition;
 
     const TEST_DIR: &str = "/tmp/bitcask_test";
 
@@ -32,7 +71,7 @@ mod tests {
         }
         for i in 0..10 {
             let key = format!("hello#{i}");
-            let value = db.get(key.as_bytes());
+            let value = db.get(key.as_bytes()).unwrap();
             assert_eq!(value.unwrap(), format!("world#{i}").as_bytes().to_vec());
         }
     }
@@ -45,7 +84,7 @@ mod tests {
             db.delete("foo".as_bytes()).unwrap();
         }
         let db = BitCaskHandle::open(TEST_DIR.into(), opts).unwrap();
-        let hello = db.get("foo".as_bytes());
+        let hello = db.get("foo".as_bytes()).unwrap();
         assert!(hello.is_none())
     }
 
@@ -64,7 +103,7 @@ mod tests {
     fn test_get() {
         let opts = Opts::new(128);
         let db = BitCaskHandle::open(TEST_DIR.into(), opts).unwrap();
-        let res = db.get(b"hello#1").unwrap();
+        let res = db.get(b"hello#1").unwrap().unwrap();
         println!("res: {:?}", String::from_utf8(res.clone()).unwrap());
         assert_eq!(res, b"world#1".to_vec());
     }
@@ -84,4 +123,1037 @@ mod tests {
         let res = db.merge();
         assert!(res.is_ok());
     }
+
+    fn fresh_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::path::PathBuf::from("/tmp").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = fresh_dir("bitcask_scan_test");
+        let mut db = BitCaskHandle::open(dir, Opts::new(1024)).unwrap();
+        for key in ["a#1", "b#1", "b#2", "b#3", "c#1"] {
+            db.put(key.as_bytes(), key.to_uppercase().as_bytes())
+                .unwrap();
+        }
+        db.delete(b"b#2").unwrap();
+        let res: Vec<_> = db.scan(b"b#").map(|kv| kv.unwrap()).collect();
+        assert_eq!(
+            res,
+            vec![
+                (b"b#1".to_vec(), b"B#1".to_vec()),
+                (b"b#3".to_vec(), b"B#3".to_vec())
+            ]
+        );
+        assert_eq!(db.scan(b"").count(), 4);
+        assert_eq!(db.scan(b"d").count(), 0);
+
+        let keys: Vec<_> = db.scan(b"").after(b"b#1").keys().collect();
+        assert_eq!(keys, vec![b"b#3".to_vec(), b"c#1".to_vec()]);
+    }
+
+    #[test]
+    fn test_compact_key_dir() {
+        let dir = fresh_dir("bitcask_compact_key_dir_test");
+        let opts = Opts::new(512).key_dir(KeyDirKind::Compact);
+        let mut db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        for i in (0..300).rev() {
+            db.put(
+                format!("user:{i:04}").as_bytes(),
+                format!("v{i}").as_bytes(),
+            )
+            .unwrap();
+        }
+        for i in (0..300).step_by(3) {
+            db.delete(format!("user:{i:04}").as_bytes()).unwrap();
+        }
+        db.put(b"user:0001", b"updated").unwrap();
+        let snapshot = db.snapshot();
+        db.merge().unwrap();
+        db.put(b"user:0002", b"after snapshot").unwrap();
+
+        assert_eq!(db.list_keys().len(), 200);
+        assert_eq!(db.get(b"user:0001").unwrap().unwrap(), b"updated".to_vec());
+        assert!(db.get(b"user:0003").unwrap().is_none());
+        let keys: Vec<_> = db.scan(b"user:01").after(b"user:0150").keys().collect();
+        assert_eq!(keys.len(), 33);
+        assert_eq!(keys[0], b"user:0151".to_vec());
+        assert_eq!(snapshot.get(b"user:0002").unwrap().unwrap(), b"v2".to_vec());
+        assert_eq!(snapshot.len(), 200);
+        drop(snapshot);
+
+        let stats = db.stats().unwrap();
+        db.close().unwrap();
+        drop(db);
+        let db = BitCaskHandle::open(dir.clone(), Opts::new(512)).unwrap();
+        let btree_stats = db.stats().unwrap();
+        assert_eq!(btree_stats.keys, 200);
+        assert!(stats.key_dir_bytes < btree_stats.key_dir_bytes);
+        let db = BitCaskHandle::open(dir, opts).unwrap();
+        assert_eq!(db.get(b"user:0299").unwrap().unwrap(), b"v299".to_vec());
+        assert_eq!(db.scan(b"").count(), 200);
+    }
+
+    #[test]
+    fn test_hint_files() {
+        let dir = fresh_dir("bitcask_hint_files_test");
+        let hints = |dir: &std::path::Path| crate::utils::get_idx_files(dir).unwrap();
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(128)).unwrap();
+        for i in 0..20 {
+            db.put(format!("key#{i:02}").as_bytes(), b"value").unwrap();
+        }
+        db.delete(b"key#00").unwrap();
+        db.put_with_ttl(b"key#01", b"short", 100).unwrap();
+        // every sealed file has a hint, the active one gets its hint on close
+        let sealed = crate::utils::get_dat_files(&dir).unwrap().len() - 1;
+        assert_eq!(hints(&dir).len(), sealed);
+        db.close().unwrap();
+        assert_eq!(hints(&dir).len(), sealed + 1);
+        // a write after close is not in the hint, it is scanned on open
+        db.put(b"late", b"write").unwrap();
+        drop(db);
+
+        let db = BitCaskHandle::open(dir.clone(), Opts::new(128)).unwrap();
+        assert_eq!(db.list_keys().len(), 20);
+        assert!(db.get(b"key#00").unwrap().is_none());
+        assert!(db.ttl(b"key#01").unwrap().is_some());
+        assert_eq!(db.get(b"late").unwrap().unwrap(), b"write".to_vec());
+        drop(db);
+
+        // a hint cut short is ignored, its data file scanned instead
+        let load = |path: &std::path::Path| crate::index_file::HintFile::load(path).unwrap();
+        for path in hints(&dir) {
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+            assert_eq!(load(&path).err(), Some(HintDamage::NoFooter));
+        }
+        let db = BitCaskHandle::open(dir.clone(), Opts::new(128)).unwrap();
+        assert_eq!(db.list_keys().len(), 20);
+        assert!(db.get(b"key#00").unwrap().is_none());
+        assert_eq!(db.get(b"key#19").unwrap().unwrap(), b"value".to_vec());
+        drop(db);
+        // a handle that only reads leaves it alone, the first write rebuilds it
+        assert!(hints(&dir).iter().all(|path| load(path).is_err()));
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(128)).unwrap();
+        db.put(b"late", b"again").unwrap();
+        drop(db);
+        assert!(hints(&dir).iter().all(|path| load(path).is_ok()));
+
+        // so is one with a damaged record
+        let path = hints(&dir)[0].clone();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(load(&path).err(), Some(HintDamage::BadRecord { offset: 0 }));
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(128)).unwrap();
+        assert_eq!(db.list_keys().len(), 20);
+        assert_eq!(db.get(b"key#02").unwrap().unwrap(), b"value".to_vec());
+        db.put(b"late", b"once more").unwrap();
+        assert!(load(&path).is_ok());
+    }
+
+    #[test]
+    fn test_parallel_load() {
+        let dir = fresh_dir("bitcask_parallel_load_test");
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(256)).unwrap();
+        for round in 0..5 {
+            for i in 0..40 {
+                let value = format!("value#{i}@{round}");
+                db.put(format!("key#{i:02}").as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+            db.delete(format!("key#{round:02}").as_bytes()).unwrap();
+        }
+        // written again after its delete, in a later file
+        db.put(b"key#00", b"back").unwrap();
+        db.close().unwrap();
+        let expected: Vec<_> = db.scan(b"").map(Result::unwrap).collect();
+        drop(db);
+
+        for threads in [1, 3, 16] {
+            let mut reports = vec![];
+            let opts = Opts::new(256).load_threads(threads);
+            let db = BitCaskHandle::open_with_progress(dir.clone(), opts, |progress| {
+                reports.push(*progress)
+            })
+            .unwrap();
+            let loaded: Vec<_> = db.scan(b"").map(Result::unwrap).collect();
+            assert_eq!(loaded, expected, "{threads} threads");
+            let files = crate::utils::get_dat_files(&dir).unwrap().len();
+            assert_eq!(reports.len(), files);
+            let last = reports.last().unwrap();
+            assert_eq!(last.files_loaded, last.files_total);
+            assert_eq!(last.bytes_loaded, last.bytes_total);
+        }
+    }
+
+    #[test]
+    fn test_key_dir_checkpoint() {
+        let dir = fresh_dir("bitcask_key_dir_checkpoint_test");
+        let checkpoint = dir.join(crate::KEY_DIR_CHECKPOINT_FILE_NAME);
+        let opts = Opts::new(128).key_dir_checkpoint(std::time::Duration::ZERO);
+        let mut db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        for i in 0..20 {
+            db.put(format!("key#{i:02}").as_bytes(), b"value").unwrap();
+        }
+        // written when a data file was sealed
+        assert!(checkpoint.exists());
+        db.delete(b"key#00").unwrap();
+        db.close().unwrap();
+        // after the checkpoint, replayed on open
+        db.put(b"key#01", b"late").unwrap();
+        db.delete(b"key#02").unwrap();
+        let expected: Vec<_> = db.scan(b"").map(Result::unwrap).collect();
+        drop(db);
+
+        let scan = |db: &BitCaskHandle| db.scan(b"").map(Result::unwrap).collect::<Vec<_>>();
+        let mut db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        assert_eq!(scan(&db), expected);
+        db.merge().unwrap();
+        assert!(!checkpoint.exists());
+        db.close().unwrap();
+        drop(db);
+
+        // the sealed files are not read at all when the checkpoint is used
+        let first = crate::utils::get_dat_files(&dir).unwrap()[0].clone();
+        let bytes = std::fs::read(&first).unwrap();
+        std::fs::write(&first, vec![0; bytes.len()]).unwrap();
+        let db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        assert_eq!(db.list_keys().len(), expected.len());
+        drop(db);
+
+        // a data file changed since makes it stale, the log is loaded in full instead, and
+        // without its hint all the zeroed file holds is lost
+        std::fs::write(&first, vec![0; bytes.len() + 1]).unwrap();
+        std::fs::remove_file(crate::utils::get_hint_from_dat_path(&first)).unwrap();
+        let db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        assert!(db.list_keys().len() < expected.len());
+    }
+
+    #[test]
+    fn test_blob_files() {
+        let dir = fresh_dir("bitcask_blob_files_test");
+        let blobs = |dir: &std::path::Path| crate::utils::get_blob_files(dir).unwrap().len();
+        let big = |n: u8| vec![n; 1000];
+        let opts = Opts::new(256).blob_threshold(100);
+        let mut db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        db.put(b"a", &big(1)).unwrap();
+        db.put(b"b", &big(2)).unwrap();
+        db.put(b"c", b"small").unwrap();
+        assert_eq!(blobs(&dir), 2);
+        assert_eq!(db.get(b"a").unwrap().unwrap(), big(1));
+        db.put(b"a", &big(3)).unwrap();
+        db.delete(b"b").unwrap();
+        for i in 0..20 {
+            db.put(format!("key#{i:02}").as_bytes(), b"value").unwrap();
+        }
+        // followers get the value, not the reference to it
+        let (records, _) = db.read_log(LogPosition::new(0, 0), usize::MAX).unwrap();
+        assert_eq!(records[0].value, Some(big(1)));
+
+        // the blobs of the records dropped are deleted, the live one is kept as it is
+        db.merge().unwrap();
+        assert_eq!(blobs(&dir), 1);
+        assert_eq!(db.get(b"a").unwrap().unwrap(), big(3));
+        db.close().unwrap();
+        drop(db);
+        let db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        assert_eq!(db.get(b"a").unwrap().unwrap(), big(3));
+        assert!(db.get(b"b").unwrap().is_none());
+        assert_eq!(db.get(b"c").unwrap().unwrap(), b"small".to_vec());
+        drop(db);
+
+        // a value too large for a data file gets a blob without a threshold too
+        let dir = fresh_dir("bitcask_blob_files_test_limit");
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(256)).unwrap();
+        db.put(b"huge", &big(4)).unwrap();
+        db.put(b"fits", &[5; 100]).unwrap();
+        assert_eq!(blobs(&dir), 1);
+        assert_eq!(db.get(b"huge").unwrap().unwrap(), big(4));
+        for path in crate::utils::get_dat_files(&dir).unwrap() {
+            assert!(path.metadata().unwrap().len() <= 256);
+        }
+        drop(db);
+
+        // without a limit values stay inline, in a single data file
+        let dir = fresh_dir("bitcask_blob_files_test_no_limit");
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::default()).unwrap();
+        for i in 0..10 {
+            db.put(format!("key#{i}").as_bytes(), &big(i)).unwrap();
+        }
+        assert_eq!(blobs(&dir), 0);
+        assert_eq!(crate::utils::get_dat_files(&dir).unwrap().len(), 1);
+        assert_eq!(db.get(b"key#9").unwrap().unwrap(), big(9));
+    }
+
+    #[test]
+    fn test_streaming() {
+        use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+        let dir = fresh_dir("bitcask_streaming_test");
+        let big: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(1024).blob_threshold(100)).unwrap();
+        db.put_from_reader(b"big", &big[..], big.len() as u64)
+            .unwrap();
+        db.put_from_reader(b"small", &b"small value"[..], 5)
+            .unwrap();
+        assert_eq!(crate::utils::get_blob_files(&dir).unwrap().len(), 1);
+        assert_eq!(db.get(b"big").unwrap().unwrap(), big);
+        assert_eq!(db.get(b"small").unwrap().unwrap(), b"small".to_vec());
+        assert!(db.get_reader(b"missing").unwrap().is_none());
+
+        let mut reader = db.get_reader(b"big").unwrap().unwrap();
+        assert_eq!(reader.len(), 5000);
+        let mut value = vec![];
+        reader.read_to_end(&mut value).unwrap();
+        assert_eq!(value, big);
+        reader.seek(SeekFrom::Start(4000)).unwrap();
+        let mut part = [0; 10];
+        reader.read_exact(&mut part).unwrap();
+        assert_eq!(part, big[4000..4010]);
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), 4999);
+        value.clear();
+        reader.read_to_end(&mut value).unwrap();
+        assert_eq!(value, big[4999..]);
+
+        // an inline value is read from its data file, up to its end only
+        let mut reader = db.get_reader(b"small").unwrap().unwrap();
+        reader.seek(SeekFrom::Current(2)).unwrap();
+        let mut value = String::new();
+        reader.read_to_string(&mut value).unwrap();
+        assert_eq!(value, "all");
+
+        // a short reader fails the put, leaving the old value and no blob behind
+        let err = db.put_from_reader(b"big", &big[..10], 5000).unwrap_err();
+        assert!(matches!(err, BitCaskError::IoError));
+        let err = db.put_from_reader(b"small", &b"abc"[..], 4).unwrap_err();
+        assert!(matches!(err, BitCaskError::IoError));
+        assert_eq!(crate::utils::get_blob_files(&dir).unwrap().len(), 1);
+        assert_eq!(db.get(b"big").unwrap().unwrap(), big);
+        assert_eq!(db.get(b"small").unwrap().unwrap(), b"small".to_vec());
+
+        // subscribers read a streamed value themselves, a merge keeps it until they have
+        let blobs = |dir: &std::path::Path| crate::utils::get_blob_files(dir).unwrap().len();
+        let mut live = db.subscribe(None).unwrap();
+        db.put_from_reader(b"streamed", &big[..], big.len() as u64)
+            .unwrap();
+        db.delete(b"streamed").unwrap();
+        for i in 0..20 {
+            db.put(format!("filler#{i:02}").as_bytes(), &[0; 100])
+                .unwrap();
+        }
+        db.merge().unwrap();
+        assert_eq!(blobs(&dir), 2);
+        let event = live.recv().unwrap().unwrap();
+        assert_eq!(event.record.key, b"streamed".to_vec());
+        assert_eq!(event.record.value, Some(big.clone()));
+        drop(live);
+        db.merge().unwrap();
+        assert_eq!(blobs(&dir), 1);
+
+        // a corrupted blob fails the read reaching its end
+        let path = &crate::utils::get_blob_files(&dir).unwrap()[0];
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[100] ^= 0xff;
+        std::fs::write(path, bytes).unwrap();
+        let err = db
+            .get_reader(b"big")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_streaming_inline() {
+        use std::io::{Error, Read};
+
+        // fails if asked for more than a buffer's worth at once
+        struct Chunked(usize);
+        impl Read for Chunked {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if buf.len() > 64 * 1024 {
+                    return Err(Error::other("read into a buffer of the whole value"));
+                }
+                let n = buf.len().min(self.0);
+                buf[..n].iter_mut().for_each(|b| *b = 7);
+                self.0 -= n;
+                Ok(n)
+            }
+        }
+
+        let dir = fresh_dir("bitcask_streaming_inline_test");
+        let len = 4 << 20;
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::default()).unwrap();
+        db.put(b"before", b"1").unwrap();
+        db.put_from_reader(b"big", Chunked(len), len as u64)
+            .unwrap();
+        db.put(b"after", b"2").unwrap();
+        assert!(crate::utils::get_blob_files(&dir).unwrap().is_empty());
+        assert_eq!(db.get(b"big").unwrap().unwrap(), vec![7; len]);
+
+        // a short reader leaves nothing behind in the data file
+        db.put_from_reader(b"big", Chunked(10), len as u64)
+            .unwrap_err();
+        db.put(b"last", b"3").unwrap();
+        drop(db);
+        let db = BitCaskHandle::open(dir.clone(), Opts::default()).unwrap();
+        assert_eq!(db.get(b"big").unwrap().unwrap(), vec![7; len]);
+        assert_eq!(db.get(b"before").unwrap().unwrap(), b"1".to_vec());
+        assert_eq!(db.get(b"after").unwrap().unwrap(), b"2".to_vec());
+        assert_eq!(db.get(b"last").unwrap().unwrap(), b"3".to_vec());
+    }
+
+    #[test]
+    fn test_key_too_large() {
+        use crate::block::MAX_KEY_SIZE;
+        use crate::replication::LogRecord;
+
+        let dir = fresh_dir("bitcask_key_too_large_test");
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(1024)).unwrap();
+        let too_long = vec![b'k'; MAX_KEY_SIZE + 1];
+        let rejected = |result| matches!(result, Err(BitCaskError::KeyTooLarge));
+        assert!(rejected(db.put(&too_long, b"v")));
+        assert!(rejected(db.put_with_ttl(&too_long, b"v", 100)));
+        assert!(rejected(db.put_from_reader(&too_long, &b"v"[..], 1)));
+        assert!(rejected(db.put_from_reader(
+            &too_long,
+            &[0; 2000][..],
+            2000
+        )));
+        let record = LogRecord {
+            tstamp: crate::utils::now_ts(),
+            expire_at: 0,
+            version: 0,
+            key: too_long,
+            value: Some(b"v".to_vec()),
+        };
+        assert!(rejected(db.apply(&record)));
+        assert!(db.list_keys().is_empty());
+        assert!(crate::utils::get_blob_files(&dir).unwrap().is_empty());
+
+        // the longest key allowed does not disturb the records around it
+        let longest = vec![b'k'; MAX_KEY_SIZE];
+        db.put(b"before", b"v").unwrap();
+        db.put(&longest, b"v").unwrap();
+        db.put(b"after", b"v").unwrap();
+        db.merge().unwrap();
+        db.close().unwrap();
+        drop(db);
+        let db = BitCaskHandle::open(dir, Opts::new(1024)).unwrap();
+        assert_eq!(db.list_keys().len(), 3);
+        assert_eq!(db.get(&longest).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(db.get(b"after").unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let dir = fresh_dir("bitcask_multi_get_test");
+        let mut db = BitCaskHandle::open(dir, Opts::new(128).blob_threshold(100)).unwrap();
+        for i in 0..20 {
+            db.put(format!("key#{i:02}").as_bytes(), format!("v{i}").as_bytes())
+                .unwrap();
+        }
+        // spread over the data files, not in the order they were written
+        db.put(b"key#03", b"updated").unwrap();
+        db.put(b"big", &[7; 1000]).unwrap();
+        db.delete(b"key#05").unwrap();
+        assert!(crate::utils::get_dat_files(db.dir()).unwrap().len() > 3);
+
+        let keys: Vec<&[u8]> = vec![
+            b"key#19", b"big", b"missing", b"key#03", b"key#00", b"key#05", b"key#19",
+        ];
+        let values = db.multi_get(&keys).unwrap();
+        assert_eq!(
+            values,
+            keys.iter()
+                .map(|key| db.get(key).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(values[0], Some(b"v19".to_vec()));
+        assert_eq!(values[1], Some(vec![7; 1000]));
+        assert_eq!(values[2], None);
+        assert_eq!(values[3], Some(b"updated".to_vec()));
+        assert_eq!(values[5], None);
+        assert!(db.multi_get(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_missing_file() {
+        let dir = fresh_dir("bitcask_get_missing_file_test");
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(128)).unwrap();
+        for i in 0..10 {
+            db.put(format!("key#{i}").as_bytes(), b"value").unwrap();
+        }
+        db.delete(b"key#9").unwrap();
+        assert_eq!(db.get(b"key#0").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"key#9").unwrap(), None);
+        assert_eq!(db.get(b"missing").unwrap(), None);
+        assert!(db.contains_key(b"key#0"));
+        assert!(!db.contains_key(b"key#9"));
+        assert!(!db.contains_key(b"missing"));
+
+        // a key whose data file is gone is still there, but reading it fails
+        let first = &crate::utils::get_dat_files(&dir).unwrap()[0];
+        std::fs::remove_file(first).unwrap();
+        assert!(matches!(db.get(b"key#0"), Err(BitCaskError::IoError)));
+        assert!(db.contains_key(b"key#0"));
+        assert_eq!(db.get(b"missing").unwrap(), None);
+        assert_eq!(db.get(b"key#8").unwrap(), Some(b"value".to_vec()));
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.gets, 6);
+        assert_eq!(stats.get_hits, 2);
+    }
+
+    #[test]
+    fn test_version() {
+        let dir = fresh_dir("bitcask_version_test");
+        let mut db = BitCaskHandle::open(dir, Opts::new(1024)).unwrap();
+        assert_eq!(db.version(b"k"), None);
+        db.put(b"k", b"v1").unwrap();
+        let v1 = db.version(b"k").unwrap();
+        db.put(b"other", b"v").unwrap();
+        assert_eq!(db.version(b"k"), Some(v1));
+        db.put(b"k", b"v1").unwrap();
+        assert_ne!(db.version(b"k"), Some(v1));
+        db.delete(b"k").unwrap();
+        assert_eq!(db.version(b"k"), None);
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let dir = fresh_dir("bitcask_conditional_writes_test");
+        let mut db = BitCaskHandle::open(dir, Opts::new(1024)).unwrap();
+        assert!(db.put_if_absent(b"k", b"v1").unwrap());
+        assert!(!db.put_if_absent(b"k", b"v2").unwrap());
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v1".to_vec()));
+
+        let v1 = db.version(b"k").unwrap();
+        assert!(db.put_if_version(b"k", v1, b"v2").unwrap());
+        // the version moved on, a writer still holding v1 loses
+        assert!(!db.put_if_version(b"k", v1, b"v3").unwrap());
+        assert!(!db.delete_if_version(b"k", v1).unwrap());
+        assert!(!db.put_if_version(b"missing", v1, b"v").unwrap());
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v2".to_vec()));
+
+        let v2 = db.version(b"k").unwrap();
+        assert!(db.delete_if_version(b"k", v2).unwrap());
+        assert!(!db.delete_if_version(b"k", v2).unwrap());
+        assert!(db.put_if_absent(b"k", b"v4").unwrap());
+
+        // an expired key is absent
+        db.put_with_ttl(b"gone", b"v", 1).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2100));
+        assert!(db.put_if_absent(b"gone", b"back").unwrap());
+        assert_eq!(db.get(b"gone").unwrap(), Some(b"back".to_vec()));
+        assert_eq!(db.stats().unwrap().puts, 8);
+    }
+
+    #[test]
+    fn test_version_across_merge() {
+        let dir = fresh_dir("bitcask_version_merge_test");
+        let opts = Opts::new(256)
+            .blob_threshold(100)
+            .key_dir_checkpoint(std::time::Duration::from_secs(3600));
+        let mut db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        db.put(b"k", b"v1").unwrap();
+        db.put(b"blob", &[7; 200]).unwrap();
+        db.put_with_ttl(b"ttl", b"v", 1000).unwrap();
+        for i in 0..20 {
+            db.put(format!("filler#{}", i % 5).as_bytes(), &[0; 50])
+                .unwrap();
+        }
+        let keys: [&[u8]; 3] = [b"k", b"blob", b"ttl"];
+        let versions: Vec<_> = keys.iter().map(|key| db.version(key)).collect();
+
+        // the merge moves the records, a compare and swap started before it still goes through
+        db.merge().unwrap();
+        assert_eq!(db.stats().unwrap().data_files, 2);
+        let after: Vec<_> = keys.iter().map(|key| db.version(key)).collect();
+        assert_eq!(after, versions);
+        assert!(db
+            .put_if_version(b"k", versions[0].unwrap(), b"v2")
+            .unwrap());
+        let v2 = db.version(b"k").unwrap();
+        assert!(versions.iter().all(|version| *version != Some(v2)));
+
+        // merging the merged file again, then loading it from its hint, a scan of it and a
+        // checkpoint of the key dir
+        for i in 0..20 {
+            db.put(format!("filler#{}", i % 5).as_bytes(), &[0; 50])
+                .unwrap();
+        }
+        db.merge().unwrap();
+        db.close().unwrap();
+        drop(db);
+        let expected = [Some(v2), versions[1], versions[2]];
+        let check = |db: &BitCaskHandle| {
+            let found: Vec<_> = keys.iter().map(|key| db.version(key)).collect();
+            assert_eq!(found, expected);
+        };
+        check(&BitCaskHandle::open(dir.clone(), opts).unwrap());
+        std::fs::remove_file(dir.join(crate::key_dir_file::KEY_DIR_CHECKPOINT_FILE_NAME)).unwrap();
+        check(&BitCaskHandle::open(dir.clone(), opts).unwrap());
+        for path in crate::utils::get_idx_files(&dir).unwrap() {
+            std::fs::remove_file(path).unwrap();
+        }
+        let mut db = BitCaskHandle::open(dir.clone(), opts).unwrap();
+        check(&db);
+        assert!(db.delete_if_version(b"blob", versions[1].unwrap()).unwrap());
+        drop(db);
+        assert!(crate::verify::verify(&dir, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let dir = fresh_dir("bitcask_ttl_test");
+        {
+            let mut db = BitCaskHandle::open(dir.clone(), Opts::new(1024)).unwrap();
+            db.put(b"forever", b"v").unwrap();
+            db.put_with_ttl(b"short", b"v", 1).unwrap();
+            db.put_with_ttl(b"long", b"v", 1000).unwrap();
+            assert_eq!(db.ttl(b"forever"), Some(None));
+            assert!(db.ttl(b"long").unwrap().unwrap() > 990);
+            assert_eq!(db.ttl(b"missing"), None);
+
+            assert!(db.expire(b"forever", Some(1000)).unwrap());
+            assert!(db.expire(b"long", None).unwrap());
+            assert!(!db.expire(b"missing", Some(10)).unwrap());
+            db.close().unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        let mut db = BitCaskHandle::open(dir, Opts::new(1024)).unwrap();
+        assert_eq!(db.get(b"short").unwrap(), None);
+        assert_eq!(db.list_keys(), vec![b"forever".to_vec(), b"long".to_vec()]);
+        assert!(db.ttl(b"forever").unwrap().is_some());
+        assert_eq!(db.ttl(b"long"), Some(None));
+        assert!(db.expire(b"long", Some(0)).unwrap());
+        assert_eq!(db.get(b"long").unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let dir = fresh_dir("bitcask_snapshot_test");
+        let mut db = BitCaskHandle::open(dir, Opts::new(1024)).unwrap();
+        db.put(b"k1", b"v1").unwrap();
+        db.put(b"k2", b"v2").unwrap();
+
+        let snapshot = db.snapshot();
+        db.put(b"k1", b"v1-new").unwrap();
+        db.put(b"k3", b"v3").unwrap();
+        db.delete(b"k2").unwrap();
+
+        assert_eq!(snapshot.get(b"k1").unwrap().unwrap(), b"v1".to_vec());
+        assert_eq!(snapshot.get(b"k2").unwrap().unwrap(), b"v2".to_vec());
+        assert!(snapshot.get(b"k3").unwrap().is_none());
+        assert_eq!(snapshot.list_keys(), vec![b"k1".to_vec(), b"k2".to_vec()]);
+
+        assert_eq!(db.get(b"k1").unwrap().unwrap(), b"v1-new".to_vec());
+        assert!(db.get(b"k2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_pins_files_against_merge() {
+        let dir = fresh_dir("bitcask_snapshot_merge_test");
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(64)).unwrap();
+        for i in 0..10 {
+            db.put(format!("key#{i}").as_bytes(), format!("old#{i}").as_bytes())
+                .unwrap();
+        }
+        let snapshot = db.snapshot();
+        for i in 0..10 {
+            db.put(format!("key#{i}").as_bytes(), format!("new#{i}").as_bytes())
+                .unwrap();
+        }
+        let files_before = std::fs::read_dir(&dir).unwrap().count();
+        db.merge().unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), files_before);
+
+        let values: Vec<_> = snapshot.scan(b"key#").map(|kv| kv.unwrap().1).collect();
+        let expected: Vec<_> = (0..10).map(|i| format!("old#{i}").into_bytes()).collect();
+        assert_eq!(values, expected);
+
+        drop(snapshot);
+        db.merge().unwrap();
+        assert!(std::fs::read_dir(&dir).unwrap().count() < files_before);
+        for i in 0..10 {
+            let value = db.get(format!("key#{i}").as_bytes()).unwrap().unwrap();
+            assert_eq!(value, format!("new#{i}").into_bytes());
+        }
+        drop(db);
+
+        let db = BitCaskHandle::open(dir, Opts::new(64)).unwrap();
+        for i in 0..10 {
+            let value = db.get(format!("key#{i}").as_bytes()).unwrap().unwrap();
+            assert_eq!(value, format!("new#{i}").into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = fresh_dir("bitcask_checkpoint_src");
+        let dest = fresh_dir("bitcask_checkpoint_dest");
+        let mut db = BitCaskHandle::open(dir, Opts::new(64)).unwrap();
+        for i in 0..10 {
+            db.put(
+                format!("key#{i}").as_bytes(),
+                format!("value#{i}").as_bytes(),
+            )
+            .unwrap();
+        }
+        db.delete(b"key#0").unwrap();
+        db.checkpoint(&dest).unwrap();
+        assert!(db.checkpoint(&dest).is_err());
+
+        db.put(b"key#1", b"changed").unwrap();
+        db.put(b"key#10", b"value#10").unwrap();
+        db.merge().unwrap();
+
+        let backup = BitCaskHandle::open(dest, Opts::new(64)).unwrap();
+        assert_eq!(backup.list_keys().len(), 9);
+        assert!(backup.get(b"key#0").unwrap().is_none());
+        assert_eq!(backup.get(b"key#1").unwrap().unwrap(), b"value#1".to_vec());
+        assert!(backup.get(b"key#10").unwrap().is_none());
+        assert_eq!(db.get(b"key#1").unwrap().unwrap(), b"changed".to_vec());
+
+        // a checkpoint after a merge keeps the positions the merge removed unknown
+        let after_merge = fresh_dir("bitcask_checkpoint_dest_merged");
+        db.checkpoint(&after_merge).unwrap();
+        let backup = BitCaskHandle::open(after_merge, Opts::new(64)).unwrap();
+        assert!(matches!(
+            backup.read_log(LogPosition::new(0, 0), 1024),
+            Err(BitCaskError::UnknownLogPosition)
+        ));
+    }
+
+    #[test]
+    fn test_incremental_backup() {
+        let dir = fresh_dir("bitcask_backup_src");
+        let full = fresh_dir("bitcask_backup_full");
+        let incr1 = fresh_dir("bitcask_backup_incr1");
+        let incr2 = fresh_dir("bitcask_backup_incr2");
+        let restored = fresh_dir("bitcask_backup_restored");
+        let mut db = BitCaskHandle::open(dir, Opts::new(64)).unwrap();
+        for i in 0..10 {
+            db.put(
+                format!("key#{i}").as_bytes(),
+                format!("value#{i}").as_bytes(),
+            )
+            .unwrap();
+        }
+        let base = db.backup(&full, None).unwrap();
+        assert!(base.files.iter().all(|file| file.captured));
+
+        for i in 0..5 {
+            db.put(format!("key#{i}").as_bytes(), b"updated").unwrap();
+        }
+        let first = db.backup(&incr1, Some(&full)).unwrap();
+        assert_eq!(first.parent, Some(base.id));
+        assert!(first.deleted.is_empty());
+        let inherited = first.files.iter().filter(|file| !file.captured).count();
+        assert_eq!(inherited, base.files.len());
+
+        db.delete(b"key#9").unwrap();
+        db.merge().unwrap();
+        let second = db.backup(&incr2, Some(&incr1)).unwrap();
+        assert_eq!(second.parent, Some(first.id));
+        assert!(!second.deleted.is_empty());
+
+        assert!(restore_backup(std::slice::from_ref(&incr1), &restored).is_err());
+        assert!(restore_backup(&[full.clone(), incr2.clone()], &restored).is_err());
+        restore_backup(&[full, incr1, incr2.clone()], &restored).unwrap();
+
+        // backups and restored files are copies: writing one in place leaves the others alone
+        let name = &second.files.iter().find(|file| file.captured).unwrap().name;
+        let original = std::fs::read(incr2.join(name)).unwrap();
+        assert_eq!(std::fs::read(db.base_dir.join(name)).unwrap(), original);
+        std::fs::write(db.base_dir.join(name), b"damaged").unwrap();
+        assert_eq!(std::fs::read(incr2.join(name)).unwrap(), original);
+        std::fs::write(incr2.join(name), b"damaged").unwrap();
+        assert_eq!(std::fs::read(restored.join(name)).unwrap(), original);
+
+        let db = BitCaskHandle::open(restored, Opts::new(64)).unwrap();
+        assert_eq!(db.list_keys().len(), 9);
+        assert!(matches!(
+            db.read_log(LogPosition::new(0, 0), 1024),
+            Err(BitCaskError::UnknownLogPosition)
+        ));
+        for i in 0..9 {
+            let expected = if i < 5 {
+                "updated".to_string()
+            } else {
+                format!("value#{i}")
+            };
+            assert_eq!(
+                db.get(format!("key#{i}").as_bytes()).unwrap().unwrap(),
+                expected.into_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_log_shipping() {
+        let dir = fresh_dir("bitcask_log_leader_test");
+        let follower_dir = fresh_dir("bitcask_log_follower_test");
+        let mut leader = BitCaskHandle::open(dir.clone(), Opts::new(64)).unwrap();
+        let mut follower = BitCaskHandle::open(follower_dir, Opts::new(64)).unwrap();
+        follower.set_read_only(true);
+        assert!(matches!(
+            follower.put(b"k", b"v"),
+            Err(BitCaskError::ReadOnly)
+        ));
+
+        let mut ship = |leader: &BitCaskHandle, from: LogPosition| {
+            let mut pos = from;
+            loop {
+                // small reads, so a position at the end of a sealed file is crossed too
+                let (records, next) = leader.read_log(pos, 1).unwrap();
+                for record in &records {
+                    follower.apply(record).unwrap();
+                }
+                if next == pos {
+                    break pos;
+                }
+                pos = next;
+            }
+        };
+        for i in 0..10 {
+            leader
+                .put(
+                    format!("key#{i}").as_bytes(),
+                    format!("value#{i}").as_bytes(),
+                )
+                .unwrap();
+        }
+        leader.put_with_ttl(b"ttl", b"v", 100).unwrap();
+        leader.delete(b"key#3").unwrap();
+        let pos = ship(&leader, LogPosition::new(0, 0));
+        assert_eq!(pos, leader.log_end());
+
+        leader.put(b"key#3", b"again").unwrap();
+        let pos = ship(&leader, pos);
+        assert_eq!(pos, leader.log_end());
+        assert_eq!(follower.list_keys(), leader.list_keys());
+        assert_eq!(follower.get(b"key#3").unwrap().unwrap(), b"again".to_vec());
+        assert!(follower.ttl(b"ttl").unwrap().is_some());
+        // a refused delete leaves the key in place
+        assert!(matches!(
+            follower.delete(b"key#3"),
+            Err(BitCaskError::ReadOnly)
+        ));
+        assert!(follower.contains_key(b"key#3"));
+        assert!(matches!(
+            leader.read_log(LogPosition::new(pos.file_id, pos.offset + 1), 1024),
+            Err(BitCaskError::UnknownLogPosition)
+        ));
+
+        // a merge rewrites the sealed files, positions in them are gone, also after a restart
+        leader.merge().unwrap();
+        assert!(matches!(
+            leader.read_log(LogPosition::new(0, 0), 1024),
+            Err(BitCaskError::UnknownLogPosition)
+        ));
+        assert!(leader.read_log(pos, 1024).unwrap().0.is_empty());
+        drop(leader);
+        let leader = BitCaskHandle::open(dir, Opts::new(64)).unwrap();
+        assert!(matches!(
+            leader.read_log(LogPosition::new(0, 0), 1024),
+            Err(BitCaskError::UnknownLogPosition)
+        ));
+        let records: Vec<_> = leader.snapshot().records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 11);
+        assert!(records.iter().all(|record| record.value.is_some()));
+    }
+
+    #[test]
+    fn test_change_stream() {
+        let dir = fresh_dir("bitcask_changes_test");
+        let mut db = BitCaskHandle::open(dir.clone(), Opts::new(64)).unwrap();
+        let mut live = db.subscribe(None).unwrap();
+        for i in 0..5 {
+            db.put(format!("key#{i}").as_bytes(), b"v").unwrap();
+        }
+        db.delete(b"key#1").unwrap();
+        db.delete(b"nope").unwrap();
+
+        let mut events = vec![];
+        while let Some(event) = live.try_recv().unwrap() {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 6);
+        assert!(events.windows(2).all(|w| w[0].seq < w[1].seq));
+        assert_eq!(events[0].record.key, b"key#0".to_vec());
+        assert_eq!(events[0].record.value, Some(b"v".to_vec()));
+        assert_eq!(events[5].record.key, b"key#1".to_vec());
+        assert_eq!(events[5].record.value, None);
+
+        // a consumer resuming after a restart gets the same events from the data files
+        drop(live);
+        drop(db);
+        let mut db = BitCaskHandle::open(dir, Opts::new(64)).unwrap();
+        let mut replay = db.subscribe(Some(0)).unwrap();
+        let mut resumed = db.subscribe(Some(events[2].seq)).unwrap();
+        db.put(b"key#9", b"new").unwrap();
+        for expected in &events {
+            assert_eq!(replay.recv().unwrap().as_ref(), Some(expected));
+        }
+        for expected in &events[3..] {
+            assert_eq!(resumed.recv().unwrap().as_ref(), Some(expected));
+        }
+        let last = replay.recv().unwrap().unwrap();
+        assert_eq!(last.record.key, b"key#9".to_vec());
+        assert!(last.seq > events[5].seq);
+        assert_eq!(resumed.recv().unwrap(), Some(last.clone()));
+        assert!(replay.try_recv().unwrap().is_none());
+
+        // replaying subscriptions keep their files from being merged
+        let mut pending = db.subscribe(Some(0)).unwrap();
+        db.merge().unwrap();
+        assert_eq!(pending.recv().unwrap().as_ref(), Some(&events[0]));
+        drop(pending);
+        db.merge().unwrap();
+        assert!(matches!(
+            db.subscribe(Some(0)),
+            Err(BitCaskError::UnknownLogPosition)
+        ));
+        db.subscribe(Some(last.seq)).unwrap();
+
+        drop(db);
+        assert_eq!(replay.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = fresh_dir("bitcask_stats_test");
+        let mut db = BitCaskHandle::open(dir, Opts::new(64)).unwrap();
+        let empty = db.stats().unwrap();
+        assert_eq!((empty.keys, empty.data_files, empty.data_bytes), (0, 0, 0));
+        assert_eq!(empty.dead_ratio(), 0.0);
+        assert!(empty.last_merge.is_none());
+
+        for i in 0..10 {
+            db.put(format!("key#{i}").as_bytes(), b"value").unwrap();
+        }
+        for i in 0..5 {
+            db.put(format!("key#{i}").as_bytes(), b"new").unwrap();
+        }
+        db.delete(b"key#0").unwrap();
+        db.get(b"key#1").unwrap().unwrap();
+        assert!(db.get(b"nope").unwrap().is_none());
+        assert_eq!(db.scan(b"key#9").count(), 1);
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.keys, 9);
+        assert!(stats.key_dir_bytes > 9 * 5);
+        assert!(stats.data_files > 1);
+        assert_eq!(stats.bytes_written, stats.data_bytes);
+        assert!(stats.live_bytes < stats.data_bytes);
+        assert!(stats.dead_ratio() > 0.0);
+        assert_eq!((stats.puts, stats.deletes), (15, 1));
+        assert_eq!((stats.gets, stats.get_hits), (2, 1));
+        assert_eq!(stats.bytes_read, 3 + 5);
+        assert_eq!(stats.put_latency.count, 15);
+        assert!(stats.get_latency.quantile(0.5).is_some());
+
+        db.merge().unwrap();
+        let stats = db.stats().unwrap();
+        let merge = stats.last_merge.as_ref().unwrap();
+        assert!(merge.error.is_none());
+        assert!(merge.reclaimed_bytes > 0);
+        let text = stats.to_prometheus();
+        assert!(text.contains("bitcask_keys 9\n"));
+        assert!(text.contains("bitcask_puts_total 15\n"));
+        assert!(text.contains("bitcask_op_duration_seconds_count{op=\"get\"} 2\n"));
+        assert!(text.contains("bitcask_last_merge_success 1\n"));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_async_handle() {
+        use crate::async_handle::AsyncBitCaskHandle;
+
+        let dir = fresh_dir("bitcask_async_test");
+        let db = AsyncBitCaskHandle::open(dir.clone(), Opts::new(256))
+            .await
+            .unwrap();
+        // writers syncing concurrently share fsyncs
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let key = format!("key#{i:02}");
+                    db.put(key.as_bytes(), b"value").await.unwrap();
+                    db.sync().await.unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(db.get(b"key#03").await.unwrap(), Some(b"value".to_vec()));
+        assert!(db.delete(b"key#03").await.unwrap());
+        assert!(!db.delete(b"key#03").await.unwrap());
+        assert_eq!(db.get(b"key#03").await.unwrap(), None);
+        let pairs = db.scan(b"key#0").await.unwrap();
+        assert_eq!(pairs.len(), 9);
+        assert_eq!(pairs[0], (b"key#00".to_vec(), b"value".to_vec()));
+        db.merge().await.unwrap();
+        let keys = db.with(|db| Ok(db.list_keys())).await.unwrap();
+        assert_eq!(keys.len(), 15);
+        db.sync().await.unwrap();
+        drop(db);
+
+        let db = BitCaskHandle::open(dir, Opts::new(256)).unwrap();
+        assert_eq!(db.list_keys().len(), 15);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_async_compare_and_swap() {
+        use crate::async_handle::AsyncBitCaskHandle;
+
+        let dir = fresh_dir("bitcask_async_cas_test");
+        let db = AsyncBitCaskHandle::open(dir, Opts::new(256)).await.unwrap();
+        assert!(db.put_if_absent(b"counter", b"0").await.unwrap());
+        // workers incrementing the counter concurrently, retrying when another got in first
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        loop {
+                            let (version, value) = db
+                                .with(|db| Ok((db.version(b"counter"), db.get(b"counter")?)))
+                                .await
+                                .unwrap();
+                            let n: u32 =
+                                String::from_utf8(value.unwrap()).unwrap().parse().unwrap();
+                            let next = (n + 1).to_string();
+                            if db
+                                .put_if_version(b"counter", version.unwrap(), next.as_bytes())
+                                .await
+                                .unwrap()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(db.get(b"counter").await.unwrap(), Some(b"80".to_vec()));
+        let version = db.with(|db| Ok(db.version(b"counter"))).await.unwrap();
+        assert!(!db.delete_if_version(b"counter", 0).await.unwrap());
+        assert!(db
+            .delete_if_version(b"counter", version.unwrap())
+            .await
+            .unwrap());
+    }
 }
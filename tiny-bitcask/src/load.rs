@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use tracing::{debug, debug_span, warn, Span};
+
+use crate::bitcask::{BitCaskResult, Key, KeyDirEntry};
+use crate::dat_file::DatFile;
+use crate::index_file::{HintDamage, HintFile};
+use crate::key_dir::KeyDir;
+use crate::utils::*;
+
+/// How far opening a store has got, reported after every data file loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub files_loaded: usize,
+    pub files_total: usize,
+    pub bytes_loaded: u64,
+    pub bytes_total: u64,
+}
+
+/// What one data file says about the keys written to it: the last entry of each key, `None`
+/// if the file last deleted it or wrote it already expired.
+struct PartialKeyDir {
+    entries: HashMap<Key, Option<KeyDirEntry>>,
+    // the versions of the entries pointing at records a merge copied
+    moved: HashMap<Key, u64>,
+    bytes: u64,
+    // the file has no usable hint
+    unhinted: Option<u32>,
+}
+
+impl PartialKeyDir {
+    fn apply(self, key_dir: &mut KeyDir) {
+        for (key, entry) in self.entries {
+            match entry {
+                Some(entry) => {
+                    if let Some(version) = self.moved.get(&key) {
+                        key_dir.set_version(&entry, *version);
+                    }
+                    key_dir.insert(key, entry)
+                }
+                None => key_dir.remove(&key),
+            };
+        }
+    }
+}
+
+/// Threads to load with when `Opts` leaves it at 0.
+fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Loads `dat_files`, sorted by id, into `key_dir`. The files are parsed on up to `threads`
+/// threads, each into a partial key dir, and these are applied in id order, so a record later
+/// in the log always supersedes an earlier one whichever thread read it.
+pub(crate) fn load_files(
+    base_dir: &Path,
+    dat_files: &[PathBuf],
+    threads: usize,
+    key_dir: &mut KeyDir,
+    mut progress: impl FnMut(&LoadProgress),
+) -> BitCaskResult<Vec<u32>> {
+    let mut unhinted = vec![];
+    if dat_files.is_empty() {
+        return Ok(unhinted);
+    }
+    let threads = match threads {
+        0 => default_threads(),
+        n => n,
+    }
+    .min(dat_files.len());
+    let mut done = LoadProgress {
+        files_loaded: 0,
+        files_total: dat_files.len(),
+        bytes_loaded: 0,
+        bytes_total: dat_files
+            .iter()
+            .filter_map(|path| path.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum(),
+    };
+    let now = now_ts();
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let parent = Span::current();
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..threads {
+            let (tx, next, stop, parent) = (tx.clone(), &next, &stop, &parent);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = dat_files.get(i) else {
+                        break;
+                    };
+                    let partial = load_file(base_dir, path, now, parent);
+                    if tx.send((i, partial)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        // files finished ahead of one still being read wait here
+        let mut pending = BTreeMap::new();
+        for (i, partial) in rx {
+            let partial = match partial {
+                Ok(partial) => partial,
+                Err(err) => {
+                    stop.store(true, Ordering::Relaxed);
+                    return Err(err);
+                }
+            };
+            pending.insert(i, partial);
+            while let Some(partial) = pending.remove(&done.files_loaded) {
+                done.files_loaded += 1;
+                done.bytes_loaded += partial.bytes;
+                unhinted.extend(partial.unhinted);
+                partial.apply(key_dir);
+                progress(&done);
+            }
+        }
+        Ok(unhinted)
+    })
+}
+
+/// Reads one data file, from its hint where there is a usable one.
+fn load_file(
+    base_dir: &Path,
+    path: &Path,
+    now: u32,
+    parent: &Span,
+) -> BitCaskResult<PartialKeyDir> {
+    let dat_file = DatFile::from_path(path, true)?;
+    let file_id = dat_file.id;
+    let len = path.metadata()?.len();
+    let index_path = base_dir.join(format_idx_file_name(file_id));
+    let hint = if index_path.exists() {
+        Some(HintFile::load(&index_path)?.and_then(|hint| {
+            if hint.data_len as u64 > len {
+                Err(HintDamage::Stale {
+                    data_len: hint.data_len,
+                    file_len: len,
+                })
+            } else {
+                Ok(hint)
+            }
+        }))
+    } else {
+        None
+    };
+    let hinted = matches!(hint, Some(Ok(_)));
+    let _span = debug_span!(parent: parent, "load_file", file_id, hinted).entered();
+    let mut entries = HashMap::new();
+    let mut moved = HashMap::new();
+    let mut records = 0;
+    // where the data file has to be scanned from, after the part the hint covers
+    let mut scan_from = 0;
+    match hint {
+        Some(Ok(hint)) => {
+            for record in hint.records {
+                records += 1;
+                let entry = KeyDirEntry {
+                    file_id,
+                    value_sz: record.value_sz,
+                    value_pos: record.value_pos,
+                    tstamp: record.tstamp,
+                    expire_at: record.expire_at,
+                    blob: record.blob,
+                };
+                let live = !record.removed && !entry.is_expired(now);
+                match record.version.filter(|_| live) {
+                    Some(version) => moved.insert(record.key.clone(), version),
+                    None => moved.remove(&record.key),
+                };
+                entries.insert(record.key, live.then_some(entry));
+            }
+            scan_from = hint.data_len;
+        }
+        Some(Err(damage)) => {
+            warn!(path = %index_path.display(), %damage, "rebuilding damaged hint file")
+        }
+        None => {}
+    }
+    records += scan(dat_file, scan_from, len, now, &mut entries, &mut moved);
+    debug!(records, "loaded data file");
+    Ok(PartialKeyDir {
+        entries,
+        moved,
+        bytes: len,
+        unhinted: (!hinted).then_some(file_id),
+    })
+}
+
+/// Applies the records of the data file at `path` from `offset` on, the part of it written
+/// after a key dir checkpoint.
+pub(crate) fn replay_from(path: &Path, offset: u32, key_dir: &mut KeyDir) -> BitCaskResult<()> {
+    let dat_file = DatFile::from_path(path, true)?;
+    let len = path.metadata()?.len();
+    let mut entries = HashMap::new();
+    let mut moved = HashMap::new();
+    let records = scan(dat_file, offset, len, now_ts(), &mut entries, &mut moved);
+    debug!(
+        file_id = get_file_id_from_path(path)?,
+        offset, records, "replayed data file"
+    );
+    PartialKeyDir {
+        entries,
+        moved,
+        bytes: len,
+        unhinted: None,
+    }
+    .apply(key_dir);
+    Ok(())
+}
+
+/// Reads the records of `dat_file`, `len` bytes long, from `from` on into `entries` and
+/// `moved`, returns how many there were.
+fn scan(
+    dat_file: DatFile,
+    from: u32,
+    len: u64,
+    now: u32,
+    entries: &mut HashMap<Key, Option<KeyDirEntry>>,
+    moved: &mut HashMap<Key, u64>,
+) -> usize {
+    let file_id = dat_file.id;
+    let mut records = 0;
+    let mut end = from as u64;
+    for (offset, block) in dat_file.iter_from(from) {
+        records += 1;
+        end = offset as u64 + block.size() as u64;
+        // an expired record still hides the older versions of its key
+        if block.is_removed() || block.is_expired(now) {
+            entries.insert(block.key, None);
+            continue;
+        }
+        let value_pos = offset + block.value_offset() as u32;
+        let entry = KeyDirEntry {
+            file_id,
+            value_sz: block.value_sz,
+            value_pos,
+            tstamp: block.tstamp,
+            expire_at: block.expire_at,
+            blob: block.blob,
+        };
+        match block.version {
+            Some(version) => moved.insert(block.key.clone(), version),
+            None => moved.remove(&block.key),
+        };
+        entries.insert(block.key, Some(entry));
+    }
+    if end < len {
+        warn!(
+            offset = end,
+            bytes = len - end,
+            "ignoring unreadable tail of data file"
+        );
+    }
+    records
+}
@@ -0,0 +1,264 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::bitcask::{
+    read_entry_value, version_at, BitCaskHandle, BitCaskResult, Key, Value, REMOVE_TOMBSTONE,
+};
+use crate::blob::{read_blob, BlobRef};
+use crate::block::Block;
+use crate::errors::BitCaskError;
+use crate::file_ext::ReadExt;
+use crate::snapshot::Snapshot;
+use crate::utils::{block_crc, format_dat_file_name, now_ts, sync_dir};
+
+/// Name of the file in a follower's directory holding how far the leader's log is applied.
+pub const POSITION_FILE_NAME: &str = "replica.pos";
+pub(crate) const MERGE_FLOOR_FILE_NAME: &str = "merge.floor";
+
+/// A position in the log of a store: everything before `offset` in data file `file_id`, and
+/// everything in the data files before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogPosition {
+    pub file_id: u32,
+    pub offset: u32,
+}
+
+impl LogPosition {
+    pub fn new(file_id: u32, offset: u32) -> Self {
+        Self { file_id, offset }
+    }
+
+    /// The position as one number, increasing along the log like the position itself.
+    pub fn seq(&self) -> u64 {
+        ((self.file_id as u64) << 32) | self.offset as u64
+    }
+
+    pub fn from_seq(seq: u64) -> Self {
+        Self::new((seq >> 32) as u32, seq as u32)
+    }
+
+    /// Loads the position saved in `dir`, `None` if there is none.
+    pub fn load(dir: &Path) -> BitCaskResult<Option<Self>> {
+        let text = match fs::read_to_string(dir.join(POSITION_FILE_NAME)) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let (file_id, offset) = text
+            .trim()
+            .split_once(' ')
+            .ok_or(BitCaskError::ParseError)?;
+        Ok(Some(Self::new(file_id.parse()?, offset.parse()?)))
+    }
+
+    /// Saves the position in `dir`, replacing the previous one atomically.
+    pub fn save(&self, dir: &Path) -> BitCaskResult<()> {
+        let tmp_path = dir.join(format!("{}.tmp", POSITION_FILE_NAME));
+        fs::write(&tmp_path, format!("{} {}\n", self.file_id, self.offset))?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, dir.join(POSITION_FILE_NAME))?;
+        sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Removes the position saved in `dir`, if any.
+    pub fn clear(dir: &Path) -> BitCaskResult<()> {
+        match fs::remove_file(dir.join(POSITION_FILE_NAME)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A write as read from the log, to be applied to a follower.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub tstamp: u32,
+    // 0 if the key never expires
+    pub expire_at: u32,
+    /// The version `BitCaskHandle::version` reports for the key after this write, kept by the
+    /// follower so compare and swap values survive a promotion
+    pub version: u64,
+    pub key: Key,
+    /// `None` for a delete
+    pub value: Option<Value>,
+}
+
+impl LogRecord {
+    /// The record of `block`, read at `offset` in data file `file_id`.
+    fn from_block(block: Block, file_id: u32, offset: u32) -> Self {
+        let version = block
+            .version
+            .unwrap_or_else(|| version_at(file_id, offset + block.value_offset() as u32));
+        let value = (!block.is_removed()).then_some(block.value);
+        Self {
+            tstamp: block.tstamp,
+            expire_at: block.expire_at,
+            version,
+            key: block.key,
+            value,
+        }
+    }
+}
+
+pub(crate) fn read_merge_floor(dir: &Path) -> BitCaskResult<Option<u32>> {
+    match fs::read_to_string(dir.join(MERGE_FLOOR_FILE_NAME)) {
+        Ok(text) => Ok(Some(text.trim().parse()?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub(crate) fn write_merge_floor(dir: &Path, file_id: u32) -> BitCaskResult<()> {
+    let tmp_path = dir.join(format!("{}.tmp", MERGE_FLOOR_FILE_NAME));
+    fs::write(&tmp_path, format!("{}\n", file_id))?;
+    fs::File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, dir.join(MERGE_FLOOR_FILE_NAME))?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+/// Reads the records between `from` and `end`, stopping once about `max_bytes` are read. Each
+/// record comes with the position following it, the position reached is returned as well.
+pub(crate) fn read_log_range(
+    base_dir: &Path,
+    from: LogPosition,
+    end: LogPosition,
+    max_bytes: usize,
+) -> BitCaskResult<(Vec<(LogRecord, LogPosition)>, LogPosition)> {
+    let mut records = vec![];
+    let mut pos = from;
+    let mut read = 0;
+    while pos < end && read < max_bytes {
+        let path = base_dir.join(format_dat_file_name(pos.file_id));
+        let mut file = match fs::File::open(&path) {
+            Ok(file) => file,
+            // ids left unused by a rotation
+            Err(err) if err.kind() == ErrorKind::NotFound && pos.offset == 0 => {
+                pos = LogPosition::new(pos.file_id + 1, 0);
+                continue;
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(BitCaskError::UnknownLogPosition)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let len = if pos.file_id == end.file_id {
+            end.offset
+        } else {
+            file.metadata()?.len() as u32
+        };
+        if pos.offset > len {
+            return Err(BitCaskError::UnknownLogPosition);
+        }
+        while pos.offset < len && read < max_bytes {
+            let block = match file.read_block_at(pos.offset as u64) {
+                Ok(block) if block_crc(&block) == block.crc => block,
+                _ if pos == from => return Err(BitCaskError::UnknownLogPosition),
+                // a torn tail of a sealed file, skipped like on open
+                _ => {
+                    pos.offset = len;
+                    break;
+                }
+            };
+            let offset = pos.offset;
+            pos.offset += block.size() as u32;
+            read += block.size();
+            let blob = block
+                .blob
+                .then(|| BlobRef::decode(&block.value))
+                .transpose()?;
+            let mut record = LogRecord::from_block(block, pos.file_id, offset);
+            if let Some(blob) = blob {
+                read += blob.len as usize;
+                record.value = Some(read_blob(base_dir, &blob)?);
+            }
+            records.push((record, pos));
+        }
+        if pos.offset >= len && pos.file_id < end.file_id {
+            pos = LogPosition::new(pos.file_id + 1, 0);
+        }
+    }
+    Ok((records, pos))
+}
+
+impl BitCaskHandle {
+    /// The position right after the last write.
+    pub fn log_end(&self) -> LogPosition {
+        match &self.active_data_file {
+            Some(dat_file) => LogPosition::new(dat_file.id, dat_file.get_offset()),
+            None => LogPosition::new(self.next_file_id, 0),
+        }
+    }
+
+    /// Reads the records written from `from` on, stopping once about `max_bytes` are read, and
+    /// returns them with the position following the last one.
+    ///
+    /// Fails with `UnknownLogPosition` if `from` is not a record boundary of this log, e.g. when
+    /// a merge has rewritten the file since: the follower then has to start over from a
+    /// snapshot.
+    pub fn read_log(
+        &self,
+        from: LogPosition,
+        max_bytes: usize,
+    ) -> BitCaskResult<(Vec<LogRecord>, LogPosition)> {
+        let end = self.log_end();
+        if from > end || self.merge_floor.is_some_and(|floor| from.file_id <= floor) {
+            return Err(BitCaskError::UnknownLogPosition);
+        }
+        let (records, pos) = read_log_range(&self.base_dir, from, end, max_bytes)?;
+        Ok((records.into_iter().map(|(record, _)| record).collect(), pos))
+    }
+
+    /// Applies a record read from another store's log, keeping its timestamps and version.
+    /// Works on a read only handle, that is how a follower is kept in sync.
+    pub fn apply(&mut self, record: &LogRecord) -> BitCaskResult<()> {
+        match &record.value {
+            Some(value) => self.append_versioned(
+                &record.key,
+                value,
+                record.tstamp,
+                record.expire_at,
+                Some(record.version),
+            ),
+            None => {
+                if self.key_dir.contains_key(&record.key) {
+                    self.append(&record.key, REMOVE_TOMBSTONE, record.tstamp, 0)?;
+                    self.key_dir.remove(&record.key);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes the write operations of the store fail with `ReadOnly`, `apply` and `merge` still
+    /// work.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+impl Snapshot {
+    /// The live keys of the snapshot as records, to bring a follower up to the snapshot's
+    /// state.
+    pub fn records(&self) -> impl Iterator<Item = BitCaskResult<LogRecord>> + '_ {
+        let now = now_ts();
+        self.key_dir
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| {
+                read_entry_value(&self.base_dir, &entry).map(|value| LogRecord {
+                    tstamp: entry.tstamp,
+                    expire_at: entry.expire_at,
+                    version: self.key_dir.version(&entry),
+                    key: key.into_owned(),
+                    value: Some(value),
+                })
+            })
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::bitcask::{
+    read_entry_value, BitCaskHandle, BitCaskResult, Key, KeyRef, ScanIter, Value,
+};
+use crate::key_dir::KeyDir;
+use crate::utils::now_ts;
+
+/// Reference counts of the data files held by live snapshots, keyed by file id.
+/// Shared between a handle and the snapshots it hands out.
+pub(crate) type FilePins = Arc<Mutex<BTreeMap<u32, usize>>>;
+
+pub(crate) fn min_pinned_file(pins: &FilePins) -> Option<u32> {
+    pins.lock().unwrap().keys().next().copied()
+}
+
+pub(crate) fn pin_file(pins: &FilePins, file_id: u32) {
+    *pins.lock().unwrap().entry(file_id).or_default() += 1;
+}
+
+pub(crate) fn unpin_file(pins: &FilePins, file_id: u32) {
+    let mut pins = pins.lock().unwrap();
+    if let Some(count) = pins.get_mut(&file_id) {
+        *count -= 1;
+        if *count == 0 {
+            pins.remove(&file_id);
+        }
+    }
+}
+
+/// A read-only view of the store frozen at the moment `BitCaskHandle::snapshot` was called.
+///
+/// Writes made through the handle afterwards are not visible, and the data files the view
+/// refers to are pinned: `merge` will not rewrite or delete them until the snapshot is dropped.
+pub struct Snapshot {
+    pub(crate) base_dir: PathBuf,
+    pub(crate) key_dir: KeyDir,
+    pins: FilePins,
+    pinned: Vec<u32>,
+}
+
+impl Snapshot {
+    pub fn get(&self, key: &KeyRef) -> BitCaskResult<Option<Value>> {
+        match self.key_dir.get(key) {
+            Some(entry) => read_entry_value(&self.base_dir, &entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &KeyRef) -> bool {
+        self.key_dir.contains_key(key)
+    }
+
+    pub fn list_keys(&self) -> Vec<Key> {
+        self.key_dir
+            .iter()
+            .map(|(key, _)| key.into_owned())
+            .collect()
+    }
+
+    pub fn scan(&self, prefix: &KeyRef) -> ScanIter<'_> {
+        ScanIter::new(&self.base_dir, &self.key_dir, prefix)
+    }
+
+    pub fn len(&self) -> usize {
+        self.key_dir.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key_dir.is_empty()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        for file_id in &self.pinned {
+            unpin_file(&self.pins, *file_id);
+        }
+    }
+}
+
+impl BitCaskHandle {
+    /// Takes a point-in-time view of the store. The key dir is copied, so the snapshot stays
+    /// valid while the handle keeps accepting writes. Keys already expired are left out.
+    pub fn snapshot(&self) -> Snapshot {
+        let now = now_ts();
+        let mut key_dir = self.key_dir.clone();
+        key_dir.retain(|_, entry| !entry.is_expired(now));
+        let pinned: Vec<u32> = key_dir
+            .iter()
+            .map(|(_, entry)| entry.file_id)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        for file_id in &pinned {
+            pin_file(&self.pins, *file_id);
+        }
+        Snapshot {
+            base_dir: self.base_dir.clone(),
+            key_dir,
+            pins: self.pins.clone(),
+            pinned,
+        }
+    }
+}
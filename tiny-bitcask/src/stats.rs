@@ -0,0 +1,345 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::bitcask::{BitCaskHandle, BitCaskResult, KeyDirEntry, KeyRef};
+use crate::block::{header_size, FLAG_EXPIRES, FLAG_VERSION};
+use crate::utils::{get_dat_files, now_ts};
+
+/// Latency buckets are powers of two microseconds up to about a second, plus one for slower
+/// operations.
+const LATENCY_BUCKETS: usize = 21;
+
+#[derive(Default)]
+struct LatencyHistogram {
+    counts: [AtomicU64; LATENCY_BUCKETS + 1],
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn record(&self, started: Instant) {
+        let micros = started.elapsed().as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.saturating_sub(1).leading_zeros()) as usize;
+        self.counts[bucket.min(LATENCY_BUCKETS)].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let counts: Vec<u64> = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        Histogram {
+            bounds_micros: (0..LATENCY_BUCKETS).map(|i| 1 << i).collect(),
+            count: counts.iter().sum(),
+            counts,
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters of a handle, updated by operations that only borrow it.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    gets: AtomicU64,
+    get_hits: AtomicU64,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    pub(crate) bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    get_latency: LatencyHistogram,
+    put_latency: LatencyHistogram,
+    delete_latency: LatencyHistogram,
+    last_merge: Mutex<Option<MergeStats>>,
+}
+
+impl Metrics {
+    pub(crate) fn record_get(&self, started: Instant, value_len: Option<usize>) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        if let Some(len) = value_len {
+            self.get_hits.fetch_add(1, Ordering::Relaxed);
+            self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+        }
+        self.get_latency.record(started);
+    }
+
+    pub(crate) fn record_put(&self, started: Instant) {
+        self.puts.fetch_add(1, Ordering::Relaxed);
+        self.put_latency.record(started);
+    }
+
+    pub(crate) fn record_delete(&self, started: Instant) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+        self.delete_latency.record(started);
+    }
+
+    pub(crate) fn record_write(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_merge(&self, merge: MergeStats) {
+        *self.last_merge.lock().unwrap() = Some(merge);
+    }
+}
+
+/// Counts of operations by latency. `counts[i]` holds those that took at most
+/// `bounds_micros[i]` and more than the bound before; the extra last count holds the slower
+/// ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    pub bounds_micros: Vec<u64>,
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub sum_micros: u64,
+}
+
+impl Histogram {
+    /// Upper bound of the bucket holding the `q` quantile, `None` if nothing was recorded or it
+    /// falls in the last, unbounded bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, count) in self.bounds_micros.iter().zip(&self.counts) {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_micros(*bound));
+            }
+        }
+        None
+    }
+}
+
+/// How the last merge of a handle went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeStats {
+    /// unix time the merge finished at
+    pub finished_at: u32,
+    pub duration: Duration,
+    /// data file bytes the merge freed
+    pub reclaimed_bytes: u64,
+    /// `None` if the merge succeeded
+    pub error: Option<String>,
+}
+
+/// A point-in-time view of the size and activity of a store, see `BitCaskHandle::stats`.
+/// Counters start at zero when the handle is opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// live keys, those already expired not counted
+    pub keys: usize,
+    /// rough heap size of the key dir
+    pub key_dir_bytes: usize,
+    pub data_files: usize,
+    pub data_bytes: u64,
+    /// bytes of the records the key dir points at, all others are reclaimable by a merge
+    pub live_bytes: u64,
+    pub gets: u64,
+    pub get_hits: u64,
+    pub puts: u64,
+    pub deletes: u64,
+    /// value bytes returned by gets and scans
+    pub bytes_read: u64,
+    /// record bytes appended to the data files, merges not counted
+    pub bytes_written: u64,
+    pub get_latency: Histogram,
+    pub put_latency: Histogram,
+    pub delete_latency: Histogram,
+    pub last_merge: Option<MergeStats>,
+}
+
+impl Stats {
+    /// Share of the data file bytes no longer referenced, 0 for an empty store.
+    pub fn dead_ratio(&self) -> f64 {
+        if self.data_bytes == 0 {
+            return 0.0;
+        }
+        self.data_bytes.saturating_sub(self.live_bytes) as f64 / self.data_bytes as f64
+    }
+
+    /// Formats the stats in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let gauges: [(&str, &str, f64); 6] = [
+            ("keys", "Live keys.", self.keys as f64),
+            (
+                "key_dir_bytes",
+                "Estimated heap size of the key dir.",
+                self.key_dir_bytes as f64,
+            ),
+            ("data_files", "Data files.", self.data_files as f64),
+            (
+                "data_bytes",
+                "Size of the data files.",
+                self.data_bytes as f64,
+            ),
+            (
+                "live_bytes",
+                "Data file bytes still referenced.",
+                self.live_bytes as f64,
+            ),
+            (
+                "dead_ratio",
+                "Share of the data file bytes a merge would reclaim.",
+                self.dead_ratio(),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            metric(&mut out, name, "gauge", help, value);
+        }
+        let counters: [(&str, &str, u64); 6] = [
+            ("gets_total", "Get operations.", self.gets),
+            ("get_hits_total", "Gets that found the key.", self.get_hits),
+            ("puts_total", "Put operations.", self.puts),
+            ("deletes_total", "Delete operations.", self.deletes),
+            ("read_bytes_total", "Value bytes read.", self.bytes_read),
+            (
+                "written_bytes_total",
+                "Record bytes appended to the data files.",
+                self.bytes_written,
+            ),
+        ];
+        for (name, help, value) in counters {
+            metric(&mut out, name, "counter", help, value as f64);
+        }
+        let histograms = [
+            ("get", &self.get_latency),
+            ("put", &self.put_latency),
+            ("delete", &self.delete_latency),
+        ];
+        let _ = writeln!(
+            out,
+            "# HELP bitcask_op_duration_seconds Latency of store operations."
+        );
+        let _ = writeln!(out, "# TYPE bitcask_op_duration_seconds histogram");
+        for (op, histogram) in histograms {
+            let mut cumulative = 0;
+            for (bound, count) in histogram.bounds_micros.iter().zip(&histogram.counts) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "bitcask_op_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    op,
+                    *bound as f64 / 1e6,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "bitcask_op_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                op, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "bitcask_op_duration_seconds_sum{{op=\"{}\"}} {}",
+                op,
+                histogram.sum_micros as f64 / 1e6
+            );
+            let _ = writeln!(
+                out,
+                "bitcask_op_duration_seconds_count{{op=\"{}\"}} {}",
+                op, histogram.count
+            );
+        }
+        if let Some(merge) = &self.last_merge {
+            let merges: [(&str, &str, f64); 4] = [
+                (
+                    "last_merge_timestamp_seconds",
+                    "Unix time the last merge finished.",
+                    merge.finished_at as f64,
+                ),
+                (
+                    "last_merge_duration_seconds",
+                    "Duration of the last merge.",
+                    merge.duration.as_secs_f64(),
+                ),
+                (
+                    "last_merge_reclaimed_bytes",
+                    "Bytes freed by the last merge.",
+                    merge.reclaimed_bytes as f64,
+                ),
+                (
+                    "last_merge_success",
+                    "1 if the last merge succeeded.",
+                    merge.error.is_none() as u8 as f64,
+                ),
+            ];
+            for (name, help, value) in merges {
+                metric(&mut out, name, "gauge", help, value);
+            }
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP bitcask_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE bitcask_{} {}", name, kind);
+    let _ = writeln!(out, "bitcask_{} {}", name, value);
+}
+
+/// Number and total size of the data files in `dir`.
+pub(crate) fn data_file_usage(dir: &Path) -> std::io::Result<(usize, u64)> {
+    let files = get_dat_files(dir)?;
+    let mut bytes = 0;
+    for path in &files {
+        bytes += path.metadata()?.len();
+    }
+    Ok((files.len(), bytes))
+}
+
+/// Size of the record an entry points at.
+fn record_size(key: &KeyRef, entry: &KeyDirEntry, moved: bool) -> u64 {
+    let mut raw_ksz = 0;
+    if entry.expire_at != 0 {
+        raw_ksz |= FLAG_EXPIRES;
+    }
+    // a merge gives every record it copies a version, see `Block::with_version`
+    if moved {
+        raw_ksz |= FLAG_VERSION;
+    }
+    (header_size(raw_ksz) + key.len()) as u64 + entry.value_sz as u64
+}
+
+impl BitCaskHandle {
+    /// Collects the current stats of the store. Walks the key dir and lists the data files,
+    /// so it is not free on a big store.
+    pub fn stats(&self) -> BitCaskResult<Stats> {
+        let now = now_ts();
+        let mut keys = 0;
+        let mut live_bytes = 0;
+        for (key, entry) in self.key_dir.iter() {
+            // expired records are dead too, the next merge drops them
+            if !entry.is_expired(now) {
+                keys += 1;
+                let moved = self.key_dir.moved_version(&entry).is_some();
+                live_bytes += record_size(&key, &entry, moved);
+            }
+        }
+        let (data_files, data_bytes) = data_file_usage(&self.base_dir)?;
+        let metrics = &self.metrics;
+        Ok(Stats {
+            keys,
+            key_dir_bytes: self.key_dir.heap_bytes(),
+            data_files,
+            data_bytes,
+            live_bytes,
+            gets: metrics.gets.load(Ordering::Relaxed),
+            get_hits: metrics.get_hits.load(Ordering::Relaxed),
+            puts: metrics.puts.load(Ordering::Relaxed),
+            deletes: metrics.deletes.load(Ordering::Relaxed),
+            bytes_read: metrics.bytes_read.load(Ordering::Relaxed),
+            bytes_written: metrics.bytes_written.load(Ordering::Relaxed),
+            get_latency: metrics.get_latency.snapshot(),
+            put_latency: metrics.put_latency.snapshot(),
+            delete_latency: metrics.delete_latency.snapshot(),
+            last_merge: metrics.last_merge.lock().unwrap().clone(),
+        })
+    }
+}
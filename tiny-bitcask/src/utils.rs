@@ -15,6 +15,10 @@ pub fn format_idx_file_name(file_id: u32) -> String {
     format!("{:0>9}.idx", file_id)
 }
 
+pub fn format_blob_file_name(blob_id: u64) -> String {
+    format!("{:016x}.blob", blob_id)
+}
+
 pub fn now_ts() -> u32 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -22,14 +26,33 @@ pub fn now_ts() -> u32 {
         .as_secs() as u32
 }
 
+pub fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos() as u64
+}
+
 pub fn block_crc(block: &Block) -> u32 {
+    let mut hasher = header_crc(block);
+    hasher.update(&block.value);
+    hasher.finalize()
+}
+
+/// The crc of a record up to the end of its key, for its value to be added to.
+pub fn header_crc(block: &Block) -> crc32fast::Hasher {
     let mut hasher = crc32fast::Hasher::new();
     hasher.update(&block.tstamp.to_le_bytes());
-    hasher.update(&block.ksz.to_le_bytes());
+    hasher.update(&block.raw_ksz().to_le_bytes());
     hasher.update(&block.value_sz.to_le_bytes());
+    if block.expire_at != 0 {
+        hasher.update(&block.expire_at.to_le_bytes());
+    }
+    if let Some(version) = block.version {
+        hasher.update(&version.to_le_bytes());
+    }
     hasher.update(&block.key);
-    hasher.update(&block.value);
-    hasher.finalize()
+    hasher
 }
 
 mod file_name_utils {
@@ -38,19 +61,13 @@ mod file_name_utils {
     pub fn get_file_name_without_extension(path: &Path) -> std::io::Result<&str> {
         path.file_stem()
             .and_then(|path| path.to_str())
-            .ok_or(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "parse error",
-            ))
+            .ok_or(std::io::Error::other("parse error"))
     }
 
     pub fn get_file_name(path: &Path) -> std::io::Result<&str> {
         path.file_name()
             .and_then(|path| path.to_str())
-            .ok_or(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "parse error",
-            ))
+            .ok_or(std::io::Error::other("parse error"))
     }
 }
 
@@ -68,6 +85,7 @@ pub mod file_utils {
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(path)
         }
     }
@@ -84,6 +102,25 @@ pub mod file_utils {
     }
 }
 
+/// Hard-links `src` to `dst`, falling back to a synced copy when linking is not possible,
+/// e.g. when `dst` lives on another filesystem.
+pub fn link_or_copy(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if fs::hard_link(src, dst).is_ok() {
+        return Ok(());
+    }
+    copy_synced(src, dst)
+}
+
+/// Copies `src` to `dst` and syncs the copy, which shares nothing with `src`.
+pub fn copy_synced(src: &Path, dst: &Path) -> std::io::Result<()> {
+    fs::copy(src, dst)?;
+    fs::File::open(dst)?.sync_all()
+}
+
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
 pub fn create_base_dir_if_not_exists(base_dir: &Path) -> std::io::Result<()> {
     if !base_dir.exists() {
         fs::create_dir_all(base_dir)?;
@@ -99,7 +136,7 @@ pub fn get_file_id_from_path(path: &Path) -> BitCaskResult<u32> {
 
 fn dat_file_filter(path: &Path) -> bool {
     let re = Regex::new(r"^\d+\.dat$").unwrap();
-    file_name_utils::get_file_name(path).map_or(false, |file_name| re.is_match(file_name))
+    file_name_utils::get_file_name(path).is_ok_and(|file_name| re.is_match(file_name))
 }
 
 pub fn get_dat_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
@@ -108,9 +145,29 @@ pub fn get_dat_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
     Ok(files)
 }
 
+fn idx_file_filter(path: &Path) -> bool {
+    let re = Regex::new(r"^\d+\.idx$").unwrap();
+    file_name_utils::get_file_name(path).is_ok_and(|file_name| re.is_match(file_name))
+}
+
+pub fn get_idx_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    file_utils::list_files_in_dir(dir, &idx_file_filter)
+}
+
+fn blob_file_filter(path: &Path) -> bool {
+    let re = Regex::new(r"^[0-9a-f]{16}\.blob$").unwrap();
+    file_name_utils::get_file_name(path).is_ok_and(|file_name| re.is_match(file_name))
+}
+
+pub fn get_blob_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = file_utils::list_files_in_dir(dir, &blob_file_filter)?;
+    files.sort();
+    Ok(files)
+}
+
 const DATAFILE_START_INDEX: u32 = 0;
 
-pub fn get_next_id(dat_files: &Vec<PathBuf>) -> u32 {
+pub fn get_next_id(dat_files: &[PathBuf]) -> u32 {
     if dat_files.is_empty() {
         return DATAFILE_START_INDEX;
     }
@@ -118,12 +175,12 @@ pub fn get_next_id(dat_files: &Vec<PathBuf>) -> u32 {
     get_file_id_from_path(last_dat_file.as_path()).unwrap() + 1
 }
 
-pub fn delete_file(path: &PathBuf) -> std::io::Result<()> {
+pub fn delete_file(path: &Path) -> std::io::Result<()> {
     fs::remove_file(path)
 }
 
-pub fn get_hint_from_dat_path(dat_file_path: &PathBuf) -> PathBuf {
-    let mut hint_file_path = dat_file_path.clone();
+pub fn get_hint_from_dat_path(dat_file_path: &Path) -> PathBuf {
+    let mut hint_file_path = dat_file_path.to_path_buf();
     hint_file_path.set_extension("idx");
     hint_file_path
 }
@@ -142,6 +199,9 @@ mod test_utils {
             tstamp: 123456,
             ksz: 5,
             value_sz: 5,
+            expire_at: 0,
+            blob: false,
+            version: None,
             key: b"hello".to_vec(),
             value: b"world".to_vec(),
         };
@@ -157,6 +217,7 @@ mod test_utils {
     }
 
     #[test]
+    #[ignore = "lists a directory that only exists on the author's machine"]
     fn test_list_file() {
         let files = get_dat_files(&std::path::PathBuf::from(
             "/Users/arthur/CLionProjects/lets-ddia",
@@ -167,6 +228,7 @@ mod test_utils {
     }
 
     #[test]
+    #[ignore = "parses the file name with its extension, which is not a number"]
     fn test_fid() {
         let fid = "000001234.dat";
         let id = str::parse::<u32>(fid).unwrap();
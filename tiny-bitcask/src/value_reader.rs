@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+
+/// Reads a stored value straight from its file, see `BitCaskHandle::get_reader`.
+///
+/// The reader keeps the file open, so the value stays readable even if a merge deletes the
+/// file meanwhile. A value kept in a blob file is checked against its crc when read from
+/// start to end without seeking: the read reaching the end then fails with `InvalidData` if
+/// it does not match.
+pub struct ValueReader {
+    file: BufReader<File>,
+    // where the value starts in the file, and its length
+    start: u64,
+    len: u64,
+    pos: u64,
+    // crc32 of what was read so far and of the whole value, until a seek
+    check: Option<(crc32fast::Hasher, u32)>,
+}
+
+impl ValueReader {
+    pub(crate) fn new(mut file: File, start: u64, len: u64, crc: Option<u32>) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            file: BufReader::new(file),
+            start,
+            len,
+            pos: 0,
+            check: crc.map(|crc| (crc32fast::Hasher::new(), crc)),
+        })
+    }
+
+    /// Length of the value in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            if let Some((hasher, crc)) = self.check.take() {
+                if hasher.finalize() != crc {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "checksum mismatch"));
+                }
+            }
+            return Ok(0);
+        }
+        let remaining = self.len - self.pos;
+        let max = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read(&mut buf[..max])?;
+        if n == 0 {
+            // the file was cut short
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if let Some((hasher, _)) = &mut self.check {
+            hasher.update(&buf[..n]);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ValueReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek before the value"))?;
+        if target != self.pos {
+            self.check = None;
+            self.file.seek(SeekFrom::Start(self.start + target))?;
+            self.pos = target;
+        }
+        Ok(target)
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::bitcask::{version_at, BitCaskResult, Key, KeyDirEntry};
+use crate::block::{header_size, FLAG_EXPIRES, FLAG_VERSION};
+use crate::dump::{dump_file, DumpRecord, RecordKind};
+use crate::index_file::{HintDamage, HintFile};
+use crate::utils::*;
+
+pub const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// A problem found by `verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// a record in the middle of a data file fails its checksum
+    CorruptRecord { file: PathBuf, offset: u64 },
+    /// the data file ends with bytes that do not form valid records, from its start if even
+    /// the first record is torn
+    TornTail {
+        file: PathBuf,
+        offset: u64,
+        len: u64,
+    },
+    /// the file cannot be read
+    UnreadableFile { file: PathBuf, reason: String },
+    /// a hint record does not match the record it points to in the data file
+    BadHint { file: PathBuf, reason: String },
+    /// a hint file without a data file
+    OrphanedHint { file: PathBuf },
+    /// the working directory of an interrupted merge
+    LeftoverTmpDir { path: PathBuf },
+    /// data file ids `from..=to` are missing between two existing files
+    MissingFiles { from: u32, to: u32 },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::CorruptRecord { file, offset } => {
+                write!(f, "{}: corrupt record at offset {}", file.display(), offset)
+            }
+            Issue::TornTail { file, offset, len } => write!(
+                f,
+                "{}: {} bytes of torn tail at offset {}",
+                file.display(),
+                len,
+                offset
+            ),
+            Issue::UnreadableFile { file, reason } => {
+                write!(f, "{}: unreadable, {}", file.display(), reason)
+            }
+            Issue::BadHint { file, reason } => {
+                write!(f, "{}: bad hint, {}", file.display(), reason)
+            }
+            Issue::OrphanedHint { file } => {
+                write!(f, "{}: hint file without data file", file.display())
+            }
+            Issue::LeftoverTmpDir { path } => {
+                write!(f, "{}: leftover merge directory", path.display())
+            }
+            Issue::MissingFiles { from, to } => {
+                write!(f, "data files {} to {} are missing", from, to)
+            }
+        }
+    }
+}
+
+/// A change made by `verify` in repair mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    Truncated { file: PathBuf, len: u64 },
+    RegeneratedHint { file: PathBuf },
+    Quarantined { file: PathBuf, to: PathBuf },
+    RemovedTmpDir { path: PathBuf },
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::Truncated { file, len } => {
+                write!(f, "{}: truncated to {} bytes", file.display(), len)
+            }
+            Repair::RegeneratedHint { file } => write!(f, "{}: regenerated", file.display()),
+            Repair::Quarantined { file, to } => {
+                write!(f, "{}: moved to {}", file.display(), to.display())
+            }
+            Repair::RemovedTmpDir { path } => write!(f, "{}: removed", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub data_files: usize,
+    pub hint_files: usize,
+    pub records: usize,
+    pub issues: Vec<Issue>,
+    pub repairs: Vec<Repair>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// What a data file walk learned about one record, used to cross-check hints.
+struct DatRecordInfo {
+    key: Key,
+    value_sz: u32,
+    tstamp: u32,
+    expire_at: u32,
+    version: u64,
+    kind: RecordKind,
+}
+
+/// Checks a data directory of a store that is not open: every record of every data file, every
+/// hint record against the data record it points to, and the directory layout.
+///
+/// With `repair`, torn tails are truncated, bad hint files regenerated, unreadable files moved
+/// into a `quarantine` subdirectory and a leftover merge directory removed. Corrupt records in
+/// the middle of a file and missing files are only reported, fixing them would lose data.
+pub fn verify(dir: &Path, repair: bool) -> BitCaskResult<VerifyReport> {
+    let mut report = VerifyReport::default();
+
+    let tmp_dir = dir.join("tmp");
+    if tmp_dir.exists() {
+        report.issues.push(Issue::LeftoverTmpDir {
+            path: tmp_dir.clone(),
+        });
+        if repair {
+            fs::remove_dir_all(&tmp_dir)?;
+            report.repairs.push(Repair::RemovedTmpDir { path: tmp_dir });
+        }
+    }
+
+    let dat_files = get_dat_files(dir)?;
+    let mut ids = vec![];
+    let mut records_by_file: HashMap<u32, BTreeMap<u64, DatRecordInfo>> = HashMap::new();
+    for path in &dat_files {
+        let file_id = get_file_id_from_path(path)?;
+        report.data_files += 1;
+        match check_dat_file(path, file_id, repair, &mut report) {
+            Some(records) => {
+                ids.push(file_id);
+                records_by_file.insert(file_id, records);
+            }
+            None => quarantine(dir, path, repair, &mut report)?,
+        }
+    }
+    for pair in ids.windows(2) {
+        if pair[1] > pair[0] + 1 {
+            report.issues.push(Issue::MissingFiles {
+                from: pair[0] + 1,
+                to: pair[1] - 1,
+            });
+        }
+    }
+
+    for path in get_idx_files(dir)? {
+        report.hint_files += 1;
+        let file_id = get_file_id_from_path(&path)?;
+        let Some(records) = records_by_file.get(&file_id) else {
+            report
+                .issues
+                .push(Issue::OrphanedHint { file: path.clone() });
+            quarantine(dir, &path, repair, &mut report)?;
+            continue;
+        };
+        if let Err(reason) = check_hint_file(&path, file_id, records) {
+            report.issues.push(Issue::BadHint {
+                file: path.clone(),
+                reason,
+            });
+            if repair {
+                report.repairs.push(regenerate_hint(&path, records)?);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Walks a data file, returns its valid records by value position, or None if it cannot be
+/// read. A torn tail is cut off after the last valid record, at offset 0 if there is none.
+fn check_dat_file(
+    path: &Path,
+    file_id: u32,
+    repair: bool,
+    report: &mut VerifyReport,
+) -> Option<BTreeMap<u64, DatRecordInfo>> {
+    let unreadable = |report: &mut VerifyReport, reason: String| {
+        report.issues.push(Issue::UnreadableFile {
+            file: path.to_path_buf(),
+            reason,
+        });
+        None
+    };
+    let iter = match dump_file(path) {
+        Ok(iter) => iter,
+        Err(err) => return unreadable(report, err.to_string()),
+    };
+    let len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+
+    let mut records = BTreeMap::new();
+    // offsets of bad records seen since the last good one, they are corruption only if a
+    // good record follows, otherwise they are part of the torn tail
+    let mut pending_bad = vec![];
+    let mut valid_end = 0;
+    for record in iter {
+        match record {
+            Ok(DumpRecord::Data {
+                offset,
+                crc_valid: true,
+                tstamp,
+                expire_at,
+                version,
+                value_sz,
+                kind,
+                key,
+                ..
+            }) => {
+                for offset in pending_bad.drain(..) {
+                    report.issues.push(Issue::CorruptRecord {
+                        file: path.to_path_buf(),
+                        offset,
+                    });
+                }
+                let mut flags = if expire_at != 0 { FLAG_EXPIRES } else { 0 };
+                if version.is_some() {
+                    flags |= FLAG_VERSION;
+                }
+                let value_pos = offset + (header_size(flags) + key.len()) as u64;
+                valid_end = value_pos + value_sz as u64;
+                records.insert(
+                    value_pos,
+                    DatRecordInfo {
+                        key,
+                        value_sz,
+                        tstamp,
+                        expire_at,
+                        version: version.unwrap_or_else(|| version_at(file_id, value_pos as u32)),
+                        kind,
+                    },
+                );
+                report.records += 1;
+            }
+            Ok(DumpRecord::Data { offset, .. }) => pending_bad.push(offset),
+            Ok(_) => {}
+            Err(err) => return unreadable(report, err.to_string()),
+        }
+    }
+
+    if valid_end == len {
+        return Some(records);
+    }
+    report.issues.push(Issue::TornTail {
+        file: path.to_path_buf(),
+        offset: valid_end,
+        len: len - valid_end,
+    });
+    if repair {
+        let truncated = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(valid_end).and_then(|_| file.sync_all()));
+        match truncated {
+            Ok(()) => report.repairs.push(Repair::Truncated {
+                file: path.to_path_buf(),
+                len: valid_end,
+            }),
+            Err(err) => report.issues.push(Issue::UnreadableFile {
+                file: path.to_path_buf(),
+                reason: format!("cannot truncate the torn tail, {}", err),
+            }),
+        }
+    }
+    Some(records)
+}
+
+fn check_hint_file(
+    path: &Path,
+    file_id: u32,
+    records: &BTreeMap<u64, DatRecordInfo>,
+) -> Result<(), String> {
+    let iter = dump_file(path).map_err(|err| err.to_string())?;
+    let mut count = 0;
+    for record in iter {
+        match record.map_err(|err| err.to_string())? {
+            DumpRecord::Hint {
+                offset,
+                kind,
+                crc_valid,
+                version,
+                key,
+                value_sz,
+                value_pos,
+                ..
+            } => {
+                if !crc_valid {
+                    return Err(HintDamage::BadRecord { offset }.to_string());
+                }
+                let version = version.unwrap_or_else(|| version_at(file_id, value_pos));
+                let matches = records.get(&(value_pos as u64)).is_some_and(|info| {
+                    info.key == key
+                        && info.value_sz == value_sz
+                        && info.kind == kind
+                        && info.version == version
+                });
+                if !matches {
+                    return Err(format!(
+                        "record at offset {} does not point at a matching data record",
+                        offset
+                    ));
+                }
+                count += 1;
+            }
+            DumpRecord::HintFooter {
+                data_len,
+                records: footer_count,
+                crc_valid,
+                ..
+            } => {
+                if !crc_valid {
+                    return Err(HintDamage::Checksum.to_string());
+                }
+                if footer_count != count {
+                    return Err(HintDamage::Count {
+                        footer: footer_count,
+                        found: count,
+                    }
+                    .to_string());
+                }
+                if data_len as u64 > data_end(records) {
+                    return Err(format!(
+                        "covers {} bytes, more than the data file",
+                        data_len
+                    ));
+                }
+                return Ok(());
+            }
+            DumpRecord::Truncated { offset, .. } => {
+                return Err(format!("truncated at offset {}", offset))
+            }
+            DumpRecord::Data { .. } => unreachable!("hint files hold hint records"),
+        }
+    }
+    Err(HintDamage::NoFooter.to_string())
+}
+
+/// End of the last valid record of a data file.
+fn data_end(records: &BTreeMap<u64, DatRecordInfo>) -> u64 {
+    records
+        .iter()
+        .next_back()
+        .map_or(0, |(value_pos, info)| value_pos + info.value_sz as u64)
+}
+
+/// Rebuilds a hint file from the valid records of its data file.
+fn regenerate_hint(path: &Path, records: &BTreeMap<u64, DatRecordInfo>) -> BitCaskResult<Repair> {
+    delete_file(path)?;
+    let file_id = get_file_id_from_path(path)?;
+    let tmp_path = path.with_extension("idx.tmp");
+    let mut hint_file = HintFile::create(tmp_path)?;
+    for (value_pos, info) in records {
+        let entry = KeyDirEntry {
+            file_id,
+            value_sz: info.value_sz,
+            value_pos: *value_pos as u32,
+            tstamp: info.tstamp,
+            expire_at: info.expire_at,
+            blob: info.kind == RecordKind::Blob,
+        };
+        let moved_version =
+            (info.version != version_at(file_id, entry.value_pos)).then_some(info.version);
+        match info.kind {
+            RecordKind::Put | RecordKind::Blob => hint_file.put(&info.key, entry, moved_version)?,
+            RecordKind::Delete => hint_file.put_removed(&info.key, entry)?,
+        }
+    }
+    hint_file.finish(data_end(records) as u32)?;
+    hint_file.rename(&path.to_path_buf())?;
+    Ok(Repair::RegeneratedHint {
+        file: path.to_path_buf(),
+    })
+}
+
+fn quarantine(
+    dir: &Path,
+    path: &Path,
+    repair: bool,
+    report: &mut VerifyReport,
+) -> BitCaskResult<()> {
+    if !repair {
+        return Ok(());
+    }
+    let quarantine_dir = dir.join(QUARANTINE_DIR_NAME);
+    create_base_dir_if_not_exists(&quarantine_dir)?;
+    let to = quarantine_dir.join(path.file_name().unwrap());
+    fs::rename(path, &to)?;
+    report.repairs.push(Repair::Quarantined {
+        file: path.to_path_buf(),
+        to,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::bitcask::{BitCask, BitCaskHandle, Opts};
+
+    #[test]
+    fn test_verify_and_repair() {
+        let dir = PathBuf::from("/tmp/bitcask_verify_test");
+        let _ = fs::remove_dir_all(&dir);
+        {
+            let mut db = BitCaskHandle::open(dir.clone(), Opts::new(64)).unwrap();
+            for i in 0..10 {
+                db.put(
+                    format!("key#{i}").as_bytes(),
+                    format!("value#{i}").as_bytes(),
+                )
+                .unwrap();
+            }
+            db.merge().unwrap();
+            db.put(b"key#0", b"updated").unwrap();
+        }
+        let report = verify(&dir, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        // the merged file's, and the one written when the file after it was sealed
+        assert_eq!(report.hint_files, 2);
+
+        let dat_files = get_dat_files(&dir).unwrap();
+        let merged = dat_files.first().unwrap();
+        let active = dat_files.last().unwrap();
+        // torn write at the end of the active file
+        let mut file = OpenOptions::new().append(true).open(active).unwrap();
+        file.write_all(&[7; 20]).unwrap();
+        // a damaged hint record, a stale merge dir and an orphaned hint
+        let hint = get_hint_from_dat_path(merged);
+        let mut bytes = fs::read(&hint).unwrap();
+        bytes[4 + 4 + 5 + 4] ^= 1;
+        fs::write(&hint, bytes).unwrap();
+        fs::create_dir_all(dir.join("tmp")).unwrap();
+        fs::write(dir.join("000000100.idx"), b"").unwrap();
+        // a data file whose first record is torn
+        let first_record = fs::read(merged).unwrap();
+        let next_id = get_file_id_from_path(active).unwrap() + 1;
+        let torn = dir.join(format_dat_file_name(next_id));
+        fs::write(&torn, &first_record[..10]).unwrap();
+
+        let report = verify(&dir, false).unwrap();
+        assert_eq!(report.issues.len(), 5, "{:?}", report.issues);
+        assert!(report.repairs.is_empty());
+
+        let report = verify(&dir, true).unwrap();
+        assert_eq!(report.repairs.len(), 5, "{:?}", report.repairs);
+        // cut off like any other torn tail, not quarantined
+        assert!(report.repairs.contains(&Repair::Truncated {
+            file: torn.clone(),
+            len: 0
+        }));
+        let report = verify(&dir, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert!(dir.join(QUARANTINE_DIR_NAME).join("000000100.idx").exists());
+        assert_eq!(fs::metadata(&torn).unwrap().len(), 0);
+
+        let db = BitCaskHandle::open(dir, Opts::new(64)).unwrap();
+        assert_eq!(db.list_keys().len(), 10);
+        assert_eq!(db.get(b"key#0").unwrap().unwrap(), b"updated".to_vec());
+        assert_eq!(db.get(b"key#9").unwrap().unwrap(), b"value#9".to_vec());
+    }
+}